@@ -3,6 +3,37 @@ extern crate criterion;
 extern crate red_mod;
 
 use criterion::Criterion;
+use red_mod::stream::{IdSpec, Stream, StreamID};
+
+/// Entries per listpack node, the trade-off between append and range cost.
+const NODE_MAX_ENTRIES: [u64; 4] = [16, 100, 1000, 10000];
+
+fn filled(node_max_entries: u64, n: u64) -> Stream {
+    let mut s = Stream::with_node_limits(0, node_max_entries);
+    for _ in 0..n {
+        s.add(&[(b"field", b"value")], IdSpec::Auto).unwrap();
+    }
+    s
+}
+
+fn stream_benchmark(c: &mut Criterion) {
+    c.bench_function_over_inputs("stream add", |b, &&max| {
+        b.iter_with_setup(|| Stream::with_node_limits(0, max), |mut s| {
+            for _ in 0..1000 {
+                s.add(&[(b"field", b"value")], IdSpec::Auto).unwrap();
+            }
+        })
+    }, &NODE_MAX_ENTRIES);
+
+    c.bench_function_over_inputs("stream range 10", |b, &&max| {
+        let s = filled(max, 20000);
+        let start = s.range(StreamID::min(), StreamID::max(), Some(10000))
+            .last()
+            .unwrap()
+            .id;
+        b.iter(|| s.range(start, StreamID::max(), Some(10)).count())
+    }, &NODE_MAX_ENTRIES);
+}
 
 fn criterion_benchmark(_: &mut Criterion) {
 //    c.bench_function("hash", move |b| {
@@ -17,5 +48,5 @@ fn criterion_benchmark(_: &mut Criterion) {
 }
 
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, criterion_benchmark, stream_benchmark);
 criterion_main!(benches);
\ No newline at end of file
@@ -27,6 +27,10 @@ fn main() {
 //        .file("c/stream_ext.c")
         .file("c/xxhash.c")
         .file("c/lz4.c")
+        .file("c/lzf_c.c")
+        .file("c/lzf_d.c")
+        .file("c/ziplist.c")
+        .file("c/quicklist.c")
 
         .file("src/redismodule.c")
         .include("c/")
@@ -29,6 +29,7 @@
 
 #include "endianconv.h"
 #include "stream.h"
+#include "stream_ext.h"
 
 #define STREAM_BYTES_PER_LISTPACK 2048
 
@@ -193,6 +194,17 @@ int streamAppendItemForPersist(
  * C_ERR if an ID was given via 'use_id', but adding it failed since the
  * current top ID is greater or equal. */
 int streamAppendItemSDSMap(stream *s, void **argv, int64_t numfields, streamID *added_id, streamID *use_id) {
+    return streamAppendItemSDSMapLimits(s,argv,numfields,added_id,use_id,
+                                        OBJ_STREAM_NODE_MAX_BYTES,
+                                        OBJ_STREAM_NODE_MAX_ENTRIES);
+}
+
+/* Like streamAppendItemSDSMap() but with the listpack node size limits of
+ * the stream given by the caller instead of the server defaults. A new node
+ * is started when the tail one is bigger than 'node_max_bytes' or holds more
+ * than 'node_max_entries' entries. Zero disables the respective limit. */
+int streamAppendItemSDSMapLimits(stream *s, void **argv, int64_t numfields, streamID *added_id, streamID *use_id,
+                                 size_t node_max_bytes, int64_t node_max_entries) {
     /* If an ID was given, check that it's greater than the last entry ID
      * or return an error. */
     if (use_id && streamCompareID(use_id,&s->last_id) <= 0) return C_ERR;
@@ -263,27 +275,16 @@ int streamAppendItemSDSMap(stream *s, void **argv, int64_t numfields, streamID *
      * if we need to switch to the next one. 'lp' will be set to NULL if
      * the current node is full. */
     if (lp != NULL) {
-//        if (server.stream_node_max_bytes &&
-//            lp_bytes > server.stream_node_max_bytes)
-//        {
-//            lp = NULL;
-//        } else if (server.stream_node_max_entries) {
-//            int64_t count = lpGetInteger(lpFirst(lp));
-//            if (count > server.stream_node_max_entries) lp = NULL;
-//        }
-        if (OBJ_STREAM_NODE_MAX_BYTES &&
-            lp_bytes > OBJ_STREAM_NODE_MAX_BYTES)
-        {
+        if (node_max_bytes && lp_bytes > node_max_bytes) {
             lp = NULL;
-        } else if (OBJ_STREAM_NODE_MAX_ENTRIES) {
+        } else if (node_max_entries) {
             int64_t count = lpGetInteger(lpFirst(lp));
-            if (count > OBJ_STREAM_NODE_MAX_ENTRIES) lp = NULL;
+            if (count > node_max_entries) lp = NULL;
         }
     }
 
     int flags = STREAM_ITEM_FLAG_NONE;
-//    if (lp == NULL || lp_bytes > server.stream_node_max_bytes) {
-    if (lp == NULL || lp_bytes > OBJ_STREAM_NODE_MAX_BYTES) {
+    if (lp == NULL) {
         master_id = id;
         streamEncodeID(rax_key,&id);
         /* Create the listpack having the master entry ID and fields. */
@@ -702,6 +703,112 @@ int64_t streamTrimByLength(stream *s, size_t maxlen, int approx) {
     return deleted;
 }
 
+/* Store in 'last_id' the ID of the last entry, deleted or not, of the
+ * listpack node 'lp' having the master ID 'master_id'. The 'lp-count' field
+ * tells how many elements to jump back to reach the entry flags. */
+void streamNodeLastID(unsigned char *lp, streamID *master_id, streamID *last_id) {
+    unsigned char *e = lpLast(lp);
+    int64_t lp_count = lpGetInteger(e);
+    while(lp_count--) e = lpPrev(lp,e);
+    e = lpNext(lp,e); /* Skip flags. */
+    last_id->ms = master_id->ms + lpGetInteger(e);
+    e = lpNext(lp,e);
+    last_id->seq = master_id->seq + lpGetInteger(e);
+}
+
+/* Trim the stream 's' so that no entry with an ID smaller than 'minid' is
+ * left. Whole radix tree nodes whose last entry ID is smaller than 'minid'
+ * are freed. If 'approx' is false the entries below 'minid' in the first
+ * node that survives are also marked as deleted, keeping the master entry
+ * count and deleted counters up to date.
+ *
+ * The function returns the number of entries removed. */
+int64_t streamTrimByMinID(stream *s, streamID *minid, int approx) {
+    raxIterator ri;
+    raxStart(&ri,s->rax);
+    raxSeek(&ri,"^",NULL,0);
+
+    int64_t deleted = 0;
+    while(s->length && raxNext(&ri)) {
+        unsigned char *lp = ri.data, *p = lpFirst(lp);
+        int64_t entries = lpGetInteger(p);
+        streamID master_id, last_id;
+        streamDecodeID(ri.key,&master_id);
+
+        streamNodeLastID(lp,&master_id,&last_id);
+
+        /* The whole node is below the threshold, remove it. */
+        if (streamCompareID(&last_id,minid) < 0) {
+            lpFree(lp);
+            raxRemove(s->rax,ri.key,ri.key_len,NULL);
+            raxSeek(&ri,">=",ri.key,ri.key_len);
+            s->length -= entries;
+            deleted += entries;
+            continue;
+        }
+
+        if (approx) break;
+
+        /* Mark single entries older than 'minid' as deleted. */
+        p = lpNext(lp,p); /* Seek deleted field. */
+        p = lpNext(lp,p); /* Seek num-of-fields in the master entry. */
+        int64_t master_fields_count = lpGetInteger(p);
+        p = lpNext(lp,p); /* Seek the first field. */
+        for (int64_t j = 0; j < master_fields_count; j++)
+            p = lpNext(lp,p); /* Skip all master fields. */
+        p = lpNext(lp,p); /* Skip the zero master entry terminator. */
+
+        int64_t marked = 0;
+        while(p) {
+            unsigned char *fp = p;
+            int flags = lpGetInteger(p);
+            int to_skip;
+            streamID id;
+
+            p = lpNext(lp,p);
+            id.ms = master_id.ms + lpGetInteger(p);
+            p = lpNext(lp,p);
+            id.seq = master_id.seq + lpGetInteger(p);
+            if (streamCompareID(&id,minid) >= 0) break;
+
+            if (!(flags & STREAM_ITEM_FLAG_DELETED)) {
+                lp = lpReplaceInteger(lp,&fp,flags|STREAM_ITEM_FLAG_DELETED);
+                marked++;
+                /* The listpack may have been reallocated, seek back to the
+                 * seq delta from the flags we just replaced. */
+                p = lpNext(lp,fp);
+                p = lpNext(lp,p);
+            }
+
+            p = lpNext(lp,p); /* Seek num-fields or values (if compressed). */
+            if (flags & STREAM_ITEM_FLAG_SAMEFIELDS) {
+                to_skip = master_fields_count;
+            } else {
+                to_skip = lpGetInteger(p);
+                to_skip = 1+(to_skip*2);
+            }
+
+            while(to_skip--) p = lpNext(lp,p); /* Skip the whole entry. */
+            p = lpNext(lp,p); /* Skip the final lp-count field. */
+        }
+
+        if (marked) {
+            p = lpFirst(lp);
+            lp = lpReplaceInteger(lp,&p,entries-marked);
+            p = lpNext(lp,p); /* Seek deleted field. */
+            int64_t marked_deleted = lpGetInteger(p);
+            lp = lpReplaceInteger(lp,&p,marked_deleted+marked);
+            raxInsert(s->rax,ri.key,ri.key_len,lp,NULL);
+            s->length -= marked;
+            deleted += marked;
+        }
+        break;
+    }
+
+    raxStop(&ri);
+    return deleted;
+}
+
 /* Initialize the stream iterator, so that we can call iterating functions
  * to get the next items. This requires a corresponding streamIteratorStop()
  * at the end. The 'rev' parameter controls the direction. If it's zero the
@@ -730,14 +837,14 @@ void streamIteratorStart(streamIterator *si, stream *s, streamID *start, streamI
         streamEncodeID(si->start_key,start);
     } else {
         si->start_key[0] = 0;
-        si->start_key[0] = 0;
+        si->start_key[1] = 0;
     }
 
     if (end) {
         streamEncodeID(si->end_key,end);
     } else {
         si->end_key[0] = UINT64_MAX;
-        si->end_key[0] = UINT64_MAX;
+        si->end_key[1] = UINT64_MAX;
     }
 
     /* Seek the correct node in the radix tree. */
@@ -944,10 +1051,23 @@ void streamIteratorRemoveEntry(streamIterator *si, streamID *current) {
     /* Change the valid/deleted entries count in the master entry. */
     unsigned char *p = lpFirst(lp);
     aux = lpGetInteger(p);
-    lp = lpReplaceInteger(lp,&p,aux-1);
-    p = lpNext(lp,p); /* Seek deleted field. */
-    aux = lpGetInteger(p);
-    lp = lpReplaceInteger(lp,&p,aux+1);
+
+    if (aux == 1) {
+        /* If this is the last element in the listpack, we can remove the whole
+         * node. */
+        lpFree(lp);
+        raxRemove(si->stream->rax,si->ri.key,si->ri.key_len,NULL);
+    } else {
+        /* In the base case we alter the counters of valid/deleted entries. */
+        lp = lpReplaceInteger(lp,&p,aux-1);
+        p = lpNext(lp,p); /* Seek deleted field. */
+        aux = lpGetInteger(p);
+        lp = lpReplaceInteger(lp,&p,aux+1);
+
+        /* Update the listpack with the new pointer. */
+        if (si->lp != lp)
+            raxInsert(si->stream->rax,si->ri.key,si->ri.key_len,lp,NULL);
+    }
 
     /* Update the number of entries counter. */
     si->stream->length--;
@@ -964,8 +1084,8 @@ void streamIteratorRemoveEntry(streamIterator *si, streamID *current) {
     streamIteratorStop(si);
     streamIteratorStart(si,si->stream,&start,&end,si->rev);
 
-    /* TODO: perform a garbage collection here if the ration between
-     * deleted and valid goes over a certain limit. */
+    /* Garbage collection of the tombstones is performed by
+     * streamCompact(). */
 }
 
 /* Stop the stream iterator. The only cleanup we need is to free the rax
@@ -987,9 +1107,84 @@ int streamDeleteItem(stream *s, streamID *id) {
         streamIteratorRemoveEntry(&si,&myid);
         deleted = 1;
     }
+    streamIteratorStop(&si);
     return deleted;
 }
 
+/* Rewrite every listpack node where the number of entries flagged as
+ * deleted reaches 'ratio' times the number of valid entries. The valid
+ * entries are appended to a fresh set of listpacks, so the master entry is
+ * rebuilt from the first valid entry and the node is stored under the
+ * radix tree key of that entry, honoring the given node size limits.
+ * Nodes without valid entries are dropped.
+ *
+ * The function returns the number of deleted entries reclaimed. */
+int64_t streamCompact(stream *s, double ratio, size_t node_max_bytes, int64_t node_max_entries) {
+    raxIterator ri;
+    raxStart(&ri,s->rax);
+    raxSeek(&ri,"^",NULL,0);
+
+    int64_t reclaimed = 0;
+    while(raxNext(&ri)) {
+        unsigned char *lp = ri.data, *p = lpFirst(lp);
+        int64_t entries = lpGetInteger(p);
+        p = lpNext(lp,p); /* Seek deleted field. */
+        int64_t marked_deleted = lpGetInteger(p);
+        if (marked_deleted == 0 ||
+            (entries && (double)marked_deleted < ratio*entries)) continue;
+
+        uint64_t rax_key[2];
+        memcpy(rax_key,ri.key,sizeof(rax_key));
+
+        /* Append the valid entries of this node to a scratch stream. */
+        stream *tmp = streamNew();
+        if (entries) {
+            streamID master_id, last_id;
+            streamDecodeID(rax_key,&master_id);
+            streamNodeLastID(lp,&master_id,&last_id);
+
+            streamIterator si;
+            streamIteratorStart(&si,s,&master_id,&last_id,0);
+            streamID id;
+            int64_t numfields;
+            while(streamIteratorGetID(&si,&id,&numfields)) {
+                sds *argv = zmalloc(sizeof(sds)*numfields*2);
+                for (int64_t i = 0; i < numfields; i++) {
+                    unsigned char *field, *value;
+                    int64_t field_len, value_len;
+                    streamIteratorGetField(&si,&field,&value,&field_len,&value_len);
+                    argv[i*2] = sdsnewlen(field,field_len);
+                    argv[i*2+1] = sdsnewlen(value,value_len);
+                }
+                streamAppendItemSDSMapLimits(tmp,(void**)argv,numfields,NULL,&id,
+                                             node_max_bytes,node_max_entries);
+                for (int64_t i = 0; i < numfields*2; i++) sdsfree(argv[i]);
+                zfree(argv);
+            }
+            streamIteratorStop(&si);
+        }
+
+        /* Swap the old node for the rebuilt ones. Their keys are all within
+         * the range of the old node so the stream order is preserved. */
+        lpFree(lp);
+        raxRemove(s->rax,(unsigned char*)rax_key,sizeof(rax_key),NULL);
+        raxIterator ti;
+        raxStart(&ti,tmp->rax);
+        raxSeek(&ti,"^",NULL,0);
+        while(raxNext(&ti))
+            raxInsert(s->rax,ti.key,ti.key_len,ti.data,NULL);
+        raxStop(&ti);
+        raxFree(tmp->rax);
+        zfree(tmp);
+
+        reclaimed += marked_deleted;
+        raxSeek(&ri,">",(unsigned char*)rax_key,sizeof(rax_key));
+    }
+
+    raxStop(&ri);
+    return reclaimed;
+}
+
 /* Emit a reply in the client output buffer by formatting a Stream ID
  * in the standard <ms>-<seq> format, using the simple string protocol
  * of REPL. */
@@ -1140,3 +1335,118 @@ uint64_t streamDelConsumer(streamCG *cg, sds name) {
     return retval;
 }
 
+/* Record the delivery of the entry 'id' to 'consumer' as XREADGROUP does:
+ * the group last delivered ID is updated and, unless 'noack' is true, a NACK
+ * is added to both the group and the consumer PEL. If the entry was already
+ * pending (the group ID was moved back) the existing NACK is reassigned to
+ * the new consumer and its delivery counter reset. */
+void streamGroupDeliver(streamCG *cg, streamConsumer *consumer, streamID *id, int noack) {
+    if (streamCompareID(id,&cg->last_id) > 0) cg->last_id = *id;
+    if (noack) return;
+
+    unsigned char buf[sizeof(streamID)];
+    streamEncodeID(buf,id);
+
+    streamNACK *nack = streamCreateNACK(consumer);
+    int group_inserted = raxTryInsert(cg->pel,buf,sizeof(buf),nack,NULL);
+    if (group_inserted) {
+        raxInsert(consumer->pel,buf,sizeof(buf),nack,NULL);
+        return;
+    }
+
+    streamFreeNACK(nack);
+    nack = raxFind(cg->pel,buf,sizeof(buf));
+    raxRemove(nack->consumer->pel,buf,sizeof(buf),NULL);
+    nack->consumer = consumer;
+    nack->delivery_time = mstime();
+    nack->delivery_count = 1;
+    raxInsert(consumer->pel,buf,sizeof(buf),nack,NULL);
+}
+
+/* Acknowledge the entry 'id' in the group 'cg', removing it from the group
+ * PEL and from the PEL of the consumer that owns it. Returns 1 if the entry
+ * was pending, 0 otherwise. */
+int streamGroupAck(streamCG *cg, streamID *id) {
+    unsigned char buf[sizeof(streamID)];
+    streamEncodeID(buf,id);
+
+    streamNACK *nack = raxFind(cg->pel,buf,sizeof(buf));
+    if (nack == raxNotFound) return 0;
+    raxRemove(cg->pel,buf,sizeof(buf),NULL);
+    raxRemove(nack->consumer->pel,buf,sizeof(buf),NULL);
+    streamFreeNACK(nack);
+    return 1;
+}
+
+/* Transfer the ownership of the pending entry 'id' to 'consumer', as XCLAIM
+ * does, updating the delivery time and incrementing the delivery counter.
+ * If the entry is not in the group PEL a new NACK is created, this is the
+ * FORCE option: the caller should check the entry exists in the stream. */
+void streamGroupClaim(streamCG *cg, streamConsumer *consumer, streamID *id) {
+    unsigned char buf[sizeof(streamID)];
+    streamEncodeID(buf,id);
+
+    streamNACK *nack = raxFind(cg->pel,buf,sizeof(buf));
+    if (nack == raxNotFound) {
+        nack = streamCreateNACK(NULL);
+        nack->delivery_count = 0;
+        raxInsert(cg->pel,buf,sizeof(buf),nack,NULL);
+    }
+    if (nack->consumer)
+        raxRemove(nack->consumer->pel,buf,sizeof(buf),NULL);
+    nack->consumer = consumer;
+    nack->delivery_time = mstime();
+    nack->delivery_count++;
+    raxInsert(consumer->pel,buf,sizeof(buf),nack,NULL);
+}
+
+/* -----------------------------------------------------------------------
+ * Loading a stream from a dump
+ * ----------------------------------------------------------------------- */
+
+/* Add a listpack node read from a dump, stored under 'key', the 128 bit big
+ * endian ID of its master entry. The 'buf' is copied. The node is rejected
+ * if the listpack header disagrees with 'len', the node has no master entry
+ * or the key is already taken. Returns C_OK or C_ERR. */
+int streamLoadNode(stream *s, unsigned char *key, unsigned char *buf, size_t len) {
+    if (len < 8 || lpBytes(buf) != len || buf[len-1] != 0xFF) return C_ERR;
+    if (lpFirst(buf) == NULL) return C_ERR;
+
+    unsigned char *lp = zmalloc(len);
+    memcpy(lp,buf,len);
+    if (!raxTryInsert(s->rax,key,sizeof(streamID),lp,NULL)) {
+        zfree(lp);
+        return C_ERR;
+    }
+    return C_OK;
+}
+
+/* Add a pending entry read from a dump to the group PEL. Its consumer is
+ * attached by streamLoadConsumerNACK() once the consumers are loaded.
+ * Returns C_ERR if the ID is already pending. */
+int streamLoadNACK(streamCG *cg, streamID *id, mstime_t delivery_time, uint64_t delivery_count) {
+    unsigned char buf[sizeof(streamID)];
+    streamEncodeID(buf,id);
+
+    streamNACK *nack = streamCreateNACK(NULL);
+    nack->delivery_time = delivery_time;
+    nack->delivery_count = delivery_count;
+    if (!raxTryInsert(cg->pel,buf,sizeof(buf),nack,NULL)) {
+        streamFreeNACK(nack);
+        return C_ERR;
+    }
+    return C_OK;
+}
+
+/* Assign the pending entry 'id' of the group PEL to 'consumer'. Returns
+ * C_ERR if the ID is not pending or already belongs to a consumer. */
+int streamLoadConsumerNACK(streamCG *cg, streamConsumer *consumer, streamID *id) {
+    unsigned char buf[sizeof(streamID)];
+    streamEncodeID(buf,id);
+
+    streamNACK *nack = raxFind(cg->pel,buf,sizeof(buf));
+    if (nack == raxNotFound || nack->consumer != NULL) return C_ERR;
+    nack->consumer = consumer;
+    raxInsert(consumer->pel,buf,sizeof(buf),nack,NULL);
+    return C_OK;
+}
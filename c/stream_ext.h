@@ -17,6 +17,17 @@ void streamDecodeID(void *buf, streamID *id);
 int streamCompareID(streamID *a, streamID *b);
 
 int streamAppendItemSDSMap(stream *s, void **argv, int64_t numfields, streamID *added_id, streamID *use_id);
+int streamAppendItemSDSMapLimits(stream *s, void **argv, int64_t numfields, streamID *added_id, streamID *use_id,
+                                 size_t node_max_bytes, int64_t node_max_entries);
 void streamNextID(streamID *last_id, streamID *new_id);
+void streamNodeLastID(unsigned char *lp, streamID *master_id, streamID *last_id);
+int64_t streamTrimByMinID(stream *s, streamID *minid, int approx);
+int64_t streamCompact(stream *s, double ratio, size_t node_max_bytes, int64_t node_max_entries);
+void streamGroupDeliver(streamCG *cg, streamConsumer *consumer, streamID *id, int noack);
+int streamGroupAck(streamCG *cg, streamID *id);
+void streamGroupClaim(streamCG *cg, streamConsumer *consumer, streamID *id);
+int streamLoadNode(stream *s, unsigned char *key, unsigned char *buf, size_t len);
+int streamLoadNACK(streamCG *cg, streamID *id, mstime_t delivery_time, uint64_t delivery_count);
+int streamLoadConsumerNACK(streamCG *cg, streamConsumer *consumer, streamID *id);
 
 #endif //RAX_STREAM_EXT_H
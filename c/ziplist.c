@@ -0,0 +1,142 @@
+//
+// The ziplist implementation is not vendored. quicklist.c only needs a small
+// part of its API and treats every node as an opaque blob of bytes (which it
+// LZF compresses), so the listpack we already vendor for streams is used as
+// the node encoding instead.
+//
+// The only semantic gap is the ziplist END marker: ziplistDelete() leaves 'p'
+// pointing at it when the last element is removed, and quicklist relies on
+// being able to insert before it. The listpack EOF byte plays that role here.
+//
+
+#include <stddef.h>
+#include <string.h>
+#include "listpack.h"
+#include "ziplist.h"
+
+#define LP_EOF 0xFF
+
+/* Return the address of the listpack EOF byte. */
+static unsigned char *zlEnd(unsigned char *zl) {
+    return zl + lpBytes(zl) - 1;
+}
+
+/* Create a new empty ziplist. */
+unsigned char *ziplistNew(void) {
+    return lpNew();
+}
+
+/* Merge ziplists 'first' and 'second' by appending 'second' to 'first'.
+ * The merged ziplist is returned and '*second' is freed and set to NULL.
+ * Returns NULL if the merge is not possible. */
+unsigned char *ziplistMerge(unsigned char **first, unsigned char **second) {
+    if (first == NULL || *first == NULL || second == NULL || *second == NULL)
+        return NULL;
+    if (*first == *second)
+        return NULL;
+
+    unsigned char *target = *first;
+    unsigned char *p = lpFirst(*second);
+    while (p) {
+        int64_t len;
+        unsigned char buf[LP_INTBUF_SIZE];
+        unsigned char *ele = lpGet(p, &len, buf);
+        target = lpAppend(target, ele, (uint32_t) len);
+        p = lpNext(*second, p);
+    }
+
+    lpFree(*second);
+    *second = NULL;
+    *first = target;
+    return target;
+}
+
+/* Push an element at the head or the tail of the ziplist. */
+unsigned char *ziplistPush(unsigned char *zl, unsigned char *s, unsigned int slen, int where) {
+    if (where == ZIPLIST_HEAD) {
+        unsigned char *p = lpFirst(zl);
+        if (p) return lpInsert(zl, s, slen, p, LP_BEFORE, NULL);
+    }
+    return lpAppend(zl, s, slen);
+}
+
+/* Returns a pointer to the element at 'index' (negative indexes count from
+ * the tail) or NULL when out of range. */
+unsigned char *ziplistIndex(unsigned char *zl, int index) {
+    return lpSeek(zl, index);
+}
+
+/* Return the element after 'p' or NULL at the end of the list. */
+unsigned char *ziplistNext(unsigned char *zl, unsigned char *p) {
+    if (p == NULL || p[0] == LP_EOF) return NULL;
+    return lpNext(zl, p);
+}
+
+/* Return the element before 'p' or NULL at the start of the list. When 'p'
+ * is the end marker the last element is returned. */
+unsigned char *ziplistPrev(unsigned char *zl, unsigned char *p) {
+    if (p == NULL) return NULL;
+    return lpPrev(zl, p);
+}
+
+/* Get the element pointed to by 'p'. Strings are returned through 'sval' and
+ * 'slen', integers through 'lval' with 'sval' set to NULL. Returns 0 when 'p'
+ * does not point to an element. */
+unsigned int ziplistGet(unsigned char *p, unsigned char **sval, unsigned int *slen, long long *lval) {
+    if (p == NULL || p[0] == LP_EOF) return 0;
+
+    int64_t count;
+    unsigned char *ele = lpGet(p, &count, NULL);
+    if (ele) {
+        if (sval) *sval = ele;
+        if (slen) *slen = (unsigned int) count;
+    } else {
+        if (sval) *sval = NULL;
+        if (lval) *lval = count;
+    }
+    return 1;
+}
+
+/* Insert an element before 'p'. */
+unsigned char *ziplistInsert(unsigned char *zl, unsigned char *p, unsigned char *s, unsigned int slen) {
+    if (p == NULL) p = zlEnd(zl);
+    return lpInsert(zl, s, slen, p, LP_BEFORE, NULL);
+}
+
+/* Delete the element pointed to by '*p' and update '*p' to the element that
+ * followed it, or to the end marker when the last element was deleted. */
+unsigned char *ziplistDelete(unsigned char *zl, unsigned char **p) {
+    unsigned char *next = NULL;
+    zl = lpDelete(zl, *p, &next);
+    *p = next ? next : zlEnd(zl);
+    return zl;
+}
+
+/* Delete 'num' consecutive elements starting at 'index'. */
+unsigned char *ziplistDeleteRange(unsigned char *zl, int index, unsigned int num) {
+    unsigned char *p = lpSeek(zl, index);
+    while (p && num--) {
+        zl = lpDelete(zl, p, &p);
+    }
+    return zl;
+}
+
+/* Return 1 if the element pointed to by 'p' equals the 'slen' bytes at 's'. */
+unsigned int ziplistCompare(unsigned char *p, unsigned char *s, unsigned int slen) {
+    if (p == NULL || p[0] == LP_EOF) return 0;
+
+    int64_t len;
+    unsigned char buf[LP_INTBUF_SIZE];
+    unsigned char *ele = lpGet(p, &len, buf);
+    return (uint32_t) len == slen && memcmp(ele, s, slen) == 0;
+}
+
+/* Return the number of elements. */
+unsigned int ziplistLen(unsigned char *zl) {
+    return lpLength(zl);
+}
+
+/* Return the total size in bytes. */
+size_t ziplistBlobLen(unsigned char *zl) {
+    return lpBytes(zl);
+}
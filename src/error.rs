@@ -6,6 +6,7 @@ use std::fmt;
 pub enum RedError {
     Generic(GenericError),
     FromUtf8(std::string::FromUtf8Error),
+    Utf8(std::str::Utf8Error),
     ParseInt(std::num::ParseIntError),
 }
 
@@ -21,6 +22,12 @@ impl From<std::string::FromUtf8Error> for RedError {
     }
 }
 
+impl From<std::str::Utf8Error> for RedError {
+    fn from(err: std::str::Utf8Error) -> RedError {
+        RedError::Utf8(err)
+    }
+}
+
 impl From<std::num::ParseIntError> for RedError {
     fn from(err: std::num::ParseIntError) -> RedError {
         RedError::ParseInt(err)
@@ -34,6 +41,7 @@ impl fmt::Display for RedError {
             // their implementations.
             RedError::Generic(ref err) => write!(f, "{}", err),
             RedError::FromUtf8(ref err) => write!(f, "{}", err),
+            RedError::Utf8(ref err) => write!(f, "{}", err),
             RedError::ParseInt(ref err) => write!(f, "{}", err),
         }
     }
@@ -46,6 +54,7 @@ impl error::Error for RedError {
         match *self {
             RedError::Generic(ref err) => err.description(),
             RedError::FromUtf8(ref err) => err.description(),
+            RedError::Utf8(ref err) => err.description(),
             RedError::ParseInt(ref err) => err.description(),
         }
     }
@@ -58,6 +67,7 @@ impl error::Error for RedError {
             // implement `Error`.
             RedError::Generic(ref err) => Some(err),
             RedError::FromUtf8(ref err) => Some(err),
+            RedError::Utf8(ref err) => Some(err),
             RedError::ParseInt(ref err) => Some(err),
         }
     }
@@ -36,9 +36,15 @@ allow(redundant_field_names, suspicious_arithmetic_impl))]
 pub mod listpack;
 #[cfg_attr(feature = "cargo-clippy",
 allow(redundant_field_names, suspicious_arithmetic_impl))]
+pub mod quicklist;
+#[cfg_attr(feature = "cargo-clippy",
+allow(redundant_field_names, suspicious_arithmetic_impl))]
 pub mod rax;
 #[cfg_attr(feature = "cargo-clippy",
 allow(redundant_field_names, suspicious_arithmetic_impl))]
+pub mod sds_pool;
+#[cfg_attr(feature = "cargo-clippy",
+allow(redundant_field_names, suspicious_arithmetic_impl))]
 pub mod stream;
 #[macro_use]
 pub mod key;
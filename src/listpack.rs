@@ -47,7 +47,7 @@ impl Listpack {
         };
     }
 
-    pub fn insert_sds(&mut self, _sds: SDS) {
+    pub fn insert_sds(&mut self, _sds: SdsString) {
 //        unsafe {
 //            self.lp = lpInsert(self.lp, sds.as_ptr(), sds.len(),)
 //        }
@@ -352,7 +352,8 @@ pub type RedisModuleClusterMessageReceiver = extern "C" fn(
 ///
 ///
 pub type RedisModuleTimerProc = extern "C" fn(
-    value: *mut libc::c_void,
+    ctx: *mut RedisModuleCtx,
+    data: *mut libc::c_void,
 );
 
 ///
@@ -811,6 +812,24 @@ pub fn replicate(
     unsafe { RedisModule_Replicate(ctx, cmdname, fmt) }
 }
 
+/// Replicate `cmdname` with `args`, using the "v" format specifier of
+/// RedisModule_Replicate() so any number of binary safe arguments can be
+/// passed. `cmdname` must be nul terminated.
+pub fn replicate_argv(ctx: *mut RedisModuleCtx, cmdname: &[u8], args: &[&[u8]]) -> Status {
+    let mut argv: Vec<*mut RedisModuleString> = args
+        .iter()
+        .map(|arg| create_string(ctx, arg.as_ptr(), arg.len()))
+        .collect();
+    let status = unsafe {
+        RedisModule_Replicate(ctx, cmdname.as_ptr(), b"v\0".as_ptr(), argv.as_mut_ptr(), argv.len())
+    };
+    // The replicated command holds its own references.
+    for arg in argv {
+        free_string(ctx, arg);
+    }
+    status
+}
+
 pub fn replicate_verbatim(ctx: *mut RedisModuleCtx) -> Status {
     unsafe { RedisModule_ReplicateVerbatim(ctx) }
 }
@@ -1631,10 +1650,11 @@ extern "C" {
 
 
     pub static RedisModule_Replicate:
-    extern "C" fn(
+    unsafe extern "C" fn(
         ctx: *mut RedisModuleCtx,
         cmdname: *const u8,
         fmt: *const u8,
+        ...
     ) -> Status;
 
     static RedisModule_ReplicateVerbatim:
@@ -0,0 +1,453 @@
+#![allow(dead_code)]
+
+use libc;
+use std;
+use std::marker::PhantomData;
+
+pub const QUICKLIST_HEAD: libc::c_int = 0;
+pub const QUICKLIST_TAIL: libc::c_int = -1;
+
+/// Iterator directions.
+pub const AL_START_HEAD: libc::c_int = 0;
+pub const AL_START_TAIL: libc::c_int = 1;
+
+/// Fill factor used by `quicklistCreate()`. Negative values limit nodes
+/// by size (-2 is 8kb per node), positive values by entry count.
+pub const DEFAULT_FILL: i32 = -2;
+
+/// A doubly linked list of listpacks. Nodes other than the `compress_depth`
+/// nodes at each end are LZF compressed, which keeps long queues cheap while
+/// keeping push/pop at both ends fast.
+pub struct Quicklist {
+    ql: *mut quicklist,
+}
+
+impl Quicklist {
+    /// Create a quicklist with the default fill factor and no compression.
+    pub fn new() -> Quicklist {
+        Quicklist { ql: unsafe { quicklistCreate() } }
+    }
+
+    /// Create a quicklist with the given fill factor and compression depth.
+    /// A depth of 0 disables compression, otherwise it is the number of
+    /// nodes at each end of the list that are left uncompressed.
+    pub fn with_options(fill: i32, compress_depth: u16) -> Quicklist {
+        Quicklist {
+            ql: unsafe {
+                quicklistNew(fill as libc::c_int, compress_depth as libc::c_int)
+            }
+        }
+    }
+
+    #[inline]
+    pub fn set_compress_depth(&mut self, depth: u16) {
+        unsafe { quicklistSetCompressDepth(self.ql, depth as libc::c_int) }
+    }
+
+    #[inline]
+    pub fn set_fill(&mut self, fill: i32) {
+        unsafe { quicklistSetFill(self.ql, fill as libc::c_int) }
+    }
+
+    /// The number of entries across all nodes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        unsafe { quicklistCount(self.ql) as usize }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of listpack nodes.
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        unsafe { (*self.ql).len as usize }
+    }
+
+    #[inline]
+    pub fn push_head(&mut self, value: &[u8]) {
+        unsafe {
+            quicklistPushHead(
+                self.ql,
+                value.as_ptr() as *mut libc::c_void,
+                value.len(),
+            );
+        }
+    }
+
+    #[inline]
+    pub fn push_tail(&mut self, value: &[u8]) {
+        unsafe {
+            quicklistPushTail(
+                self.ql,
+                value.as_ptr() as *mut libc::c_void,
+                value.len(),
+            );
+        }
+    }
+
+    #[inline]
+    pub fn pop_head(&mut self) -> Option<Vec<u8>> {
+        self.pop(QUICKLIST_HEAD)
+    }
+
+    #[inline]
+    pub fn pop_tail(&mut self) -> Option<Vec<u8>> {
+        self.pop(QUICKLIST_TAIL)
+    }
+
+    fn pop(&mut self, wh: libc::c_int) -> Option<Vec<u8>> {
+        unsafe {
+            let mut data: *mut u8 = std::ptr::null_mut();
+            let mut sz: libc::c_uint = 0;
+            let mut sval: libc::c_longlong = 0;
+
+            if quicklistPopCustom(
+                self.ql,
+                wh,
+                &mut data,
+                &mut sz,
+                &mut sval,
+                Some(quicklist_saver),
+            ) == 0 {
+                return None;
+            }
+
+            if data.is_null() {
+                // Integer encoded entries are handed back through 'sval'.
+                Some(sval.to_string().into_bytes())
+            } else {
+                // The saver handed over ownership of a boxed Vec.
+                Some(*Box::from_raw(data as *mut Vec<u8>))
+            }
+        }
+    }
+
+    /// Copy the entry at `index`. Negative indexes count from the tail,
+    /// -1 being the last entry.
+    pub fn index(&self, index: i64) -> Option<Vec<u8>> {
+        unsafe {
+            let iter = quicklistGetIteratorAtIdx(
+                self.ql,
+                AL_START_HEAD,
+                index as libc::c_longlong,
+            );
+            if iter.is_null() {
+                return None;
+            }
+
+            let mut entry: quicklistEntry = std::mem::zeroed();
+            let value = if quicklistNext(iter, &mut entry) == 1 {
+                Some(entry.to_vec())
+            } else {
+                None
+            };
+            // Releasing the iterator recompresses the node it decompressed.
+            quicklistReleaseIterator(iter);
+            value
+        }
+    }
+
+    /// Insert `value` so that it ends up at position `index`. An index equal
+    /// to the length appends. Returns false if `index` is out of range.
+    pub fn insert_at(&mut self, index: i64, value: &[u8]) -> bool {
+        if index == self.len() as i64 {
+            self.push_tail(value);
+            return true;
+        }
+
+        unsafe {
+            let mut entry: quicklistEntry = std::mem::zeroed();
+            if quicklistIndex(self.ql, index as libc::c_longlong, &mut entry) == 0 {
+                return false;
+            }
+            quicklistInsertBefore(
+                self.ql,
+                &mut entry,
+                value.as_ptr() as *mut libc::c_void,
+                value.len(),
+            );
+            true
+        }
+    }
+
+    /// Delete `count` entries starting at `start`. Returns true if anything
+    /// was deleted.
+    pub fn delete_range(&mut self, start: i64, count: i64) -> bool {
+        unsafe {
+            quicklistDelRange(
+                self.ql,
+                start as libc::c_long,
+                count as libc::c_long,
+            ) == 1
+        }
+    }
+
+    /// Iterate from head to tail.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter::new(self, AL_START_HEAD)
+    }
+
+    /// Iterate from tail to head.
+    pub fn iter_rev<'a>(&'a self) -> Iter<'a> {
+        Iter::new(self, AL_START_TAIL)
+    }
+}
+
+impl Default for Quicklist {
+    fn default() -> Self {
+        Quicklist::new()
+    }
+}
+
+// Map Drop -> "quicklistRelease"
+impl Drop for Quicklist {
+    fn drop(&mut self) {
+        unsafe { quicklistRelease(self.ql) }
+    }
+}
+
+/// Entries are copied out since moving to the next node recompresses the
+/// previous one, which frees the memory a borrowed slice would point into.
+pub struct Iter<'a> {
+    iter: *mut quicklistIter,
+    _marker: PhantomData<&'a Quicklist>,
+}
+
+impl<'a> Iter<'a> {
+    fn new(list: &'a Quicklist, direction: libc::c_int) -> Iter<'a> {
+        Iter {
+            iter: unsafe { quicklistGetIterator(list.ql, direction) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        unsafe {
+            let mut entry: quicklistEntry = std::mem::zeroed();
+            if quicklistNext(self.iter, &mut entry) == 1 {
+                Some(entry.to_vec())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Iter<'a> {
+    fn drop(&mut self) {
+        unsafe { quicklistReleaseIterator(self.iter) }
+    }
+}
+
+extern "C" fn quicklist_saver(data: *mut u8, sz: libc::c_uint) -> *mut libc::c_void {
+    unsafe {
+        let value = std::slice::from_raw_parts(data, sz as usize).to_vec();
+        Box::into_raw(Box::new(value)) as *mut libc::c_void
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct quicklistNode;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct quicklist {
+    head: *mut quicklistNode,
+    tail: *mut quicklistNode,
+    count: libc::c_ulong,
+    len: libc::c_ulong,
+    // int fill : 16; unsigned int compress : 16;
+    options: libc::c_int,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct quicklistIter;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct quicklistEntry {
+    quicklist: *const quicklist,
+    node: *mut quicklistNode,
+    zi: *mut u8,
+    value: *mut u8,
+    longval: libc::c_longlong,
+    sz: libc::c_uint,
+    offset: libc::c_int,
+}
+
+impl quicklistEntry {
+    fn to_vec(&self) -> Vec<u8> {
+        if self.value.is_null() {
+            self.longval.to_string().into_bytes()
+        } else {
+            unsafe { std::slice::from_raw_parts(self.value, self.sz as usize).to_vec() }
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+type quicklistSaver = extern "C" fn(data: *mut u8, sz: libc::c_uint) -> *mut libc::c_void;
+
+#[allow(improper_ctypes)]
+#[allow(non_snake_case)]
+#[link(name = "redismodule", kind = "static")]
+extern "C" {
+    fn quicklistCreate() -> *mut quicklist;
+
+    fn quicklistNew(
+        fill: libc::c_int,
+        compress: libc::c_int,
+    ) -> *mut quicklist;
+
+    fn quicklistSetCompressDepth(
+        quicklist: *mut quicklist,
+        depth: libc::c_int,
+    );
+
+    fn quicklistSetFill(
+        quicklist: *mut quicklist,
+        fill: libc::c_int,
+    );
+
+    fn quicklistRelease(quicklist: *mut quicklist);
+
+    fn quicklistPushHead(
+        quicklist: *mut quicklist,
+        value: *mut libc::c_void,
+        sz: libc::size_t,
+    ) -> libc::c_int;
+
+    fn quicklistPushTail(
+        quicklist: *mut quicklist,
+        value: *mut libc::c_void,
+        sz: libc::size_t,
+    ) -> libc::c_int;
+
+    fn quicklistInsertBefore(
+        quicklist: *mut quicklist,
+        entry: *mut quicklistEntry,
+        value: *mut libc::c_void,
+        sz: libc::size_t,
+    );
+
+    fn quicklistInsertAfter(
+        quicklist: *mut quicklist,
+        entry: *mut quicklistEntry,
+        value: *mut libc::c_void,
+        sz: libc::size_t,
+    );
+
+    fn quicklistDelRange(
+        quicklist: *mut quicklist,
+        start: libc::c_long,
+        count: libc::c_long,
+    ) -> libc::c_int;
+
+    fn quicklistGetIterator(
+        quicklist: *const quicklist,
+        direction: libc::c_int,
+    ) -> *mut quicklistIter;
+
+    fn quicklistGetIteratorAtIdx(
+        quicklist: *const quicklist,
+        direction: libc::c_int,
+        idx: libc::c_longlong,
+    ) -> *mut quicklistIter;
+
+    fn quicklistNext(
+        iter: *mut quicklistIter,
+        entry: *mut quicklistEntry,
+    ) -> libc::c_int;
+
+    fn quicklistReleaseIterator(iter: *mut quicklistIter);
+
+    fn quicklistIndex(
+        quicklist: *const quicklist,
+        index: libc::c_longlong,
+        entry: *mut quicklistEntry,
+    ) -> libc::c_int;
+
+    fn quicklistPopCustom(
+        quicklist: *mut quicklist,
+        wh: libc::c_int,
+        data: *mut *mut u8,
+        sz: *mut libc::c_uint,
+        sval: *mut libc::c_longlong,
+        saver: Option<quicklistSaver>,
+    ) -> libc::c_int;
+
+    fn quicklistCount(ql: *const quicklist) -> libc::c_ulong;
+}
+
+#[cfg(test)]
+mod tests {
+    use quicklist::Quicklist;
+
+    #[test]
+    fn push_pop() {
+        let mut ql = Quicklist::new();
+        ql.push_tail(b"b");
+        ql.push_tail(b"c");
+        ql.push_head(b"a");
+        ql.push_tail(b"100");
+
+        assert_eq!(ql.len(), 4);
+        assert_eq!(ql.index(0), Some(b"a".to_vec()));
+        assert_eq!(ql.index(-1), Some(b"100".to_vec()));
+        assert_eq!(ql.index(4), None);
+
+        assert_eq!(ql.pop_head(), Some(b"a".to_vec()));
+        assert_eq!(ql.pop_tail(), Some(b"100".to_vec()));
+        assert_eq!(ql.pop_tail(), Some(b"c".to_vec()));
+        assert_eq!(ql.pop_tail(), Some(b"b".to_vec()));
+        assert_eq!(ql.pop_tail(), None);
+        assert!(ql.is_empty());
+    }
+
+    #[test]
+    fn insert_and_delete_range() {
+        let mut ql = Quicklist::new();
+        for i in 0..10 {
+            ql.push_tail(format!("item-{}", i).as_bytes());
+        }
+
+        assert!(ql.insert_at(5, b"middle"));
+        assert!(ql.insert_at(11, b"end"));
+        assert!(!ql.insert_at(100, b"nope"));
+        assert_eq!(ql.index(5), Some(b"middle".to_vec()));
+        assert_eq!(ql.index(-1), Some(b"end".to_vec()));
+
+        assert!(ql.delete_range(0, 5));
+        assert_eq!(ql.len(), 7);
+        assert_eq!(ql.index(0), Some(b"middle".to_vec()));
+
+        let rev: Vec<Vec<u8>> = ql.iter_rev().collect();
+        assert_eq!(rev.first(), Some(&b"end".to_vec()));
+        assert_eq!(rev.last(), Some(&b"middle".to_vec()));
+    }
+
+    #[test]
+    fn compressed_nodes() {
+        // Small nodes with only the head and tail left uncompressed.
+        let mut ql = Quicklist::with_options(16, 1);
+        let value = "some fairly compressible job payload ".repeat(8);
+        for i in 0..1000 {
+            ql.push_tail(format!("{}{}", value, i).as_bytes());
+        }
+        assert!(ql.node_count() > 2);
+
+        for (i, item) in ql.iter().enumerate() {
+            assert_eq!(item, format!("{}{}", value, i).into_bytes());
+        }
+        assert_eq!(ql.index(500), Some(format!("{}{}", value, 500).into_bytes()));
+    }
+}
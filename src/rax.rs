@@ -1,6 +1,6 @@
 use error::RedError;
 use libc;
-use sds::SDS;
+use sds::SdsString;
 use std;
 use std::mem::{size_of, transmute};
 use std::ptr;
@@ -230,6 +230,25 @@ impl<K: RaxKey, V> RaxMap<K, V> {
         }
     }
 
+    /// Find by the key bytes as stored, after `RaxKey::encode`. Saves
+    /// building a `K` for the keys stored as their own bytes, like
+    /// `SdsString`.
+    pub fn find_bytes(&self, key: &[u8]) -> Option<&V> {
+        unsafe {
+            let value = raxFind(
+                self.rax,
+                key.as_ptr(),
+                key.len(),
+            );
+
+            if value.is_null() || value == raxNotFound {
+                None
+            } else {
+                Some(std::mem::transmute(value))
+            }
+        }
+    }
+
     ///
     #[inline]
     pub fn seek_min<F>(
@@ -735,8 +754,8 @@ impl RaxKey for u128 {
     }
 }
 
-impl RaxKey for SDS {
-    type Output = SDS;
+impl RaxKey for SdsString {
+    type Output = SdsString;
 
     #[inline]
     fn encode(self) -> Self::Output {
@@ -749,8 +768,8 @@ impl RaxKey for SDS {
     }
 
     #[inline]
-    fn from_buf(ptr: *const u8, len: usize) -> SDS {
-        SDS::from_ptr(ptr, len)
+    fn from_buf(ptr: *const u8, len: usize) -> SdsString {
+        unsafe { SdsString::from_ptr(ptr, len) }
     }
 }
 
@@ -1027,7 +1046,11 @@ impl<K: RaxKey, V> RaxCursor<K, V> {
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct rax;
+pub struct rax {
+    pub head: *mut raxNode,
+    pub numele: libc::uint64_t,
+    pub numnodes: libc::uint64_t,
+}
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -1043,8 +1066,21 @@ pub struct raxStack {
     oom: libc::c_int,
 }
 
+/// The raw C layout of a rax iterator, for embedding into other C structs
+/// such as `streamIterator`. `RaxCursor` is the typed Rust counterpart.
 #[repr(C)]
-pub struct raxIterator;
+pub struct raxIterator {
+    pub flags: libc::c_int,
+    pub rt: *mut rax,
+    pub key: *mut u8,
+    pub data: *mut libc::c_void,
+    pub key_len: libc::size_t,
+    pub key_max: libc::size_t,
+    pub key_static_string: [u8; 128],
+    pub node: *mut raxNode,
+    pub stack: raxStack,
+    pub node_cb: Option<raxNodeCallback>,
+}
 
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
@@ -1056,7 +1092,7 @@ extern "C" fn RaxFreeWithCallbackWrapper<V>(v: *mut libc::c_void) {
 }
 
 #[allow(non_camel_case_types)]
-type raxNodeCallback = extern "C" fn(v: *mut libc::c_void);
+pub type raxNodeCallback = extern "C" fn(v: *mut libc::c_void);
 
 
 type RaxFreeCallback = extern "C" fn(v: *mut libc::c_void);
@@ -1131,7 +1167,7 @@ extern "C" {
         old: &mut *mut u8,
     ) -> libc::c_int;
 
-    fn raxFind(
+    pub fn raxFind(
         rax: *mut rax,
         s: *const u8,
         len: libc::size_t,
@@ -1141,23 +1177,23 @@ extern "C" {
         rt: *mut rax
     ) -> *mut raxIterator;
 
-    fn raxStart(
+    pub fn raxStart(
         it: *const raxIterator,
         rt: *mut rax,
     );
 
-    fn raxSeek(
+    pub fn raxSeek(
         it: *const raxIterator,
         op: *const u8,
         ele: *const u8,
         len: libc::size_t,
     ) -> libc::c_int;
 
-    fn raxNext(
+    pub fn raxNext(
         it: *const raxIterator
     ) -> libc::c_int;
 
-    fn raxPrev(
+    pub fn raxPrev(
         it: *const raxIterator
     ) -> libc::c_int;
 
@@ -1173,7 +1209,7 @@ extern "C" {
         key_len: libc::size_t,
     ) -> libc::c_int;
 
-    fn raxStop(
+    pub fn raxStop(
         it: *const raxIterator
     );
 
@@ -1185,7 +1221,7 @@ extern "C" {
         rax: *mut rax
     );
 
-    fn raxSize(
+    pub fn raxSize(
         rax: *mut rax
     ) -> libc::uint64_t;
 }
@@ -1598,14 +1634,14 @@ mod tests {
 
     #[test]
     fn test_keyed() {
-        let mut r = RaxMap::<sds::SDS, MyMsg>::new();
+        let mut r = RaxMap::<sds::SdsString, MyMsg>::new();
 
         r.insert(
-            sds::SDS::new("hello"),
+            sds::SdsString::new("hello"),
             Box::new(MyMsg("world x10")),
         ).expect("whoops!");
 
-        match r.find(sds::SDS::new("hi")) {
+        match r.find(sds::SdsString::new("hi")) {
             Some(v) => {
                 println!("Found: {}", v.0);
             }
@@ -1615,7 +1651,7 @@ mod tests {
         };
 
 
-        match r.find(sds::SDS::new("hello")) {
+        match r.find(sds::SdsString::new("hello")) {
             Some(v) => {
                 println!("Found: {}", v.0);
             }
@@ -1624,21 +1660,21 @@ mod tests {
             }
         };
 
-        r.remove(sds::SDS::new("hello"));
+        r.remove(sds::SdsString::new("hello"));
         r.insert(
-            sds::SDS::new("hello"),
+            sds::SdsString::new("hello"),
             Box::new(MyMsg("world x10")),
         ).expect("whoops!");
         r.insert(
-            sds::SDS::new("hello-16"),
+            sds::SdsString::new("hello-16"),
             Box::new(MyMsg("world x11")),
         ).expect("whoops!");
         r.insert(
-            sds::SDS::new("hello-20"),
+            sds::SdsString::new("hello-20"),
             Box::new(MyMsg("world x12")),
         ).expect("whoops!");
         r.insert(
-            sds::SDS::new("hello-01"),
+            sds::SdsString::new("hello-01"),
             Box::new(MyMsg("world x13")),
         ).expect("whoops!");
 
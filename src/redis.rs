@@ -118,45 +118,40 @@ pub struct Redis {
     pub ctx: *mut mod_api::RedisModuleCtx,
 }
 
-extern "C" fn sliced_timer_callback(_value: *mut libc::c_void) {
-    // Ignore
-}
+/// The closure of a timer, boxed again to pass a thin pointer to Redis.
+type TimerClosure = Box<Box<dyn FnOnce(Redis)>>;
 
-extern "C" fn sliced_timer_callback_wrapper<F>(
-    closure: *mut libc::c_void) where F: Fn() {
-    let closure = closure as *mut F;
-    unsafe {
-        (*closure)();
-    }
+extern "C" fn sliced_timer_callback(ctx: *mut mod_api::RedisModuleCtx, data: *mut libc::c_void) {
+    let closure = unsafe { Box::from_raw(data as *mut Box<dyn FnOnce(Redis)>) };
+    closure(Redis { ctx });
 }
 
 impl Redis {
     /// Executes the closure on the Redis event-loop after the specified
-    /// time in milliseconds have elapsed.
-    pub fn start_timer<F>(&self, millis: i64, _f: F) -> TimerID where F: Fn() {
-        let mut x = 0 as *mut u8;
+    /// time in milliseconds have elapsed. The closure gets the context of
+    /// the timer, the one of the caller is gone by then.
+    pub fn start_timer<F>(&self, millis: i64, f: F) -> TimerID where F: FnOnce(Redis) + 'static {
+        let closure: TimerClosure = Box::new(Box::new(f));
         mod_api::create_timer(
             self.ctx,
             millis,
-            Some(sliced_timer_callback_wrapper::<F>),
-            (&mut x) as *mut _ as *mut libc::c_void)
+            Some(sliced_timer_callback),
+            Box::into_raw(closure) as *mut libc::c_void)
     }
 
     /// Executes the closure on the Redis event-loop.
-    /// This can be called from background threads.
-    pub fn run<F>(&self, _f: F) -> TimerID where F: Fn() {
-        let mut x = 0 as *mut u8;
-        mod_api::create_timer(
-            self.ctx,
-            0,
-            Some(sliced_timer_callback_wrapper::<F>),
-            (&mut x) as *mut _ as *mut libc::c_void)
+    pub fn run<F>(&self, f: F) -> TimerID where F: FnOnce(Redis) + 'static {
+        self.start_timer(0, f)
     }
 
-    /// Cancels a timer by it's ID.
+    /// Cancels a timer by it's ID, dropping its closure.
     pub fn cancel_timer(&self, timer_id: TimerID) -> mod_api::Status {
-        let mut x = 0 as *mut u8;
-        mod_api::stop_timer(self.ctx, timer_id, (&mut x) as *mut _ as *mut *mut libc::c_void)
+        let mut data = ptr::null_mut();
+        let status = mod_api::stop_timer(self.ctx, timer_id, &mut data);
+        if status == mod_api::Status::Ok && !data.is_null() {
+            drop(unsafe { Box::from_raw(data as *mut Box<dyn FnOnce(Redis)>) });
+        }
+        status
     }
 
     ///
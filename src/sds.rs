@@ -1,7 +1,12 @@
+use error::RedError;
 use libc;
 use std;
+use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
 
 const SDS_TYPE_5: libc::c_char = 0;
 const SDS_TYPE_8: libc::c_char = 1;
@@ -15,81 +20,212 @@ const SDS_TYPE_BITS: libc::c_int = 3;
 pub type Sds = *mut libc::c_char;
 
 
-#[derive(Eq, Debug)]
+/// An owned, binary safe SDS string. The pointer is freed when this value is
+/// dropped, so the only ways to move it across the FFI boundary are
+/// `into_raw` and the unsafe `from_raw`.
 #[repr(C)]
-pub struct SDS(pub Sds);
+pub struct SdsString(Sds);
 
-impl Clone for SDS {
+impl Clone for SdsString {
     fn clone(&self) -> Self {
-        SDS(dup(self.0))
+        SdsString(dup(self.0))
     }
 }
 
-impl Default for SDS {
+impl Default for SdsString {
     fn default() -> Self {
-        return SDS(empty());
+        SdsString(empty())
     }
 }
 
-impl PartialEq for SDS {
-    fn eq(&self, other: &SDS) -> bool {
+impl PartialEq for SdsString {
+    fn eq(&self, other: &SdsString) -> bool {
         unsafe { sdscmp(self.0, other.0) == 0 }
     }
-
-    fn ne(&self, other: &SDS) -> bool {
-        unsafe { sdscmp(self.0, other.0) != 0 }
-    }
 }
 
-impl PartialOrd for SDS {
-    fn partial_cmp(&self, other: &SDS) -> Option<Ordering> {
+impl Eq for SdsString {}
+
+impl PartialOrd for SdsString {
+    fn partial_cmp(&self, other: &SdsString) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for SDS {
+impl Ord for SdsString {
     fn cmp(&self, other: &Self) -> Ordering {
-        match unsafe { sdscmp(self.0, other.0) } {
-            -1 => Ordering::Less,
-            0 => Ordering::Equal,
-            1 => Ordering::Greater,
-            _ => Ordering::Greater
-        }
+        // sdscmp() returns the memcmp() result which is not limited to -1/1.
+        unsafe { sdscmp(self.0, other.0) }.cmp(&0)
+    }
+}
+
+impl Hash for SdsString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl Deref for SdsString {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Hashes and compares as its bytes, so maps keyed by `SdsString` can be
+/// queried with a plain `&[u8]`.
+impl Borrow<[u8]> for SdsString {
+    #[inline]
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for SdsString {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<'a> From<&'a [u8]> for SdsString {
+    fn from(s: &'a [u8]) -> Self {
+        SdsString::new(s)
+    }
+}
+
+impl<'a> From<&'a str> for SdsString {
+    fn from(s: &'a str) -> Self {
+        SdsString::new(s)
+    }
+}
+
+impl From<String> for SdsString {
+    fn from(s: String) -> Self {
+        SdsString::new(s)
     }
 }
 
-impl fmt::Display for SDS {
+impl From<Vec<u8>> for SdsString {
+    fn from(s: Vec<u8>) -> Self {
+        SdsString::new(s)
+    }
+}
+
+impl fmt::Display for SdsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+impl fmt::Debug for SdsString {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        f.write_str(self.to_str()).expect("");
+        write!(f, "{:?}", String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+impl fmt::Write for SdsString {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl std::io::Write for SdsString {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.push_bytes(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.push_bytes(buf);
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
 }
 
-impl Drop for SDS {
+impl Drop for SdsString {
     fn drop(&mut self) {
         free(self.0)
     }
 }
 
-impl SDS {
+impl SdsString {
+    #[inline]
+    pub fn empty() -> SdsString {
+        SdsString(empty())
+    }
+
+    /// Copy the bytes into a new SDS allocation.
+    #[inline]
+    pub fn new<T: AsRef<[u8]>>(s: T) -> SdsString {
+        let s = s.as_ref();
+        unsafe { SdsString(sdsnewlen(s.as_ptr(), s.len())) }
+    }
+
+    /// Copy `initlen` bytes at `init`.
+    ///
+    /// # Safety
+    ///
+    /// `init` must be valid for reads of `initlen` bytes.
     #[inline]
-    pub fn empty() -> SDS {
-        SDS(empty())
+    pub unsafe fn from_ptr(init: *const u8, initlen: usize) -> SdsString {
+        SdsString(sdsnewlen(init, initlen))
     }
 
+    /// Copy the C string `s`.
+    ///
+    /// # Safety
+    ///
+    /// `s` must point to a null terminated string.
     #[inline]
-    pub fn new(s: &str) -> SDS {
-        SDS(new_len(s))
+    pub unsafe fn from_cstr(s: *const u8) -> SdsString {
+        SdsString(sdsnew(s))
     }
 
     #[inline]
-    pub fn from_ptr(init: *const u8, initlen: usize) -> SDS {
-        unsafe { SDS(sdsnewlen(init, initlen)) }
+    pub fn from_long_long(value: libc::c_longlong) -> SdsString {
+        SdsString(from_long_long(value))
     }
 
+    /// Take ownership of a raw SDS pointer.
+    ///
+    /// # Safety
+    ///
+    /// It must not be used or freed by anyone else afterwards.
     #[inline]
-    pub fn from_cstr(s: *const u8) -> SDS {
-        unsafe { SDS(sdsnew(s)) }
+    pub unsafe fn from_raw(s: Sds) -> SdsString {
+        SdsString(s)
+    }
+
+    /// Hand the raw SDS pointer over to C. The caller becomes responsible
+    /// for freeing it, e.g. with `sdsfree()` or `SdsString::from_raw`.
+    #[inline]
+    pub fn into_raw(self) -> Sds {
+        let s = self.0;
+        std::mem::forget(self);
+        s
+    }
+
+    /// Borrow the raw pointer without giving up ownership.
+    #[inline]
+    pub fn as_raw(&self) -> Sds {
+        self.0
+    }
+
+    /// Borrow as a view with the same API as strings owned by C.
+    #[inline]
+    pub fn as_sds_ref<'a>(&'a self) -> SdsRef<'a> {
+        unsafe { SdsRef::from_raw(self.0) }
     }
 
     #[inline]
@@ -97,11 +233,27 @@ impl SDS {
         get_len(self.0)
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     #[inline]
     pub fn as_ptr(&self) -> *const u8 {
         self.0 as *const u8
     }
 
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.0 as *const u8, self.len()) }
+    }
+
+    /// The content as UTF-8. SDS strings are binary safe so this may fail.
+    #[inline]
+    pub fn as_str(&self) -> Result<&str, RedError> {
+        Ok(std::str::from_utf8(self.as_bytes())?)
+    }
+
     #[inline]
     pub fn avail(&self) -> usize {
         avail(self.0)
@@ -141,17 +293,6 @@ impl SDS {
         to_upper(self.0)
     }
 
-    #[inline]
-    pub fn to_str<'a>(&self) -> &'a str {
-        unsafe {
-            std::str::from_utf8(
-                std::slice::from_raw_parts(
-                    self.0 as *const u8,
-                    self.len())
-            ).unwrap()
-        }
-    }
-
     #[inline]
     pub fn sds_type(&self) -> libc::c_int {
         get_type(self.0)
@@ -212,23 +353,333 @@ impl SDS {
     /// sdsIncrLen(s, nread);
     #[inline]
     pub fn incr_len(&mut self, incr: libc::ssize_t) -> bool {
-        let new_len = self.len() + incr as usize;
-        if new_len > self.alloc_size() {
+        if incr >= 0 {
+            if incr as usize > self.avail() {
+                return false;
+            }
+        } else if incr.unsigned_abs() > self.len() {
             return false;
         }
         incr_len(self.0, incr);
         true
     }
 
+    /// Append binary safe bytes, growing the buffer with sdsMakeRoomFor()
+    /// and copying straight into the free space at the end.
+    pub fn push_bytes(&mut self, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        self.extend(buf.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                buf.as_ptr(),
+                (self.0 as *mut u8).add(self.len()),
+                buf.len(),
+            );
+        }
+        incr_len(self.0, buf.len() as libc::ssize_t);
+    }
+
+    /// Append the specified binary-safe bytes to the string.
     #[inline]
-    pub fn incr_len_dangerously(&mut self, incr: libc::ssize_t) -> bool {
-        incr_len(self.0, incr);
+    pub fn cat(&mut self, t: &[u8]) {
+        self.0 = unsafe { sdscatlen(self.0, t.as_ptr(), t.len()) }
+    }
+
+    /// Append another sds string.
+    #[inline]
+    pub fn cat_sds(&mut self, t: &SdsString) {
+        self.cat(t.as_bytes())
+    }
+
+    /// Append an escaped representation of `p` where all the non-printable
+    /// characters (tested with isprint()) are turned into escapes in the form
+    /// "\n\r\a...." or "\x<hex-number>". The result is quoted.
+    #[inline]
+    pub fn cat_repr(&mut self, p: &[u8]) {
+        self.0 = unsafe { sdscatrepr(self.0, p.as_ptr(), p.len()) }
+    }
+
+    /// Append using the small subset of printf-alike directives understood
+    /// by sdscatfmt():
+    ///
+    /// %s, %S - binary safe string (FmtArg::Str)
+    /// %i, %I - signed integer (FmtArg::Int)
+    /// %u, %U - unsigned integer (FmtArg::UInt)
+    /// %%     - verbatim "%" character.
+    ///
+    /// Arguments are type checked against the directives before anything
+    /// is appended.
+    pub fn cat_fmt(&mut self, fmt: &str, args: &[FmtArg]) -> Result<(), RedError> {
+        let fmt = fmt.as_bytes();
+        let mut directives = 0;
+        let mut i = 0;
+        while i < fmt.len() {
+            if fmt[i] == b'%' {
+                let matches = match (fmt.get(i + 1), args.get(directives)) {
+                    (Some(&b'%'), _) => {
+                        i += 2;
+                        continue;
+                    }
+                    (Some(&b's'), Some(&FmtArg::Str(_)))
+                    | (Some(&b'S'), Some(&FmtArg::Str(_)))
+                    | (Some(&b'i'), Some(&FmtArg::Int(_)))
+                    | (Some(&b'I'), Some(&FmtArg::Int(_)))
+                    | (Some(&b'u'), Some(&FmtArg::UInt(_)))
+                    | (Some(&b'U'), Some(&FmtArg::UInt(_))) => true,
+                    _ => false,
+                };
+                if !matches {
+                    return Err(RedError::generic("format directive does not match argument"));
+                }
+                directives += 1;
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+        if directives != args.len() {
+            return Err(RedError::generic("wrong number of format arguments"));
+        }
+
+        let mut literal = 0;
+        let mut arg = 0;
+        let mut i = 0;
+        while i < fmt.len() {
+            if fmt[i] != b'%' {
+                i += 1;
+                continue;
+            }
+            self.cat(&fmt[literal..i]);
+            match fmt[i + 1] {
+                b'%' => self.cat(b"%"),
+                _ => {
+                    match args[arg] {
+                        FmtArg::Str(v) => self.cat(v),
+                        FmtArg::Int(v) => unsafe {
+                            self.0 = sdscatfmt(self.0, b"%I\0".as_ptr() as *const libc::c_char, v)
+                        },
+                        FmtArg::UInt(v) => unsafe {
+                            self.0 = sdscatfmt(self.0, b"%U\0".as_ptr() as *const libc::c_char, v)
+                        },
+                    }
+                    arg += 1;
+                }
+            }
+            i += 2;
+            literal = i;
+        }
+        self.cat(&fmt[literal..]);
+        Ok(())
+    }
+
+    /// Destructively modify the string to overwrite it with the bytes in `t`.
+    #[inline]
+    pub fn cpy(&mut self, t: &[u8]) {
+        self.0 = unsafe { sdscpylen(self.0, t.as_ptr(), t.len()) }
+    }
+
+    /// Turn the string into a smaller (or equal) string containing only the
+    /// substring specified by the 'start' and 'end' indexes.
+    ///
+    /// start and end can be negative, where -1 means the last character of
+    /// the string, -2 the penultimate character, and so forth.
+    ///
+    /// The interval is inclusive, so the start and end characters will be
+    /// part of the resulting string.
+    #[inline]
+    pub fn range(&mut self, start: isize, end: isize) {
+        unsafe { sdsrange(self.0, start, end) }
+    }
+
+    /// Remove the part of the string from left and from right composed just
+    /// of contiguous characters found in 'cset'.
+    ///
+    /// A nul byte in 'cset' terminates the set, as it does in sdstrim().
+    pub fn trim(&mut self, cset: &[u8]) {
+        let mut c = Vec::with_capacity(cset.len() + 1);
+        c.extend_from_slice(cset);
+        c.push(0);
+        self.0 = unsafe { sdstrim(self.0, c.as_ptr() as *const libc::c_char) }
+    }
+
+    /// Replace every occurrence of a byte in 'from' with the byte at the same
+    /// position in 'to'. Returns false, leaving the string alone, if the two
+    /// sets differ in length.
+    pub fn map_chars(&mut self, from: &[u8], to: &[u8]) -> bool {
+        if from.len() != to.len() {
+            return false;
+        }
+        self.0 = unsafe {
+            sdsmapchars(
+                self.0,
+                from.as_ptr() as *const libc::c_char,
+                to.as_ptr() as *const libc::c_char,
+                from.len(),
+            )
+        };
         true
     }
+
+    /// Split 's' with separator 'sep'. The separator may be a multi character
+    /// string. An empty separator yields no tokens.
+    pub fn split(s: &[u8], sep: &[u8]) -> Vec<SdsString> {
+        let mut count: libc::c_int = 0;
+        unsafe {
+            let tokens = sdssplitlen(
+                s.as_ptr() as *const libc::c_char,
+                s.len() as libc::ssize_t,
+                sep.as_ptr() as *const libc::c_char,
+                sep.len() as libc::c_int,
+                &mut count,
+            );
+            take_tokens(tokens, count)
+        }
+    }
+
+    /// Split a line into arguments, where every argument can be in the
+    /// following programming-language REPL-alike form:
+    ///
+    /// foo bar "newline are supported\n" and "\xff\x00otherstuff"
+    ///
+    /// This is the tokenizer Redis uses for inline commands. Returns None on
+    /// unbalanced quotes or a closed quote followed by a non space character.
+    pub fn split_args(line: &[u8]) -> Option<Vec<SdsString>> {
+        let mut l = Vec::with_capacity(line.len() + 1);
+        l.extend_from_slice(line);
+        l.push(0);
+        let mut argc: libc::c_int = 0;
+        unsafe {
+            let argv = sdssplitargs(l.as_ptr() as *const libc::c_char, &mut argc);
+            if argv.is_null() {
+                None
+            } else {
+                Some(take_tokens(argv, argc))
+            }
+        }
+    }
+
+    /// Join an array of sds strings using the specified separator.
+    pub fn join(parts: &[SdsString], sep: &[u8]) -> SdsString {
+        // SdsString is repr(C) over a single Sds so the slice is a valid sds*.
+        unsafe {
+            SdsString::from_raw(sdsjoinsds(
+                parts.as_ptr() as *mut Sds,
+                parts.len() as libc::c_int,
+                sep.as_ptr() as *const libc::c_char,
+                sep.len(),
+            ))
+        }
+    }
+}
+
+/// A typed argument for `SdsString::cat_fmt`.
+#[derive(Clone, Copy, Debug)]
+pub enum FmtArg<'a> {
+    Str(&'a [u8]),
+    Int(i64),
+    UInt(u64),
+}
+
+/// Take ownership of every token in an sds array returned by sdssplitlen()
+/// or sdssplitargs() and release the array itself.
+unsafe fn take_tokens(tokens: *mut Sds, count: libc::c_int) -> Vec<SdsString> {
+    if tokens.is_null() {
+        return Vec::new();
+    }
+    let v = (0..count as usize)
+        .map(|i| SdsString::from_raw(*tokens.add(i)))
+        .collect();
+    sds_free(tokens as *mut libc::c_void);
+    v
+}
+
+/// A borrowed view of an SDS string owned by someone else, typically C code
+/// such as `streamConsumer.name`. It is never freed by Rust.
+#[derive(Clone, Copy)]
+pub struct SdsRef<'a> {
+    s: Sds,
+    _marker: PhantomData<&'a [u8]>,
+}
+
+impl<'a> SdsRef<'a> {
+    /// Borrow a raw SDS pointer for the lifetime 'a.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must stay valid and unmodified for that long.
+    #[inline]
+    pub unsafe fn from_raw(s: Sds) -> SdsRef<'a> {
+        SdsRef { s, _marker: PhantomData }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        get_len(self.s)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.s as *const u8
+    }
+
+    #[inline]
+    pub fn as_raw(&self) -> Sds {
+        self.s
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        unsafe { std::slice::from_raw_parts(self.s as *const u8, self.len()) }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> Result<&'a str, RedError> {
+        Ok(std::str::from_utf8(self.as_bytes())?)
+    }
+
+    /// Copy into an owned string.
+    #[inline]
+    pub fn to_sds_string(&self) -> SdsString {
+        SdsString(dup(self.s))
+    }
+}
+
+impl<'a> Deref for SdsRef<'a> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<'a> PartialEq for SdsRef<'a> {
+    fn eq(&self, other: &SdsRef<'a>) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'a> fmt::Display for SdsRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+impl<'a> fmt::Debug for SdsRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:?}", String::from_utf8_lossy(self.as_bytes()))
+    }
 }
 
 #[inline]
-pub fn new_raw(s: *const u8, len: usize) -> Sds {
+fn new_raw(s: *const u8, len: usize) -> Sds {
     unsafe { sdsnewlen(s, len) }
 }
 
@@ -250,13 +701,6 @@ pub fn new_len(s: &str) -> Sds {
     unsafe { sdsnewlen(s.as_ptr(), s.len()) }
 }
 
-/// Create a new sds string starting from a null terminated C string.
-#[inline]
-pub fn new(s: &str) -> Sds {
-//    unsafe { sdsnew(format!("{}\0", s).as_ptr()) }
-    unsafe { sdsnewlen(s.as_ptr(), s.len()) }
-}
-
 #[inline]
 pub fn from_long_long(value: libc::c_longlong) -> Sds {
     unsafe { sdsfromlonglong(value) }
@@ -271,13 +715,13 @@ pub fn empty() -> Sds {
 
 /// Free an sds string. No operation is performed if 's' is NULL.
 #[inline]
-pub fn free(s: Sds) {
+fn free(s: Sds) {
     unsafe { sdsfree(s) }
 }
 
 /// Duplicate an sds string.
 #[inline]
-pub fn dup(s: Sds) -> Sds {
+fn dup(s: Sds) -> Sds {
     unsafe { sdsdup(s) }
 }
 
@@ -286,7 +730,7 @@ pub fn dup(s: Sds) -> Sds {
 /// so that next append operations will not require allocations up to the
 /// number of bytes previously available.
 #[inline]
-pub fn clear(s: Sds) {
+fn clear(s: Sds) {
     unsafe { sdsclear(s) }
 }
 
@@ -297,7 +741,7 @@ pub fn clear(s: Sds) {
 /// Note: this does not change the *length* of the sds string as returned
 /// by sdslen(), but only the free buffer space we have.
 #[inline]
-pub fn make_room_for(s: Sds, addlen: libc::size_t) -> Sds {
+fn make_room_for(s: Sds, addlen: libc::size_t) -> Sds {
     unsafe { sdsMakeRoomFor(s, addlen) }
 }
 
@@ -308,7 +752,7 @@ pub fn make_room_for(s: Sds, addlen: libc::size_t) -> Sds {
 /// After the call, the passed sds string is no longer valid and all the
 /// references must be substituted with the new pointer returned by the call.
 #[inline]
-pub fn remove_free_space(s: Sds) -> Sds {
+fn remove_free_space(s: Sds) -> Sds {
     unsafe { sdsRemoveFreeSpace(s) }
 }
 
@@ -335,7 +779,7 @@ pub fn remove_free_space(s: Sds) -> Sds {
 /// ... check for nread <= 0 and handle it ...
 /// sdsIncrLen(s, nread);
 #[inline]
-pub fn incr_len(s: Sds, incr: libc::ssize_t) {
+fn incr_len(s: Sds, incr: libc::ssize_t) {
     unsafe { sdsIncrLen(s, incr) }
 }
 
@@ -351,33 +795,33 @@ pub fn incr_len(s: Sds, incr: libc::ssize_t) {
 /// additional characters, the longer string is considered to be greater than
 /// the smaller one.
 #[inline]
-pub fn cmp(s1: Sds, s2: Sds) -> libc::c_int {
+fn cmp(s1: Sds, s2: Sds) -> libc::c_int {
     unsafe { sdscmp(s1, s2) }
 }
 
 #[inline]
-pub fn get_len(s: Sds) -> libc::size_t {
+pub(crate) fn get_len(s: Sds) -> libc::size_t {
     unsafe { sds_getlen(s) }
 }
 
 #[inline]
-pub fn avail(s: Sds) -> libc::size_t {
+fn avail(s: Sds) -> libc::size_t {
     unsafe { sds_avail(s) }
 }
 
 #[inline]
-pub fn grow_zero(s: Sds, len: libc::size_t) -> Sds {
+fn grow_zero(s: Sds, len: libc::size_t) -> Sds {
     unsafe { sdsgrowzero(s, len) }
 }
 
 
 #[inline]
-pub fn to_lower(s: Sds) {
+fn to_lower(s: Sds) {
     unsafe { sdstolower(s) }
 }
 
 #[inline]
-pub fn to_upper(s: Sds) {
+fn to_upper(s: Sds) {
     unsafe { sdstoupper(s) }
 }
 
@@ -388,19 +832,19 @@ pub fn to_upper(s: Sds) {
 /// 3) The free buffer at the end if any.
 /// 4) The implicit null term.
 #[inline]
-pub fn alloc_size(s: Sds) -> libc::size_t {
+fn alloc_size(s: Sds) -> libc::size_t {
     unsafe { sdsAllocSize(s) }
 }
 
 /// Return the pointer of the actual SDS allocation (normally SDS strings
 /// are referenced by the start of the string buffer).
 #[inline]
-pub fn alloc_ptr(s: Sds) -> *mut libc::c_void {
+fn alloc_ptr(s: Sds) -> *mut libc::c_void {
     unsafe { sdsAllocPtr(s) }
 }
 
 #[inline]
-pub fn get_type(s: Sds) -> libc::c_int {
+fn get_type(s: Sds) -> libc::c_int {
     unsafe { sds_type(s) }
 }
 
@@ -410,7 +854,7 @@ pub fn hdr_size_for(t: libc::c_char) -> libc::c_int {
 }
 
 #[inline]
-pub fn hdr_size(s: Sds) -> libc::c_int {
+fn hdr_size(s: Sds) -> libc::c_int {
     unsafe { sds_get_hdr_size(s) }
 }
 
@@ -461,68 +905,176 @@ extern "C" {
 
     fn sdsAllocPtr(s: Sds) -> *mut libc::c_void;
 
+    fn sdscatlen(s: Sds, t: *const u8, len: libc::size_t) -> Sds;
+
+    fn sdscpylen(s: Sds, t: *const u8, len: libc::size_t) -> Sds;
+
+    fn sdscatrepr(s: Sds, p: *const u8, len: libc::size_t) -> Sds;
+
+    fn sdscatfmt(s: Sds, fmt: *const libc::c_char, ...) -> Sds;
+
+    fn sdstrim(s: Sds, cset: *const libc::c_char) -> Sds;
+
+    fn sdsrange(s: Sds, start: libc::ssize_t, end: libc::ssize_t);
+
+    fn sdssplitlen(
+        s: *const libc::c_char,
+        len: libc::ssize_t,
+        sep: *const libc::c_char,
+        seplen: libc::c_int,
+        count: *mut libc::c_int,
+    ) -> *mut Sds;
+
+    fn sdssplitargs(line: *const libc::c_char, argc: *mut libc::c_int) -> *mut Sds;
+
+    fn sdsmapchars(s: Sds, from: *const libc::c_char, to: *const libc::c_char, setlen: libc::size_t) -> Sds;
+
+    fn sdsjoinsds(argv: *mut Sds, argc: libc::c_int, sep: *const libc::c_char, seplen: libc::size_t) -> Sds;
+
+    fn sds_free(ptr: *mut libc::c_void);
+
 //    fn sdsll2str(s: )
 }
 
 #[cfg(test)]
 mod tests {
+    use libc;
     use sds;
-    use sds::SDS;
+    use sds::SdsString;
     use std;
     use std::cmp::Ordering;
 
     #[test]
     fn test_cmp() {
-        let s1 = SDS::new("test");
-        let s2 = SDS::new("test1");
+        let s1 = SdsString::new("test");
+        let s2 = SdsString::new("test1");
 
         assert_eq!(s1.cmp(&s2), Ordering::Less);
         assert_eq!(s2.cmp(&s1), Ordering::Greater);
-        assert_eq!(SDS::new("test").cmp(&s1), Ordering::Equal);
+        assert_eq!(SdsString::new("test").cmp(&s1), Ordering::Equal);
     }
 
     #[test]
-    fn test_len() {
-        println!("{}", std::mem::size_of::<sds::SDS>());
-        println!("{}", std::mem::size_of::<sds::Sds>());
-
-        let mut ss = sds::SDS::new("hello");
-
-        println!("HDR Size: {}", ss.hdr_size());
-
-        println!("HDR5 Size: {}", sds::hdr_size_for(sds::SDS_TYPE_5));
-        println!("HDR8 Size: {}", sds::hdr_size_for(sds::SDS_TYPE_8));
-        println!("HDR16 Size: {}", sds::hdr_size_for(sds::SDS_TYPE_16));
-        println!("HDR32 Size: {}", sds::hdr_size_for(sds::SDS_TYPE_32));
-        println!("HDR64 Size: {}", sds::hdr_size_for(sds::SDS_TYPE_64));
+    fn test_binary_safe() {
+        let bytes: &[u8] = b"a\0b\xff";
+        let s = SdsString::from(bytes);
+        assert_eq!(s.len(), 4);
+        assert_eq!(&s[..], bytes);
+        assert!(s.as_str().is_err());
+
+        let s = SdsString::from(String::from("hello"));
+        assert_eq!(s.as_str().unwrap(), "hello");
+        assert_eq!(s.clone(), s);
+    }
 
+    #[test]
+    fn test_raw_handoff() {
+        let raw = SdsString::new("owned by C now").into_raw();
+        {
+            let view = unsafe { sds::SdsRef::from_raw(raw) };
+            assert_eq!(view.as_bytes(), b"owned by C now");
+            assert_eq!(view.to_sds_string(), SdsString::new("owned by C now"));
+        }
+        let back = unsafe { SdsString::from_raw(raw) };
+        assert_eq!(back.as_str().unwrap(), "owned by C now");
+    }
 
-//        println!("Alloc Size: {}", sds::alloc_size(s));
-//        println!("Length    : {}", sds::len(s));
-//        println!("Avail Size: {}", sds::avail(s));
-//        sds::incr_len(s, 10);
-//        println!("Length    : {}", sds::len(s));
-//        println!("Avail Size: {}", sds::avail(s));
+    #[test]
+    fn test_write() {
+        use std::fmt::Write;
 
+        let mut s = SdsString::new("ids:");
+        for i in 0..100 {
+            write!(s, " {}-{}", i, i * 2).unwrap();
+        }
+        assert!(s.as_str().unwrap().starts_with("ids: 0-0 1-2 2-4"));
+        assert!(s.as_str().unwrap().ends_with(" 99-198"));
 
-        println!("Alloc Size: {}", ss.alloc_size());
-        println!("Length    : {}", ss.len());
-        println!("Avail Size: {}", ss.avail());
+        let mut b = SdsString::empty();
+        std::io::Write::write_all(&mut b, b"\x00\x01").unwrap();
+        writeln!(b, "{}", 42).unwrap();
+        assert_eq!(&b[..], b"\x00\x0142\n");
+    }
 
-        ss.extend(1);
-        println!("Alloc Size: {}", ss.alloc_size());
+    #[test]
+    fn test_incr_len_bounds() {
+        let mut s = SdsString::new("abc");
+        assert!(!s.incr_len(-4));
+        assert!(s.incr_len(-1));
+        assert_eq!(&s[..], b"ab");
+        let avail = s.avail() as libc::ssize_t;
+        assert!(!s.incr_len(avail + 1));
+    }
 
-//        ss.compact();
-//        println!("Alloc Size: {}", ss.alloc_size());
+    #[test]
+    fn test_manipulation() {
+        let mut s = SdsString::new("xxciaoyyy");
+        s.trim(b"xy");
+        assert_eq!(&s[..], b"ciao");
+        s.range(1, -1);
+        assert_eq!(&s[..], b"iao");
+        s.cat(b"\0!");
+        assert!(s.map_chars(b"ia", b"IA"));
+        assert_eq!(&s[..], b"IAo\0!");
+        assert!(!s.map_chars(b"IA", b"i"));
+        assert_eq!(&s[..], b"IAo\0!");
+        s.cpy(b"a");
+        s.cat_repr(b"\n\x01");
+        assert_eq!(&s[..], b"a\"\\n\\x01\"");
+    }
 
-        ss.incr_len(1);
-        println!("Alloc Size: {}", ss.alloc_size());
-        println!("Length    : {}", ss.len());
-        println!("Avail Size: {}", ss.avail());
+    #[test]
+    fn test_split_join() {
+        let parts = SdsString::split(b"foo_-_bar_-_", b"_-_");
+        assert_eq!(parts, vec![SdsString::new("foo"), SdsString::new("bar"), SdsString::empty()]);
+        assert_eq!(SdsString::join(&parts, b", "), SdsString::new("foo, bar, "));
+        assert!(SdsString::split(b"", b",").is_empty());
+
+        let args = SdsString::split_args(b"set \"k\\x00ey\" 'v a l'").unwrap();
+        assert_eq!(args, vec![SdsString::new("set"), SdsString::new(b"k\0ey"), SdsString::new("v a l")]);
+        assert!(SdsString::split_args(b"get \"unbalanced").is_none());
+    }
 
-        println!("HDR Size: {}", ss.hdr_size());
+    #[test]
+    fn test_cat_fmt() {
+        use sds::FmtArg;
+
+        let mut s = SdsString::new(">");
+        s.cat_fmt("%s-%I %u%%", &[FmtArg::Str(b"id"), FmtArg::Int(-42), FmtArg::UInt(7)])
+            .unwrap();
+        assert_eq!(&s[..], b">id--42 7%");
+        assert!(s.cat_fmt("%i", &[FmtArg::Str(b"x")]).is_err());
+        assert!(s.cat_fmt("%i %i", &[FmtArg::Int(1)]).is_err());
+        assert_eq!(&s[..], b">id--42 7%");
+    }
 
-//        unsafe {sds::sdslength(s);}
-//        assert_eq!(sds::sds_len(s), 5);
+    #[test]
+    fn test_len() {
+        assert_eq!(std::mem::size_of::<sds::SdsString>(), std::mem::size_of::<sds::Sds>());
+        let hdr_sizes: Vec<_> = [sds::SDS_TYPE_5, sds::SDS_TYPE_8, sds::SDS_TYPE_16, sds::SDS_TYPE_32, sds::SDS_TYPE_64]
+            .iter()
+            .map(|&t| sds::hdr_size_for(t))
+            .collect();
+        assert_eq!(hdr_sizes, vec![1, 3, 5, 9, 17]);
+
+        let mut ss = SdsString::new("hello");
+        assert_eq!(ss.hdr_size(), 1);
+        assert_eq!(ss.len(), 5);
+        assert_eq!(ss.avail(), 0);
+        assert!(!ss.incr_len(1));
+
+        // Type 5 strings cannot grow, room is made in a type 8 one.
+        ss.extend(1);
+        assert_eq!(ss.hdr_size(), 3);
+        let avail = ss.avail();
+        assert!(avail >= 1);
+        assert_eq!(ss.alloc_size(), ss.hdr_size() as usize + ss.len() + avail + 1);
+
+        assert!(ss.incr_len(1));
+        assert_eq!(ss.len(), 6);
+        assert_eq!(ss.avail(), avail - 1);
+        assert!(!ss.incr_len(avail as isize));
+        assert!(ss.incr_len(-6));
+        assert!(ss.is_empty());
     }
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+use rax::RaxMap;
+use sds::SdsString;
+use std::rc::Rc;
+
+/// Interns byte strings into shared SDS values. Stream field names and
+/// consumer names repeat millions of times, so every distinct string is
+/// allocated once and handed out as a reference-counted `SdsString`.
+///
+/// The pool holds one reference to every entry. Entries nobody else is
+/// holding on to are reclaimed by `purge`.
+pub struct SdsPool {
+    map: RaxMap<SdsString, Rc<SdsString>>,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SdsPoolStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SdsPoolStats {
+    /// Fraction of `intern` calls that were served without allocating.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl Default for SdsPool {
+    fn default() -> Self {
+        SdsPool::new()
+    }
+}
+
+impl SdsPool {
+    pub fn new() -> SdsPool {
+        SdsPool {
+            map: RaxMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Return the shared SDS for `s`, allocating it on first sight.
+    pub fn intern(&mut self, s: &[u8]) -> Rc<SdsString> {
+        if let Some(v) = self.map.find_bytes(s) {
+            self.hits += 1;
+            return v.clone();
+        }
+
+        self.misses += 1;
+        let v = Rc::new(SdsString::new(s));
+        let _ = self.map.insert(SdsString::new(s), Box::new(v.clone()));
+        v
+    }
+
+    /// Lookup without interning. Does not touch the statistics.
+    pub fn get(&self, s: &[u8]) -> Option<Rc<SdsString>> {
+        self.map.find_bytes(s).cloned()
+    }
+
+    pub fn contains(&self, s: &[u8]) -> bool {
+        self.map.find_bytes(s).is_some()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.map.size() as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> SdsPoolStats {
+        SdsPoolStats {
+            entries: self.map.size(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// Drop every entry only the pool still references. Returns the number
+    /// of entries reclaimed.
+    pub fn purge(&mut self) -> usize {
+        let dead = self.map
+            .iter_result(|_, it| {
+                let mut dead = Vec::new();
+                it.begin();
+                for (key, value) in it {
+                    if let Some(v) = value {
+                        if Rc::strong_count(v) == 1 {
+                            dead.push(key);
+                        }
+                    }
+                }
+                Ok(dead)
+            })
+            .unwrap_or_default();
+
+        let reclaimed = dead.len();
+        for key in dead {
+            self.map.remove(key);
+        }
+        reclaimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sds::SdsString;
+    use sds_pool::SdsPool;
+    use std::rc::Rc;
+
+    #[test]
+    fn intern_shares_allocation() {
+        let mut pool = SdsPool::new();
+        let a = pool.intern(b"auth-key");
+        let b = pool.intern(b"auth-key");
+        let c = pool.intern(b"data");
+
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(a.as_raw(), b.as_raw());
+        assert_eq!(*c, SdsString::new("data"));
+        assert_eq!(pool.len(), 2);
+
+        let stats = pool.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (2, 1, 2));
+        assert!(pool.get(b"id").is_none());
+        assert!(pool.contains(b"data"));
+    }
+
+    #[test]
+    fn purge_unreferenced() {
+        let mut pool = SdsPool::new();
+        let keep = pool.intern(b"id");
+        {
+            let _gone = pool.intern(b"tmp");
+        }
+        pool.intern(b"\x00binary\xff");
+
+        assert_eq!(pool.purge(), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(Rc::ptr_eq(&keep, &pool.intern(b"id")));
+        assert!(!pool.contains(b"tmp"));
+    }
+}
@@ -0,0 +1,270 @@
+use rax::*;
+use std::mem::size_of;
+
+use stream::*;
+
+use super::*;
+
+/// Key of the delayed jobs index, ordered by ready time then job ID.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct DelayKey {
+    /// Unix time in milliseconds the job is ready at.
+    pub ready_at: u64,
+    ms: u64,
+    seq: u64,
+}
+
+impl DelayKey {
+    pub fn new(ready_at: u64, id: StreamID) -> DelayKey {
+        DelayKey {
+            ready_at,
+            ms: id.ms(),
+            seq: id.seq(),
+        }
+    }
+
+    pub fn id(&self) -> StreamID {
+        StreamID::new(self.ms, self.seq)
+    }
+}
+
+impl RaxKey for DelayKey {
+    type Output = DelayKey;
+
+    fn encode(self) -> Self::Output {
+        DelayKey {
+            ready_at: self.ready_at.to_be(),
+            ms: self.ms.to_be(),
+            seq: self.seq.to_be(),
+        }
+    }
+
+    fn to_buf(&self) -> (*const u8, usize) {
+        (self as *const _ as *const u8, size_of::<DelayKey>())
+    }
+
+    fn from_buf(ptr: *const u8, len: usize) -> DelayKey {
+        if len != size_of::<DelayKey>() {
+            return DelayKey::default();
+        }
+
+        unsafe {
+            DelayKey {
+                ready_at: u64::from_be(*(ptr as *mut [u8; 8] as *mut u64)),
+                ms: u64::from_be(*(ptr.offset(8) as *mut [u8; 8] as *mut u64)),
+                seq: u64::from_be(*(ptr.offset(16) as *mut [u8; 8] as *mut u64)),
+            }
+        }
+    }
+}
+
+/// The keys of `index` ready at `now`, oldest first. Only those are
+/// visited.
+pub(super) fn due(index: &mut RaxMap<DelayKey, ()>, now: u64) -> Vec<DelayKey> {
+    index
+        .iter_result(|_, it| {
+            let mut due = Vec::new();
+            if it.seek_min() {
+                loop {
+                    let key = it.key();
+                    if key.ready_at > now {
+                        break;
+                    }
+                    due.push(key);
+                    if !it.forward() {
+                        break;
+                    }
+                }
+            }
+            Ok(due)
+        })
+        .unwrap_or_default()
+}
+
+impl JobStream {
+    /// Append a job that is not ready before `ready_at`, a Unix time in
+    /// milliseconds. The job is stored in the tail chunk like any other, so
+    /// it counts toward the chunk sizes and is spilled with its chunk, but
+    /// it is skipped by `next_ready` until `tick` promotes it.
+    ///
+    /// A delayed job keeps its chunk, and the chunk file, until it is removed.
+    pub fn add_delayed(&mut self, payload: &[u8], ready_at: u64) -> Result<StreamID, JobError> {
+        self.add_delayed_with_priority(payload, ready_at, 0)
+    }
+
+    /// Append a delayed job with `priority`, see `add_delayed`.
+    pub fn add_delayed_with_priority(&mut self, payload: &[u8], ready_at: u64, priority: u8) -> Result<StreamID, JobError> {
+        let now = unsafe { mstime() } as u64;
+        if ready_at <= now {
+            return self.add_with_priority(payload, None, priority);
+        }
+        if priority as usize >= self.levels.len() {
+            return Err(JobError::NoPriority(priority));
+        }
+        let id = self.append(payload, None, ready_at, priority as usize)?;
+        let _ = self.delayed.insert(DelayKey::new(ready_at, id), Box::new(()));
+        Ok(id)
+    }
+
+    /// Promote the delayed jobs ready at `now`, in the order they got ready.
+    /// Only the ready jobs of the index are visited.
+    pub(super) fn promote(&mut self, now: u64) {
+        for key in due(&mut self.delayed, now) {
+            self.delayed.remove(key);
+            let id = key.id();
+            let (l, i) = match self.chunk_index(id) {
+                Some(index) => index,
+                None => continue,
+            };
+            let list = &mut self.levels[l][i].list;
+            if let Some(mut job) = list.remove(id).1 {
+                job.ready_at = 0;
+                let _ = list.insert(id, job);
+                self.promoted.push_back(id);
+                for group in self.groups.iter_mut() {
+                    group.promoted(l, id);
+                }
+                // The garbage collection skipped it, visit its chunk again.
+                if id <= self.gc_cursors[l] {
+                    self.gc_cursors[l] = if i > 0 { self.levels[l][i - 1].last } else { StreamID::min() };
+                }
+            }
+        }
+    }
+
+    /// Number of jobs not ready yet.
+    pub fn delayed(&self) -> u64 {
+        self.delayed.size()
+    }
+
+    /// True if the job `id` exists and is ready.
+    pub fn is_ready(&self, id: StreamID) -> bool {
+        self.chunk(id)
+            .and_then(|chunk| chunk.list.find(id))
+            .map_or(false, |job| job.ready_at == 0)
+    }
+
+    /// The first ready job after `after` in ID order, of any priority.
+    pub fn next_ready(&mut self, after: StreamID) -> Option<StreamID> {
+        (0..self.levels.len())
+            .filter_map(|l| self.next_ready_in(l, after))
+            .min()
+    }
+
+    /// The first ready job of the priority level `l` after `after` in ID
+    /// order.
+    pub fn next_ready_in(&mut self, l: usize, after: StreamID) -> Option<StreamID> {
+        for chunk in self.levels.get_mut(l)?.iter_mut() {
+            if chunk.added == 0 || chunk.last <= after {
+                continue;
+            }
+            let next = chunk.list
+                .iter_result(|_, it| {
+                    it.seek_raw(">\0", after);
+                    while it.forward() {
+                        if it.data().map_or(false, |job| job.ready_at == 0) {
+                            return Ok(Some(it.key()));
+                        }
+                    }
+                    Ok(None)
+                })
+                .unwrap_or(None);
+            if next.is_some() {
+                return next;
+            }
+        }
+        None
+    }
+
+    /// The oldest promoted job still there. Consumers reading in ID order
+    /// may be past a delayed job by the time it is ready, they take it from
+    /// here.
+    pub fn pop_promoted(&mut self) -> Option<StreamID> {
+        while let Some(id) = self.promoted.pop_front() {
+            if self.is_ready(id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sliced::*;
+    use std;
+    use stream::{mstime, StreamID};
+
+    fn now() -> u64 {
+        unsafe { mstime() as u64 }
+    }
+
+    #[test]
+    fn delayed_jobs() {
+        let mut jobs = JobStream::new(Config::default());
+        let a = jobs.add(b"a", None).unwrap();
+        let b = jobs.add_delayed(b"b", now() + 30).unwrap();
+        let c = jobs.add(b"c", None).unwrap();
+        let past = jobs.add_delayed(b"past", now() - 1).unwrap();
+        assert_eq!(jobs.delayed(), 1);
+        assert!(jobs.is_ready(past));
+
+        assert_eq!(jobs.next_ready(StreamID::min()), Some(a));
+        assert_eq!(jobs.next_ready(a), Some(c));
+        assert!(!jobs.is_ready(b));
+        assert_eq!(jobs.get(b), Some(b"b".to_vec()));
+
+        jobs.tick();
+        assert_eq!(jobs.pop_promoted(), None);
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        jobs.tick();
+        assert_eq!(jobs.delayed(), 0);
+        assert!(jobs.is_ready(b));
+        assert_eq!(jobs.next_ready(a), Some(b));
+        assert_eq!(jobs.pop_promoted(), Some(b));
+        assert_eq!(jobs.pop_promoted(), None);
+
+        // Removing a delayed job drops it from the index.
+        let d = jobs.add_delayed(b"d", now() + 60_000).unwrap();
+        assert_eq!(jobs.delayed(), 1);
+        assert!(jobs.remove_job(d));
+        assert_eq!(jobs.delayed(), 0);
+        assert_eq!(jobs.len(), 4);
+    }
+
+    #[test]
+    fn delayed_survive_spilling() {
+        let dir = std::env::temp_dir().join(format!("sliced-{}-delayed", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        let mut ids: Vec<_> = (0..3).map(|_| jobs.add(&[0; 30], None).unwrap()).collect();
+        let delayed = jobs.add_delayed(&[1; 30], now() + 30).unwrap();
+        ids.extend((0..8).map(|_| jobs.add(&[0; 30], None).unwrap()));
+        jobs.tick();
+        assert_eq!(jobs.resident_chunks(), 2);
+        assert!(!jobs.levels[0][1].is_resident());
+
+        // The delayed job keeps its chunk while the others are consumed.
+        for id in &ids[..5] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.levels[0][0].first, delayed);
+        assert_eq!(jobs.next_ready(StreamID::min()), Some(ids[5]));
+
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        jobs.tick();
+        assert_eq!(jobs.pop_promoted(), Some(delayed));
+        assert_eq!(jobs.get(delayed), Some(vec![1; 30]));
+
+        drop(jobs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,802 @@
+use rax::*;
+use sds::SdsString;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use stream::*;
+
+use super::delayed::due;
+use super::*;
+
+/// Stream fields of a dead letter entry.
+const DEAD_LETTER_JOB_FIELD: &[u8] = b"job";
+const DEAD_LETTER_GROUP_FIELD: &[u8] = b"group";
+const DEAD_LETTER_ATTEMPTS_FIELD: &[u8] = b"attempts";
+
+/// Retries and promoted jobs of a group whose chunks are loaded ahead of the
+/// reads.
+const READ_AHEAD: usize = 16;
+
+pub struct GroupConfig {
+    /// Milliseconds a delivered job is hidden from the group before it is
+    /// considered lost and retried
+    pub visibility_timeout_ms: u64,
+    /// Deliveries before a job moves to the dead letter stream, zero retries
+    /// forever
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every further one
+    pub backoff_ms: u64,
+    /// Max backoff before a retry
+    pub backoff_max_ms: u64,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            visibility_timeout_ms: 30 * 1000,
+            max_attempts: 16,
+            backoff_ms: 1000,
+            backoff_max_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+impl GroupConfig {
+    /// Backoff after the delivery number `attempts` timed out, between half
+    /// and all of the exponential backoff. `random` picks where.
+    pub fn backoff(&self, attempts: u32, random: u64) -> u64 {
+        let shift = attempts.saturating_sub(1).min(63);
+        let full = self.backoff_ms
+            .checked_mul(1 << shift)
+            .map_or(self.backoff_max_ms, |b| b.min(self.backoff_max_ms));
+        let half = full / 2;
+        full - half + random % (half + 1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeliveryState {
+    /// Delivered, hidden until the visibility timeout.
+    Invisible,
+    /// Timed out, waiting for the backoff.
+    Backoff,
+    /// Waiting in the retry queue.
+    Retry,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Delivery {
+    /// Priority level of the job.
+    level: usize,
+    attempts: u32,
+    state: DeliveryState,
+    /// Unix time in milliseconds of the visibility timeout or the end of the
+    /// backoff, the key in `timeouts`.
+    deadline: u64,
+}
+
+/// Counters of a priority level of a consumer group, to tell whether the
+/// priority policy starves it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// Jobs of the level delivered for the first time.
+    pub delivered: u64,
+    /// Jobs delivered from another level while this one had a ready job.
+    pub passed_over: u64,
+    /// Age by its ID of the oldest ready job of the level the group was not
+    /// delivered yet, zero if there is none.
+    pub oldest_wait_ms: u64,
+}
+
+/// A consumer group of a `JobStream`. Every ready job is delivered to each
+/// group, in ID order within its priority level, the level being picked by
+/// `Config::priority_policy`, and is hidden from the group until acked or until the
+/// visibility timeout. A job not acked in time is retried after a backoff,
+/// ahead of the jobs never delivered, and moves to the dead letter stream
+/// after `max_attempts` deliveries.
+pub struct ConsumerGroup {
+    name: SdsString,
+    config: GroupConfig,
+    /// Last job delivered in ID order, by priority level.
+    last_ids: Vec<StreamID>,
+    /// Credit of each level for the weighted policy.
+    credits: Vec<i64>,
+    stats: Vec<LevelStats>,
+    /// Jobs delivered and not acked yet.
+    pending: HashMap<StreamID, Delivery>,
+    /// Pending jobs that are invisible or backing off by deadline.
+    timeouts: Box<RaxMap<DelayKey, ()>>,
+    /// Jobs to deliver again, in the order their backoff ended.
+    retry: VecDeque<StreamID>,
+    /// Delayed jobs that got ready after the group read past them.
+    promoted: VecDeque<StreamID>,
+    /// The pending and promoted jobs of each level, they hold the garbage
+    /// collection back.
+    held: Vec<BTreeSet<StreamID>>,
+    /// State of the jitter generator.
+    rng: u64,
+}
+
+impl ConsumerGroup {
+    fn new(name: &[u8], config: GroupConfig, seed: u64, priorities: usize) -> ConsumerGroup {
+        ConsumerGroup {
+            name: SdsString::new(name),
+            config,
+            last_ids: vec![StreamID::default(); priorities],
+            credits: vec![0; priorities],
+            stats: vec![LevelStats::default(); priorities],
+            pending: HashMap::new(),
+            timeouts: Box::new(RaxMap::new()),
+            retry: VecDeque::new(),
+            promoted: VecDeque::new(),
+            held: vec![BTreeSet::new(); priorities],
+            rng: seed | 1,
+        }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Last job of the level `priority` delivered in ID order.
+    pub fn last_id(&self, priority: u8) -> Option<StreamID> {
+        self.last_ids.get(priority as usize).cloned()
+    }
+
+    /// Number of jobs delivered and not acked yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of deliveries of the pending job `id`.
+    pub fn attempts(&self, id: StreamID) -> Option<u32> {
+        self.pending.get(&id).map(|d| d.attempts)
+    }
+
+    pub(super) fn promoted(&mut self, l: usize, id: StreamID) {
+        if id < self.last_ids[l] {
+            self.promoted.push_back(id);
+            self.held[l].insert(id);
+        }
+    }
+
+    fn set_deadline(&mut self, id: StreamID, state: DeliveryState, deadline: u64) {
+        let delivery = self.pending.get_mut(&id).expect("pending job");
+        if delivery.state != DeliveryState::Retry {
+            self.timeouts.remove(DelayKey::new(delivery.deadline, id));
+        }
+        delivery.state = state;
+        delivery.deadline = deadline;
+        if state != DeliveryState::Retry {
+            let _ = self.timeouts.insert(DelayKey::new(deadline, id), Box::new(()));
+        }
+    }
+
+    fn forget(&mut self, id: StreamID) -> bool {
+        match self.pending.remove(&id) {
+            Some(delivery) => {
+                if delivery.state != DeliveryState::Retry {
+                    self.timeouts.remove(DelayKey::new(delivery.deadline, id));
+                }
+                self.held[delivery.level].remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The level to deliver from next among `ready`, the levels with a ready
+    /// job. `Strict` takes the highest. `Weighted` is a smooth weighted round
+    /// robin: every ready level earns its weight and the richest one, the
+    /// highest on a tie, pays the sum of the weights earned.
+    fn pick(&mut self, policy: &PriorityPolicy, ready: &[usize]) -> Option<usize> {
+        let picked = match *policy {
+            PriorityPolicy::Strict => *ready.iter().max()?,
+            PriorityPolicy::Weighted(_) => {
+                let mut picked: Option<usize> = None;
+                let mut total = 0;
+                for &l in ready.iter().rev() {
+                    let weight = policy.weight(l);
+                    self.credits[l] += weight;
+                    total += weight;
+                    if picked.map_or(true, |p| self.credits[l] > self.credits[p]) {
+                        picked = Some(l);
+                    }
+                }
+                let picked = picked?;
+                self.credits[picked] -= total;
+                picked
+            }
+        };
+        for &l in ready {
+            if l != picked {
+                self.stats[l].passed_over += 1;
+            }
+        }
+        Some(picked)
+    }
+
+    /// The first job of the level `l` the group may still need.
+    fn low_watermark(&self, l: usize) -> StreamID {
+        let undelivered = self.last_ids[l].next().unwrap_or(StreamID::max());
+        self.held[l].iter().next().map_or(undelivered, |held| undelivered.min(*held))
+    }
+
+    /// xorshift64*, jitter needs no better.
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl JobStream {
+    pub fn create_group(&mut self, name: &[u8], config: GroupConfig) -> Result<(), JobError> {
+        if self.group(name).is_some() {
+            return Err(JobError::GroupExists);
+        }
+        let seed = unsafe { mstime() } as u64 ^ (self.groups.len() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let priorities = self.levels.len();
+        self.groups.push(ConsumerGroup::new(name, config, seed, priorities));
+        Ok(())
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.iter().find(|g| &g.name[..] == name)
+    }
+
+    fn group_index(&self, name: &[u8]) -> Result<usize, JobError> {
+        self.groups.iter().position(|g| &g.name[..] == name).ok_or(JobError::NoGroup)
+    }
+
+    /// The chunks of the level `l` the groups read next: the chunk at the
+    /// read position of each group and the one after it, and the chunks of
+    /// the first retries and promoted jobs.
+    pub(super) fn read_chunks(&self, l: usize) -> Vec<usize> {
+        let chunks = &self.levels[l];
+        let mut wanted = Vec::new();
+        for group in &self.groups {
+            let after = group.last_ids[l];
+            if let Some(i) = chunks.iter().position(|c| c.added > 0 && c.last > after) {
+                wanted.push(i);
+                if i + 1 < chunks.len() {
+                    wanted.push(i + 1);
+                }
+            }
+            for id in group.retry.iter().chain(group.promoted.iter()).take(READ_AHEAD) {
+                match self.chunk_index(*id) {
+                    Some((level, i)) if level == l => wanted.push(i),
+                    _ => {}
+                }
+            }
+        }
+        wanted
+    }
+
+    /// Deliver up to `count` jobs to the group `name`: the retries first, then
+    /// the delayed jobs that got ready behind the group and then the next
+    /// jobs in ID order of the level picked by the priority policy, for each
+    /// job. Each stays hidden from the group for the visibility
+    /// timeout.
+    pub fn read(&mut self, name: &[u8], count: usize) -> Result<Vec<(StreamID, Vec<u8>)>, JobError> {
+        let g = self.group_index(name)?;
+        let now = unsafe { mstime() } as u64;
+        let mut jobs = Vec::new();
+
+        while jobs.len() < count {
+            let id = match self.groups[g].retry.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let attempts = match self.groups[g].pending.get(&id) {
+                Some(d) if d.state == DeliveryState::Retry => d.attempts,
+                _ => continue,
+            };
+            match self.payload(id) {
+                Payload::Ready(payload) => {
+                    let group = &mut self.groups[g];
+                    let deadline = now + group.config.visibility_timeout_ms;
+                    group.pending.get_mut(&id).unwrap().attempts = attempts + 1;
+                    group.set_deadline(id, DeliveryState::Invisible, deadline);
+                    jobs.push((id, payload));
+                }
+                Payload::Spilled => {
+                    self.groups[g].retry.push_front(id);
+                    return Ok(self.loading(jobs));
+                }
+                // Removed meanwhile.
+                Payload::Removed => {
+                    self.groups[g].forget(id);
+                }
+            }
+        }
+
+        while jobs.len() < count {
+            let id = match self.groups[g].promoted.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            if self.groups[g].pending.contains_key(&id) {
+                continue;
+            }
+            let payload = if self.is_ready(id) { self.payload(id) } else { Payload::Removed };
+            match payload {
+                Payload::Ready(payload) => {
+                    self.deliver(g, id, now);
+                    jobs.push((id, payload));
+                }
+                Payload::Spilled => {
+                    self.groups[g].promoted.push_front(id);
+                    return Ok(self.loading(jobs));
+                }
+                Payload::Removed => {
+                    for held in self.groups[g].held.iter_mut() {
+                        held.remove(&id);
+                    }
+                }
+            }
+        }
+
+        while jobs.len() < count {
+            let mut ready = Vec::new();
+            for l in 0..self.levels.len() {
+                if let Some(id) = self.next_ready_in(l, self.groups[g].last_ids[l]) {
+                    ready.push((l, id));
+                }
+            }
+            let levels: Vec<_> = ready.iter().map(|r| r.0).collect();
+            let l = match self.groups[g].pick(&self.config.priority_policy, &levels) {
+                Some(l) => l,
+                None => break,
+            };
+            let id = ready.iter().find(|r| r.0 == l).unwrap().1;
+            match self.payload(id) {
+                Payload::Ready(payload) => {
+                    self.groups[g].last_ids[l] = id;
+                    self.deliver(g, id, now);
+                    jobs.push((id, payload));
+                }
+                Payload::Spilled => return Ok(self.loading(jobs)),
+                Payload::Removed => self.groups[g].last_ids[l] = id,
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// End a read at a job whose chunk is spilled: start loading it, the
+    /// rest is delivered by a later read.
+    fn loading(&mut self, jobs: Vec<(StreamID, Vec<u8>)>) -> Vec<(StreamID, Vec<u8>)> {
+        self.run_tasks();
+        jobs
+    }
+
+    fn deliver(&mut self, g: usize, id: StreamID, now: u64) {
+        let level = self.chunk_index(id).map_or(0, |(l, _)| l);
+        let group = &mut self.groups[g];
+        group.stats[level].delivered += 1;
+        group.held[level].insert(id);
+        let deadline = now + group.config.visibility_timeout_ms;
+        group.pending.insert(id, Delivery {
+            level,
+            attempts: 1,
+            state: DeliveryState::Invisible,
+            deadline,
+        });
+        let _ = group.timeouts.insert(DelayKey::new(deadline, id), Box::new(()));
+    }
+
+    /// Acknowledge the jobs `ids` for the group `name`. Returns the number of
+    /// jobs that were pending.
+    pub fn ack(&mut self, name: &[u8], ids: &[StreamID]) -> Result<usize, JobError> {
+        let g = self.group_index(name)?;
+        Ok(ids.iter().filter(|id| self.groups[g].forget(**id)).count())
+    }
+
+    /// The counters of each priority level of the group `name`, lowest
+    /// first.
+    pub fn starvation(&mut self, name: &[u8]) -> Result<Vec<LevelStats>, JobError> {
+        let g = self.group_index(name)?;
+        let now = unsafe { mstime() } as u64;
+        let mut stats = self.groups[g].stats.clone();
+        for (l, level) in stats.iter_mut().enumerate() {
+            level.oldest_wait_ms = self.next_ready_in(l, self.groups[g].last_ids[l])
+                .map_or(0, |id| now.saturating_sub(id.ms()));
+        }
+        Ok(stats)
+    }
+
+    /// The first job of the level `priority` some group has not delivered or
+    /// not acked yet, every job before it was consumed by all the groups.
+    /// None without groups, the jobs are then only removed with `remove_job`.
+    pub fn low_watermark(&self, priority: u8) -> Option<StreamID> {
+        let l = priority as usize;
+        if l >= self.levels.len() {
+            return None;
+        }
+        self.groups.iter().map(|g| g.low_watermark(l)).min()
+    }
+
+    /// Remove the jobs of the chunks below the low watermark of their level
+    /// but the tails, leaving the chunks empty to be dropped along with their
+    /// chunk files. A delayed job is not consumed until promoted. At most
+    /// `gc_jobs_per_tick` jobs are visited, the next call resumes where this
+    /// one stopped.
+    pub(super) fn collect(&mut self) {
+        let mut budget = self.config.gc_jobs_per_tick.max(1);
+        for l in 0..self.levels.len() {
+            let watermark = match self.low_watermark(l as u8) {
+                Some(watermark) => watermark,
+                None => return,
+            };
+            let mut i = 0;
+            while budget > 0 && i + 1 < self.levels[l].len() && self.levels[l][i].last < watermark {
+                let after = self.gc_cursors[l];
+                if self.levels[l][i].is_empty() || self.levels[l][i].last <= after {
+                    i += 1;
+                    continue;
+                }
+                let (consumed, last, visited, end) = self.levels[l][i].list
+                    .iter_result(|_, it| {
+                        let mut consumed = Vec::new();
+                        let mut last = after;
+                        let mut visited = 0;
+                        it.seek_raw(">\0", after);
+                        while visited < budget {
+                            if !it.forward() {
+                                return Ok((consumed, last, visited, true));
+                            }
+                            visited += 1;
+                            last = it.key();
+                            if it.data().map_or(false, |job| job.ready_at == 0) {
+                                consumed.push(last);
+                            }
+                        }
+                        Ok((consumed, last, visited, false))
+                    })
+                    .unwrap_or((Vec::new(), after, budget, false));
+                budget -= visited.min(budget);
+                // Past the last job left, the ones after it may have been
+                // removed since, so the chunk is done up to its last ID.
+                self.gc_cursors[l] = if end { self.levels[l][i].last } else { last };
+                for id in consumed {
+                    if let Some(job) = self.levels[l][i].list.remove(id).1 {
+                        self.len -= 1;
+                        self.release_dedupe(id, &job);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The jobs given up on after `max_attempts` deliveries. Entries have the
+    /// job ID, the group, the number of deliveries and the payload.
+    pub fn dead_letters(&self) -> &Stream {
+        &self.dead_letters
+    }
+
+    /// Handle the visibility timeouts and backoffs ending at `now`. Only the
+    /// expired entries of each group are visited.
+    pub(super) fn expire_groups(&mut self, now: u64) {
+        for g in 0..self.groups.len() {
+            for key in due(&mut self.groups[g].timeouts, now) {
+                let id = key.id();
+                let delivery = match self.groups[g].pending.get(&id) {
+                    Some(delivery) if delivery.state != DeliveryState::Retry && delivery.deadline == key.ready_at => *delivery,
+                    _ => {
+                        self.groups[g].timeouts.remove(key);
+                        continue;
+                    }
+                };
+                if delivery.state == DeliveryState::Backoff {
+                    let group = &mut self.groups[g];
+                    group.set_deadline(id, DeliveryState::Retry, now);
+                    group.retry.push_back(id);
+                    continue;
+                }
+
+                let max_attempts = self.groups[g].config.max_attempts;
+                if max_attempts > 0 && delivery.attempts >= max_attempts {
+                    match self.payload(id) {
+                        Payload::Ready(payload) => {
+                            self.groups[g].forget(id);
+                            self.dead_letter(g, id, delivery.attempts, &payload);
+                        }
+                        // Still due on the next tick.
+                        Payload::Spilled => {}
+                        Payload::Removed => {
+                            self.groups[g].forget(id);
+                        }
+                    }
+                } else if !self.chunk(id).map_or(false, |c| c.list.exists(id)) {
+                    self.groups[g].forget(id);
+                } else {
+                    let group = &mut self.groups[g];
+                    let random = group.random();
+                    let deadline = now + group.config.backoff(delivery.attempts, random);
+                    group.set_deadline(id, DeliveryState::Backoff, deadline);
+                }
+            }
+        }
+    }
+
+    fn dead_letter(&mut self, g: usize, id: StreamID, attempts: u32, payload: &[u8]) {
+        let job = id.to_string();
+        let attempts = attempts.to_string();
+        let fields: [(&[u8], &[u8]); 4] = [
+            (DEAD_LETTER_JOB_FIELD, job.as_bytes()),
+            (DEAD_LETTER_GROUP_FIELD, &self.groups[g].name),
+            (DEAD_LETTER_ATTEMPTS_FIELD, attempts.as_bytes()),
+            (PAYLOAD_FIELD, payload),
+        ];
+        let _ = self.dead_letters.add(&fields, IdSpec::Auto);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sliced::*;
+    use std;
+    use stream::{mstime, StreamID};
+
+    fn now() -> u64 {
+        unsafe { mstime() as u64 }
+    }
+
+    #[test]
+    fn visibility_timeout() {
+        let mut jobs = JobStream::new(Config::default());
+        let ids: Vec<_> = (0..3u8).map(|i| jobs.add(&[i], None).unwrap()).collect();
+        let config = || GroupConfig {
+            visibility_timeout_ms: 1000,
+            max_attempts: 0,
+            backoff_ms: 100,
+            backoff_max_ms: 100,
+        };
+        let now = now();
+        jobs.create_group(b"g", config()).unwrap();
+        jobs.create_group(b"other", config()).unwrap();
+        assert_eq!(jobs.create_group(b"g", config()), Err(JobError::GroupExists));
+        assert_eq!(jobs.read(b"nope", 1), Err(JobError::NoGroup));
+
+        assert_eq!(jobs.read(b"g", 2).unwrap(), vec![(ids[0], vec![0]), (ids[1], vec![1])]);
+        assert_eq!(jobs.read(b"g", 5).unwrap(), vec![(ids[2], vec![2])]);
+        assert_eq!(jobs.read(b"other", 5).unwrap().len(), 3);
+        assert_eq!(jobs.ack(b"g", &[ids[0], ids[0]]), Ok(1));
+        assert_eq!(jobs.group(b"g").unwrap().pending(), 2);
+
+        // Still hidden just before the timeout.
+        jobs.expire_groups(now + 990);
+        assert!(jobs.read(b"g", 5).unwrap().is_empty());
+
+        // Timed out, retried once the backoff of 50 to 100ms is over.
+        jobs.ack(b"g", &[ids[2]]).unwrap();
+        let timeout = now + 1500;
+        jobs.expire_groups(timeout);
+        jobs.expire_groups(timeout + 49);
+        assert!(jobs.read(b"g", 5).unwrap().is_empty());
+        jobs.expire_groups(timeout + 100);
+        assert_eq!(jobs.read(b"g", 5).unwrap(), vec![(ids[1], vec![1])]);
+        assert_eq!(jobs.group(b"g").unwrap().attempts(ids[1]), Some(2));
+
+        // The other group is untouched.
+        assert_eq!(jobs.group(b"other").unwrap().attempts(ids[1]), Some(1));
+        assert_eq!(jobs.group(b"other").unwrap().pending(), 3);
+    }
+
+    #[test]
+    fn dead_letter() {
+        let mut jobs = JobStream::new(Config::default());
+        let id = jobs.add(b"poison", None).unwrap();
+        let other = jobs.add(b"fine", None).unwrap();
+        jobs.create_group(b"g", GroupConfig {
+            visibility_timeout_ms: 10,
+            max_attempts: 2,
+            backoff_ms: 10,
+            backoff_max_ms: 10,
+        }).unwrap();
+
+        let mut t = now();
+        assert_eq!(jobs.read(b"g", 1).unwrap()[0].0, id);
+        for _ in 0..2 {
+            t += 20;
+            jobs.expire_groups(t);
+            t += 20;
+            jobs.expire_groups(t);
+        }
+        // Retried first, the next job after.
+        let read: Vec<_> = jobs.read(b"g", 2).unwrap().into_iter().map(|j| j.0).collect();
+        assert_eq!(read, vec![id, other]);
+        jobs.ack(b"g", &[other]).unwrap();
+        assert_eq!(jobs.dead_letters().len(), 0);
+
+        t += 2000;
+        jobs.expire_groups(t);
+        assert_eq!(jobs.group(b"g").unwrap().pending(), 0);
+        assert_eq!(jobs.dead_letters().len(), 1);
+        let entry = jobs.dead_letters().range(StreamID::min(), StreamID::max(), None).next().unwrap();
+        assert_eq!(entry.get(b"job"), Some(&id.to_string().as_bytes()[..]));
+        assert_eq!(entry.get(b"group"), Some(&b"g"[..]));
+        assert_eq!(entry.get(b"attempts"), Some(&b"2"[..]));
+        assert_eq!(entry.get(b"payload"), Some(&b"poison"[..]));
+
+        // Never delivered to the group again.
+        t += 2000;
+        jobs.expire_groups(t);
+        assert!(jobs.read(b"g", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn promoted_behind_group() {
+        let mut jobs = JobStream::new(Config::default());
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+        let delayed = jobs.add_delayed(b"later", now() + 20).unwrap();
+        let a = jobs.add(b"now", None).unwrap();
+        assert_eq!(jobs.read(b"g", 5).unwrap(), vec![(a, b"now".to_vec())]);
+
+        jobs.promote(now() + 30);
+        assert_eq!(jobs.read(b"g", 5).unwrap(), vec![(delayed, b"later".to_vec())]);
+        assert!(jobs.read(b"g", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn backoff() {
+        let config = GroupConfig {
+            backoff_ms: 100,
+            backoff_max_ms: 1000,
+            ..GroupConfig::default()
+        };
+        assert_eq!(config.backoff(1, 0), 50);
+        assert_eq!(config.backoff(1, 50), 100);
+        assert_eq!(config.backoff(3, 0), 200);
+        assert_eq!(config.backoff(3, 200), 400);
+        assert_eq!(config.backoff(10, 0), 500);
+        assert!(config.backoff(200, 7) <= 1000);
+    }
+
+    fn priority_config(policy: PriorityPolicy) -> Config {
+        Config {
+            priorities: 2,
+            priority_policy: policy,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn strict_priority() {
+        let mut jobs = JobStream::new(priority_config(PriorityPolicy::Strict));
+        let low = jobs.add(b"low", None).unwrap();
+        let high: Vec<_> = (0..2).map(|_| jobs.add_with_priority(b"high", None, 1).unwrap()).collect();
+        assert_eq!(jobs.add_with_priority(b"none", None, 2), Err(JobError::NoPriority(2)));
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+
+        let read: Vec<_> = jobs.read(b"g", 5).unwrap().into_iter().map(|j| j.0).collect();
+        assert_eq!(read, vec![high[0], high[1], low]);
+        assert_eq!(jobs.group(b"g").unwrap().last_id(0), Some(low));
+        assert_eq!(jobs.group(b"g").unwrap().last_id(1), Some(high[1]));
+
+        let stats = jobs.starvation(b"g").unwrap();
+        assert_eq!(stats[0], LevelStats {
+            delivered: 1,
+            passed_over: 2,
+            oldest_wait_ms: 0,
+        });
+        assert_eq!(stats[1].delivered, 2);
+        assert_eq!(stats[1].passed_over, 0);
+    }
+
+    #[test]
+    fn weighted_priority() {
+        let mut jobs = JobStream::new(priority_config(PriorityPolicy::Weighted(vec![1, 3])));
+        for _ in 0..10 {
+            jobs.add(b"l", None).unwrap();
+            jobs.add_with_priority(b"h", None, 1).unwrap();
+        }
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+
+        let read: Vec<_> = jobs.read(b"g", 8).unwrap().into_iter().map(|j| j.1).collect();
+        assert_eq!(read.concat(), b"hhlhhhlh".to_vec());
+
+        // The low level waits meanwhile, and takes over once the high one is
+        // drained.
+        let start = now();
+        let stats = jobs.starvation(b"g").unwrap();
+        assert_eq!((stats[0].delivered, stats[0].passed_over), (2, 6));
+        assert_eq!((stats[1].delivered, stats[1].passed_over), (6, 2));
+        assert!(stats[0].oldest_wait_ms <= now() - start + 1000);
+        let read: Vec<_> = jobs.read(b"g", 20).unwrap().into_iter().map(|j| j.1).collect();
+        assert_eq!(read.concat(), b"hhlhhlllllll".to_vec());
+        assert_eq!(jobs.starvation(b"g").unwrap()[0].oldest_wait_ms, 0);
+    }
+
+    #[test]
+    fn low_watermark_gc() {
+        let dir = std::env::temp_dir().join(format!("sliced-{}-gc", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            chunks_per_file: 1,
+            head_memory_limit: 100,
+            dir: Some(dir.clone()),
+            gc_jobs_per_tick: 2,
+            ..Config::default()
+        });
+        jobs.create_group(b"a", GroupConfig::default()).unwrap();
+        jobs.create_group(b"b", GroupConfig::default()).unwrap();
+        let ids: Vec<_> = (0..12u8).map(|i| jobs.add(&[i; 30], Some(&[i])).unwrap()).collect();
+        jobs.tick();
+        // The groups read the first two chunks next, only the third spills.
+        assert_eq!(jobs.chunks(), 4);
+        assert_eq!(jobs.files(), 1);
+        assert_eq!(jobs.low_watermark(0), Some(StreamID::min().next().unwrap()));
+
+        // Consumed by a only.
+        let read: Vec<_> = jobs.read(b"a", 6).unwrap().into_iter().map(|j| j.0).collect();
+        jobs.ack(b"a", &read).unwrap();
+        jobs.tick();
+        assert_eq!(jobs.len(), 12);
+
+        // b holds the second chunk back with an unacked job.
+        let read: Vec<_> = jobs.read(b"b", 6).unwrap().into_iter().map(|j| j.0).collect();
+        jobs.ack(b"b", &read[..4]).unwrap();
+        assert_eq!(jobs.low_watermark(0), Some(ids[4]));
+        assert_eq!(jobs.low_watermark(1), None);
+        jobs.tick();
+        assert_eq!(jobs.len(), 10);
+        jobs.tick();
+        assert_eq!(jobs.len(), 9);
+        assert_eq!(jobs.chunks(), 3);
+        assert_eq!(jobs.get(ids[0]), None);
+        assert_eq!(jobs.add(b"again", Some(&[0])).map(|_| ()), Ok(()));
+        assert_eq!(jobs.add(b"again", Some(&[4])), Err(JobError::Duplicate(ids[4])));
+
+        jobs.ack(b"b", &read[4..]).unwrap();
+        for _ in 0..3 {
+            jobs.tick();
+        }
+        assert_eq!(jobs.len(), 7);
+        assert_eq!(jobs.chunks(), 2);
+        assert_eq!(jobs.get(ids[6]), Some(vec![6; 30]));
+        // The file of the dropped chunk stays as the one to append to.
+        assert_eq!(jobs.files(), 2);
+        assert_eq!(jobs.files.values().map(|f| f.live).sum::<u64>(), 1);
+
+        drop(jobs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gc_past_removed_jobs() {
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            gc_jobs_per_tick: 1,
+            ..Config::default()
+        });
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+        let delayed = jobs.add_delayed(&[0; 30], now() + 60_000).unwrap();
+        let a = jobs.add(&[1; 30], None).unwrap();
+        let b = jobs.add(&[2; 30], None).unwrap();
+        jobs.add(&[3; 30], None).unwrap();
+        assert_eq!(jobs.chunks(), 2);
+
+        let read: Vec<_> = jobs.read(b"g", 2).unwrap().into_iter().map(|j| j.0).collect();
+        assert_eq!(read, vec![a, b]);
+        jobs.ack(b"g", &read).unwrap();
+        jobs.tick();
+
+        // The cursor stopped on the delayed job, the jobs after it are gone.
+        assert!(jobs.remove_job(a));
+        assert!(jobs.remove_job(b));
+        jobs.tick();
+        jobs.tick();
+        assert_eq!(jobs.len(), 2);
+
+        // Consumed once promoted.
+        jobs.promote(now() + 120_000);
+        assert_eq!(jobs.read(b"g", 1).unwrap()[0].0, delayed);
+        jobs.ack(b"g", &[delayed]).unwrap();
+        jobs.tick();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs.chunks(), 1);
+    }
+}
@@ -1,12 +1,34 @@
 use rax::*;
 use std;
-use sds::SDS;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use sds::SdsString;
 //use std::mem::size_of;
 
 use stream::*;
 
+mod delayed;
+mod group;
+mod worker;
+
+pub use self::delayed::DelayKey;
+pub use self::group::{ConsumerGroup, GroupConfig, LevelStats};
+pub use self::worker::{Done, FileOp, IoPool};
+
 const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024 * 8;
 const DEFAULT_CHUNKS_PER_FILE: u64 = 8;
+const DEFAULT_DEDUPE_WINDOW_MS: u64 = 1000 * 60 * 60;
+const DEFAULT_GC_JOBS_PER_TICK: u64 = 4096;
+
+/// Longest dedupe key, stored inline in `Job`.
+pub const MAX_DEDUPE_KEY_LEN: usize = 48;
+
+/// Stream fields of a job entry.
+const PAYLOAD_FIELD: &[u8] = b"payload";
+const DEDUPE_FIELD: &[u8] = b"dedupe";
 
 pub struct Config {
     /// Size of chunk
@@ -17,95 +39,981 @@ pub struct Config {
     pub page_size: u32,
     /// Max amount of memory to keep in the head list
     pub head_memory_limit: u64,
+    /// Directory chunk files are written to, none keeps every chunk in
+    /// memory. Every JobStream needs a directory of its own.
+    pub dir: Option<PathBuf>,
+    /// Max size in bytes of a single stream listpack node
     pub node_max_bytes: u32,
+    /// Max number of entries in a single stream listpack node
     pub node_max_entries: u16,
+    /// Milliseconds a dedupe key rejects duplicates for, zero disables
+    /// de-duplication
+    pub dedupe_window_ms: u64,
+    /// Number of priority levels, each with its own chain of chunks
+    pub priorities: u8,
+    /// How consumer groups pick the priority level to read next
+    pub priority_policy: PriorityPolicy,
+    /// Jobs the garbage collection of consumed chunks visits per tick at
+    /// most
+    pub gc_jobs_per_tick: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunks_per_file: DEFAULT_CHUNKS_PER_FILE,
+            page_size: 4096,
+            head_memory_limit: DEFAULT_CHUNK_SIZE,
+            dir: None,
+            node_max_bytes: STREAM_NODE_MAX_BYTES as u32,
+            node_max_entries: STREAM_NODE_MAX_ENTRIES as u16,
+            dedupe_window_ms: DEFAULT_DEDUPE_WINDOW_MS,
+            priorities: 1,
+            priority_policy: PriorityPolicy::Strict,
+            gc_jobs_per_tick: DEFAULT_GC_JOBS_PER_TICK,
+        }
+    }
+}
+
+/// How a consumer group picks the priority level it reads next, among the
+/// levels with a ready job it was not delivered yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PriorityPolicy {
+    /// Always the highest level, lower levels wait until it is drained.
+    Strict,
+    /// Each level gets a share of the reads proportional to its weight,
+    /// interleaved evenly. Weights are by level, a missing one is 1.
+    Weighted(Vec<u32>),
+}
+
+impl PriorityPolicy {
+    fn weight(&self, level: usize) -> i64 {
+        match *self {
+            PriorityPolicy::Strict => 1,
+            PriorityPolicy::Weighted(ref weights) => weights.get(level).map_or(1, |w| *w as i64),
+        }
+    }
+}
+
+impl Config {
+    /// A stream using the node limits of this configuration.
+    pub fn new_stream(&self) -> Stream {
+        Stream::with_node_limits(self.node_max_bytes as usize, self.node_max_entries as u64)
+    }
 }
 
 /// Active job structure
 #[repr(packed)]
 pub struct Job {
-    nack: usize,
-
     // Support upto 48 byte dedupe keys
     dup_len: u8,
+    dup: [u8; MAX_DEDUPE_KEY_LEN],
+
+    /// Unix time in milliseconds a delayed job is ready at, zero once ready.
+    ready_at: u64,
 }
 
-impl Drop for Job {
-    fn drop(&mut self) {
-        if self.dup_len > 0 {}
+impl Job {
+    fn new(dedupe_key: Option<&[u8]>, ready_at: u64) -> Job {
+        let mut job = Job {
+            dup_len: 0,
+            dup: [0; MAX_DEDUPE_KEY_LEN],
+            ready_at,
+        };
+        if let Some(key) = dedupe_key {
+            job.dup[..key.len()].copy_from_slice(key);
+            job.dup_len = key.len() as u8;
+        }
+        job
+    }
+
+    pub fn dedupe_key(&self) -> Option<&[u8]> {
+        if self.dup_len == 0 {
+            None
+        } else {
+            Some(&self.dup[..self.dup_len as usize])
+        }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ChunkState {
     Idle,
+    /// Being read back from its chunk file.
     Loading,
+    /// Being written to a chunk file.
+    Flushing,
+    /// The chunk file could not be read back.
     Error,
 }
 
+/// A chunk file holding up to `chunks_per_file` chunks, each a stream dump
+/// starting at a page boundary. Files are only appended to, a chunk written
+/// once is never rewritten. Once at most half of its chunks are left the
+/// file is compacted into a new one, and it is deleted when none are left.
 pub struct JobsFile {
     pub first: StreamID,
     pub last: StreamID,
-    //
-    pub list: Vec<JobsChunk>,
+    pub path: PathBuf,
+    /// Number of chunks written.
+    pub chunks: u64,
+    /// Chunks written and not dropped yet.
+    pub live: u64,
+    /// Bytes written, including the padding.
+    pub len: u64,
+    /// The chunks being written to the file. It is only deleted once they
+    /// are written, a late write would create it again.
+    writes: Vec<StreamID>,
+    /// The number of the file the live chunks are being copied to, the file
+    /// and their new location.
+    compacting: Option<(u64, Box<JobsFile>, Vec<(StreamID, ChunkLocation)>)>,
 }
 
+impl JobsFile {
+    fn new(path: PathBuf) -> JobsFile {
+        JobsFile {
+            first: StreamID::default(),
+            last: StreamID::default(),
+            path,
+            chunks: 0,
+            live: 0,
+            len: 0,
+            writes: Vec::new(),
+            compacting: None,
+        }
+    }
+
+    /// Make room for the chunk from `first` to `last` of `len` bytes at the
+    /// next page boundary and return its offset.
+    fn reserve(&mut self, first: StreamID, last: StreamID, len: u64, page_size: u32) -> u64 {
+        let page_size = page_size.max(1) as u64;
+        let offset = (self.len + page_size - 1) / page_size * page_size;
+        if self.chunks == 0 {
+            self.first = first;
+        }
+        self.last = last;
+        self.chunks += 1;
+        self.live += 1;
+        self.len = offset + len;
+        offset
+    }
+}
+
+/// Where a chunk was written, see `JobsFile`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLocation {
+    /// Number of the file.
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A run of consecutive jobs. The payloads are entries of the chunk stream,
+/// removed jobs stay there as tombstones until the chunk is dropped.
 ///
-#[repr(packed)]
+/// Cold chunks are spilled to a chunk file and only their job index stays in
+/// memory. The index is authoritative, jobs removed after the chunk was
+/// written are still in the file.
 pub struct JobsChunk {
     pub first: StreamID,
     pub last: StreamID,
     ///
     pub state: ChunkState,
 
-    ///
+    /// Jobs not removed yet.
     pub list: Box<RaxMap<StreamID, Job>>,
+    /// Payload and dedupe key bytes added to the chunk.
+    pub bytes: u64,
+    /// Jobs added to the chunk, the removed ones included. Jobs may have no
+    /// bytes, so `bytes` does not tell whether there are any.
+    pub added: u64,
+    /// The payloads, none while spilled.
+    stream: Option<Stream>,
+    /// Set once the chunk was written to a chunk file.
+    location: Option<ChunkLocation>,
+    /// Where the chunk is being written.
+    pending: Option<ChunkLocation>,
 }
 
-pub enum Task {
-    Load(String)
+impl JobsChunk {
+    fn new(config: &Config) -> JobsChunk {
+        JobsChunk {
+            first: StreamID::default(),
+            last: StreamID::default(),
+            state: ChunkState::Idle,
+            list: Box::new(RaxMap::new()),
+            bytes: 0,
+            added: 0,
+            stream: Some(config.new_stream()),
+            location: None,
+            pending: None,
+        }
+    }
+
+    /// Number of jobs not removed yet.
+    pub fn len(&self) -> u64 {
+        self.list.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True if the payloads are in memory.
+    pub fn is_resident(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn contains(&self, id: StreamID) -> bool {
+        self.added > 0 && id >= self.first && id <= self.last
+    }
 }
 
-pub struct ConsumerGroup;
+/// Chunk I/O, queued by `JobStream::tick` and run by the `IoPool` if the
+/// stream has one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Task {
+    /// Read the spilled chunk starting at the ID back in.
+    Load(StreamID),
+    /// Write the chunk starting at the ID to the current chunk file and free
+    /// its payloads.
+    Flush(StreamID),
+    /// Copy the live chunks of a file to a new one.
+    Compact(u64),
+    DeleteFile(PathBuf),
+}
 
 pub struct Consumer;
 
+/// The payload of a job about to be delivered.
+enum Payload {
+    Ready(Vec<u8>),
+    /// In a spilled chunk, being loaded.
+    Spilled,
+    Removed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobError {
+    /// A job with the same dedupe key was added within the dedupe window.
+    Duplicate(StreamID),
+    /// Dedupe keys are limited to `MAX_DEDUPE_KEY_LEN` bytes.
+    DedupeKeyTooLong,
+    /// The priority is not below `Config::priorities`.
+    NoPriority(u8),
+    NoGroup,
+    GroupExists,
+    Stream(StreamError),
+}
+
+impl From<StreamError> for JobError {
+    fn from(err: StreamError) -> JobError {
+        JobError::Stream(err)
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JobError::Duplicate(id) => write!(f, "duplicate of job {}", id),
+            JobError::DedupeKeyTooLong => write!(f, "dedupe key longer than {} bytes", MAX_DEDUPE_KEY_LEN),
+            JobError::NoPriority(p) => write!(f, "no priority level {}", p),
+            JobError::NoGroup => write!(f, "no such consumer group"),
+            JobError::GroupExists => write!(f, "consumer group already exists"),
+            JobError::Stream(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for JobError {
+    fn description(&self) -> &str {
+        match *self {
+            JobError::Duplicate(_) => "duplicate job",
+            JobError::DedupeKeyTooLong => "dedupe key too long",
+            JobError::NoPriority(_) => "no such priority level",
+            JobError::NoGroup => "no such consumer group",
+            JobError::GroupExists => "consumer group exists",
+            JobError::Stream(_) => "stream error",
+        }
+    }
+}
+
 /// This is the core Redis Data Type.
 pub struct JobStream {
     flags: u32,
-    stream: *mut stream,
-    /// Chunk of jobs to be consumed
-    head: Box<JobsChunk>,
-    /// Pending Chunks
-    queue: std::collections::VecDeque<Box<JobsChunk>>,
-    /// Chunk
-    tail: Box<JobsChunk>,
-    /// Radix tree of all chunks.
-    chunks: Box<RaxMap<StreamID, JobsChunk>>,
-    /// De-duplication RAX
-    dup: Option<Box<RaxMap<SDS, Job>>>,
+    /// ID of the last job added, of any priority.
+    last_id: StreamID,
+    /// The chunks of each priority level, oldest first. The front one is the
+    /// head consumers read from and the back one the tail new jobs are added
+    /// to.
+    levels: Vec<VecDeque<Box<JobsChunk>>>,
+    /// De-duplication RAX, dedupe key to the ID of the job that added it
+    dup: Option<Box<RaxMap<SdsString, StreamID>>>,
     /// Current configuration to control the behavior and memory consumption.
     config: Config,
     /// Dequeue of tasks.
-    tasks: std::collections::VecDeque<Task>,
+    tasks: VecDeque<Task>,
+    /// Chunk files by number.
+    files: BTreeMap<u64, JobsFile>,
+    /// The file chunks are appended to.
+    current_file: Option<u64>,
+    next_file: u64,
+    /// Runs the tasks off the main thread, without one they run in `tick`.
+    pool: Option<Arc<IoPool>>,
+    /// Results of the tasks submitted to the pool.
+    done: (mpsc::Sender<Done>, mpsc::Receiver<Done>),
+    /// Tasks submitted to the pool without a result yet.
+    in_flight: usize,
+    /// Delayed jobs by ready time.
+    delayed: Box<RaxMap<DelayKey, ()>>,
+    /// Delayed jobs promoted by `tick`, see `pop_promoted`.
+    promoted: VecDeque<StreamID>,
+    groups: Vec<ConsumerGroup>,
+    /// Jobs given up on, see `dead_letters`.
+    dead_letters: Stream,
+    /// Last job of each level visited by the garbage collection.
+    gc_cursors: Vec<StreamID>,
+    /// Number of jobs not removed yet.
+    len: u64,
 }
 
 impl JobStream {
-    fn tick(&mut self) {
-        // Perform cleanup and timeouts
-        // Iterate all consumer groups
-        // Find min ID - First entry on group PEL
-        // Fax max ID -
+    pub fn new(config: Config) -> JobStream {
+        let levels = (0..config.priorities.max(1))
+            .map(|_| {
+                let mut chunks = VecDeque::new();
+                chunks.push_back(Box::new(JobsChunk::new(&config)));
+                chunks
+            })
+            .collect();
+        let dead_letters = config.new_stream();
+        let gc_cursors = vec![StreamID::default(); config.priorities.max(1) as usize];
+        JobStream {
+            flags: 0,
+            last_id: StreamID::default(),
+            levels,
+            dup: if config.dedupe_window_ms > 0 {
+                Some(Box::new(RaxMap::new()))
+            } else {
+                None
+            },
+            config,
+            tasks: VecDeque::new(),
+            files: BTreeMap::new(),
+            current_file: None,
+            next_file: 0,
+            pool: None,
+            done: mpsc::channel(),
+            in_flight: 0,
+            delayed: Box::new(RaxMap::new()),
+            promoted: VecDeque::new(),
+            groups: Vec::new(),
+            dead_letters,
+            gc_cursors,
+            len: 0,
+        }
+    }
+
+    /// Run the chunk file I/O on `pool` from now on.
+    pub fn set_io_pool(&mut self, pool: Arc<IoPool>) {
+        self.pool = Some(pool);
+    }
+
+    /// Apply the finished I/O, collect the jobs consumed by every group,
+    /// drop the chunks left empty and start the I/O keeping the head and the
+    /// tail in memory. With an `IoPool` this is to be called periodically
+    /// from a `Redis::start_timer` timer.
+    pub fn tick(&mut self) {
+        self.poll();
+        let now = unsafe { mstime() } as u64;
+        self.promote(now);
+        self.expire_groups(now);
+        self.collect();
+
+        // Drop the chunks whose jobs were all removed, but the tails.
+        for l in 0..self.levels.len() {
+            let mut i = 0;
+            while i + 1 < self.levels[l].len() {
+                if !self.levels[l][i].is_empty() {
+                    i += 1;
+                    continue;
+                }
+                let chunk = self.levels[l].remove(i).unwrap();
+                for location in chunk.location.iter().chain(chunk.pending.iter()) {
+                    self.release(location.file);
+                }
+            }
+        }
+
+        self.schedule();
+        self.run_tasks();
+    }
+
+    /// Apply the results the pool sent back.
+    fn poll(&mut self) {
+        while let Ok((task, result)) = self.done.1.try_recv() {
+            self.in_flight -= 1;
+            self.finish(task, result);
+        }
+    }
+
+    /// Number of chunks at the head of the level `l` kept in memory, within
+    /// `head_memory_limit` and at least one.
+    fn head_chunks(&self, l: usize) -> usize {
+        let mut bytes = 0;
+        let mut n = 0;
+        for chunk in &self.levels[l] {
+            if n > 0 && bytes + chunk.bytes > self.config.head_memory_limit {
+                break;
+            }
+            bytes += chunk.bytes;
+            n += 1;
+        }
+        n
+    }
+
+    /// Whether each chunk of the level `l` is to be kept in memory: the head
+    /// chunks, the tail and the chunks the consumer groups read next.
+    fn hot_chunks(&self, l: usize) -> Vec<bool> {
+        let mut hot = vec![false; self.levels[l].len()];
+        for i in 0..self.head_chunks(l) {
+            hot[i] = true;
+        }
+        *hot.last_mut().unwrap() = true;
+        for i in self.read_chunks(l) {
+            hot[i] = true;
+        }
+        hot
+    }
+
+    fn is_hot(&self, l: usize, i: usize) -> bool {
+        self.hot_chunks(l)[i]
+    }
+
+    fn all_chunks<'a>(&'a self) -> impl Iterator<Item = &'a Box<JobsChunk>> + 'a {
+        self.levels.iter().flat_map(|chunks| chunks.iter())
+    }
+
+    /// Queue the I/O keeping the head chunks up to `head_memory_limit`, the
+    /// tail of every level and the chunks the groups are about to read in
+    /// memory, every other chunk spilled and the chunk files compact.
+    fn schedule(&mut self) {
+        if self.config.dir.is_none() {
+            return;
+        }
+        let mut tasks = Vec::new();
+        for l in 0..self.levels.len() {
+            let hot_chunks = self.hot_chunks(l);
+            let files = &self.files;
+            for (i, chunk) in self.levels[l].iter_mut().enumerate() {
+                let hot = hot_chunks[i];
+                if chunk.state != ChunkState::Idle {
+                    continue;
+                }
+                if hot && !chunk.is_resident() {
+                    // Read once the file is compacted, the old one goes away.
+                    let readable = chunk.location
+                        .and_then(|l| files.get(&l.file))
+                        .map_or(false, |f| f.compacting.is_none());
+                    if readable {
+                        chunk.state = ChunkState::Loading;
+                        tasks.push(Task::Load(chunk.first));
+                    }
+                } else if !hot && chunk.is_resident() {
+                    tasks.push(Task::Flush(chunk.first));
+                }
+            }
+        }
+
+        for (&n, file) in &self.files {
+            let busy = Some(n) == self.current_file || file.compacting.is_some() || !file.writes.is_empty();
+            if busy || file.live == 0 || file.live * 2 > file.chunks {
+                continue;
+            }
+            let loading = self.all_chunks()
+                .any(|c| c.state == ChunkState::Loading && c.location.map_or(false, |l| l.file == n));
+            if !loading {
+                tasks.push(Task::Compact(n));
+            }
+        }
+
+        for task in tasks {
+            if !self.tasks.contains(&task) {
+                self.tasks.push_back(task);
+            }
+        }
+    }
+
+    fn run_tasks(&mut self) {
+        while let Some(task) = self.tasks.pop_front() {
+            let op = match self.start(&task) {
+                Some(op) => op,
+                None => continue,
+            };
+            match self.pool {
+                Some(ref pool) => {
+                    pool.submit(op, task, &self.done.0);
+                    self.in_flight += 1;
+                }
+                None => {
+                    let result = op.run();
+                    self.finish(task, result);
+                }
+            }
+        }
+    }
+
+    /// Update the chunks for `task` being started and return its I/O, none
+    /// if there is nothing left to do.
+    fn start(&mut self, task: &Task) -> Option<FileOp> {
+        match *task {
+            Task::Load(id) => {
+                let chunk = self.chunk(id)?;
+                let location = chunk.location?;
+                if chunk.state != ChunkState::Loading {
+                    return None;
+                }
+                Some(FileOp::Read {
+                    path: self.files.get(&location.file)?.path.clone(),
+                    offset: location.offset,
+                    len: location.len,
+                })
+            }
+            Task::Flush(id) => {
+                let (l, i) = self.chunk_index(id)?;
+                if self.is_hot(l, i) || self.levels[l][i].state != ChunkState::Idle {
+                    return None;
+                }
+                if self.levels[l][i].location.is_some() {
+                    // Already in a file, loaded back.
+                    self.levels[l][i].stream = None;
+                    return None;
+                }
+                let mut bytes = Vec::new();
+                self.levels[l][i].stream.as_ref()?.dump(&mut bytes).expect("dump to memory");
+
+                let n = match self.current_file {
+                    Some(n) if self.files[&n].chunks < self.config.chunks_per_file.max(1) => n,
+                    current => {
+                        let n = self.new_file();
+                        self.current_file = Some(n);
+                        if let Some(full) = current {
+                            self.delete_if_unused(full);
+                        }
+                        n
+                    }
+                };
+                let (file, chunk) = (self.files.get_mut(&n).unwrap(), &mut self.levels[l][i]);
+                let offset = file.reserve(chunk.first, chunk.last, bytes.len() as u64, self.config.page_size);
+                chunk.pending = Some(ChunkLocation {
+                    file: n,
+                    offset,
+                    len: bytes.len() as u64,
+                });
+                chunk.state = ChunkState::Flushing;
+                file.writes.push(id);
+                Some(FileOp::Write {
+                    path: file.path.clone(),
+                    offset,
+                    bytes,
+                })
+            }
+            Task::Compact(n) => {
+                if self.files.get(&n).map_or(true, |f| f.compacting.is_some()) {
+                    return None;
+                }
+                let to_n = self.next_file;
+                self.next_file += 1;
+                let path = self.new_path(to_n);
+                let mut to = JobsFile::new(path.clone());
+                let mut moved = Vec::new();
+                let mut ranges = Vec::new();
+                for chunk in self.all_chunks() {
+                    if let Some(location) = chunk.location.filter(|l| l.file == n) {
+                        let offset = to.reserve(chunk.first, chunk.last, location.len, self.config.page_size);
+                        ranges.push((location.offset, location.len, offset));
+                        moved.push((chunk.first, ChunkLocation {
+                            file: to_n,
+                            offset,
+                            len: location.len,
+                        }));
+                    }
+                }
+                let from = self.files.get_mut(&n).unwrap();
+                from.compacting = Some((to_n, Box::new(to), moved));
+                Some(FileOp::Copy {
+                    from: from.path.clone(),
+                    to: path,
+                    ranges,
+                })
+            }
+            Task::DeleteFile(ref path) => Some(FileOp::Delete { path: path.clone() }),
+        }
+    }
+
+    /// Apply the result of the I/O of `task`. The chunks may have been
+    /// dropped in the meantime.
+    fn finish(&mut self, task: Task, result: io::Result<Vec<u8>>) {
+        match task {
+            Task::Load(id) => {
+                let chunk = match self.chunk_mut(id) {
+                    Some(chunk) => chunk,
+                    None => return,
+                };
+                if chunk.state != ChunkState::Loading {
+                    return;
+                }
+                match result.ok().and_then(|bytes| Stream::restore(&mut &bytes[..]).ok()) {
+                    Some(mut stream) => {
+                        // Chunk files are never rewritten, the jobs removed
+                        // while spilled are only gone from the index.
+                        let removed: Vec<_> = stream.range(chunk.first, chunk.last, None)
+                            .map(|e| e.id)
+                            .filter(|id| !chunk.list.exists(*id))
+                            .collect();
+                        stream.delete(&removed);
+                        chunk.stream = Some(stream);
+                        chunk.state = ChunkState::Idle;
+                    }
+                    None => chunk.state = ChunkState::Error,
+                }
+            }
+            Task::Flush(id) => {
+                let written = self.files.iter_mut().find(|f| f.1.writes.contains(&id)).map(|(&n, file)| {
+                    file.writes.retain(|w| *w != id);
+                    n
+                });
+                if let Some(n) = written {
+                    // The chunk may have been dropped during the write.
+                    self.delete_if_unused(n);
+                }
+                let (l, i) = match self.chunk_index(id) {
+                    Some(index) => index,
+                    None => return,
+                };
+                let location = match self.levels[l][i].pending.take() {
+                    Some(location) => location,
+                    None => return,
+                };
+                self.levels[l][i].state = ChunkState::Idle;
+                if result.is_ok() {
+                    self.levels[l][i].location = Some(location);
+                    if !self.is_hot(l, i) {
+                        self.levels[l][i].stream = None;
+                    }
+                } else {
+                    // Kept in memory, retried on the next tick.
+                    self.release(location.file);
+                }
+            }
+            Task::Compact(n) => {
+                let (to_n, mut to, moved) = match self.files.get_mut(&n).and_then(|f| f.compacting.take()) {
+                    Some(compacting) => compacting,
+                    None => return,
+                };
+                if result.is_err() {
+                    self.tasks.push_back(Task::DeleteFile(to.path.clone()));
+                    return;
+                }
+                to.live = 0;
+                for (id, location) in moved {
+                    let moved = match self.chunk_mut(id) {
+                        Some(ref mut chunk) if chunk.location.map_or(false, |l| l.file == n) => {
+                            chunk.location = Some(location);
+                            true
+                        }
+                        _ => false,
+                    };
+                    if moved {
+                        self.files.get_mut(&n).unwrap().live -= 1;
+                        to.live += 1;
+                    }
+                }
+                self.files.insert(to_n, *to);
+                self.delete_if_unused(to_n);
+                self.delete_if_unused(n);
+            }
+            Task::DeleteFile(_) => {}
+        }
+    }
+
+    fn new_path(&self, n: u64) -> PathBuf {
+        self.config.dir.as_ref().expect("chunk file directory").join(format!("{}.chunks", n))
+    }
+
+    fn new_file(&mut self) -> u64 {
+        let n = self.next_file;
+        self.next_file += 1;
+        let path = self.new_path(n);
+        self.files.insert(n, JobsFile::new(path));
+        n
+    }
+
+    /// A chunk written to the file `n` was dropped.
+    fn release(&mut self, n: u64) {
+        if let Some(file) = self.files.get_mut(&n) {
+            file.live = file.live.saturating_sub(1);
+        }
+        self.delete_if_unused(n);
+    }
+
+    /// Queue the deletion of the file `n` if it holds no live chunk, no
+    /// more will be added to it and no write to it is running.
+    fn delete_if_unused(&mut self, n: u64) {
+        let unused = self.files.get(&n).map_or(false, |f| {
+            f.live == 0 && f.writes.is_empty() && f.compacting.is_none() && Some(n) != self.current_file
+        });
+        if unused {
+            let file = self.files.remove(&n).unwrap();
+            self.tasks.push_back(Task::DeleteFile(file.path));
+        }
+    }
+
+    /// Append a job with the lowest priority. With a `dedupe_key` the job is
+    /// rejected if another job with the same key was added within the dedupe
+    /// window and was not removed since.
+    pub fn add(&mut self, payload: &[u8], dedupe_key: Option<&[u8]>) -> Result<StreamID, JobError> {
+        self.add_with_priority(payload, dedupe_key, 0)
+    }
+
+    /// Append a job with `priority`, from 0 to `Config::priorities` - 1,
+    /// higher is more urgent. See `add`.
+    pub fn add_with_priority(&mut self, payload: &[u8], dedupe_key: Option<&[u8]>, priority: u8) -> Result<StreamID, JobError> {
+        if priority as usize >= self.levels.len() {
+            return Err(JobError::NoPriority(priority));
+        }
+        let dedupe_key = if self.dup.is_some() { dedupe_key } else { None };
+        if let Some(key) = dedupe_key {
+            if key.len() > MAX_DEDUPE_KEY_LEN {
+                return Err(JobError::DedupeKeyTooLong);
+            }
+            if let Some(id) = self.duplicate_of(key) {
+                return Err(JobError::Duplicate(id));
+            }
+        }
+        self.append(payload, dedupe_key, 0, priority as usize)
+    }
+
+    /// Append a job to the tail chunk of the level `l`, rolling over to a new
+    /// chunk once the tail is full.
+    fn append(&mut self, payload: &[u8], dedupe_key: Option<&[u8]>, ready_at: u64, l: usize) -> Result<StreamID, JobError> {
+        let bytes = (payload.len() + dedupe_key.map_or(0, |k| k.len())) as u64;
+        let full = {
+            let tail = self.levels[l].back().unwrap();
+            tail.added > 0 && tail.bytes + bytes > self.config.chunk_size
+        };
+        if full {
+            let chunk = JobsChunk::new(&self.config);
+            self.levels[l].push_back(Box::new(chunk));
+            self.schedule();
+        }
+
+        let id = self.last_id.next_auto();
+        {
+            let tail = self.levels[l].back_mut().unwrap();
+            let stream = tail.stream.as_mut().expect("resident tail");
+            match dedupe_key {
+                Some(key) => stream.add(&[(PAYLOAD_FIELD, payload), (DEDUPE_FIELD, key)], IdSpec::Explicit(id))?,
+                None => stream.add(&[(PAYLOAD_FIELD, payload)], IdSpec::Explicit(id))?,
+            };
+            if tail.added == 0 {
+                tail.first = id;
+            }
+            tail.last = id;
+            tail.bytes += bytes;
+            tail.added += 1;
+            let _ = tail.list.insert(id, Box::new(Job::new(dedupe_key, ready_at)));
+        }
+        self.last_id = id;
+        self.len += 1;
+
+        if let Some(key) = dedupe_key {
+            self.add_to_dedupe(key, id);
+        }
+        Ok(id)
+    }
+
+    /// Record that the job `id` holds the dedupe key `key`.
+    fn add_to_dedupe(&mut self, key: &[u8], id: StreamID) {
+        if let Some(ref mut dup) = self.dup {
+            let _ = dup.insert(SdsString::new(key), Box::new(id));
+        }
+    }
+
+    /// The job holding `key` if it was added within the dedupe window.
+    fn duplicate_of(&mut self, key: &[u8]) -> Option<StreamID> {
+        let window = self.config.dedupe_window_ms;
+        let dup = self.dup.as_mut()?;
+        let key = SdsString::new(key);
+        let id = *dup.find(key.clone())?;
+        let age = unsafe { mstime() } - id.ms() as i64;
+        if age >= 0 && age as u64 >= window {
+            // Stale, the new job takes the key over.
+            dup.remove(key);
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    /// Remove the job `id`. Its entry becomes a tombstone, for a spilled job
+    /// once its chunk is loaded back, and its dedupe key is released. Returns
+    /// false if there is no such job.
+    pub fn remove_job(&mut self, id: StreamID) -> bool {
+        let job = {
+            let chunk = match self.chunk_mut(id) {
+                Some(chunk) => chunk,
+                None => return false,
+            };
+            match chunk.list.remove(id).1 {
+                Some(job) => {
+                    if let Some(ref mut stream) = chunk.stream {
+                        stream.delete(&[id]);
+                    }
+                    job
+                }
+                None => return false,
+            }
+        };
+        self.len -= 1;
+
+        let ready_at = job.ready_at;
+        if ready_at != 0 {
+            self.delayed.remove(DelayKey::new(ready_at, id));
+        }
+        self.release_dedupe(id, &job);
+        true
+    }
+
+    /// Release the dedupe key of the removed job `id`.
+    fn release_dedupe(&mut self, id: StreamID, job: &Job) {
+        if let (Some(key), Some(dup)) = (job.dedupe_key(), self.dup.as_mut()) {
+            let key = SdsString::new(key);
+            // The key may have been taken over by a newer job.
+            if dup.find(key.clone()) == Some(&id) {
+                dup.remove(key);
+            }
+        }
     }
 
-    fn add(&mut self) {
+    /// The payload of the job `id`, if it was not removed and its chunk is in
+    /// memory. Spilled chunks are never read here, see `payload`.
+    pub fn get(&self, id: StreamID) -> Option<Vec<u8>> {
+        let chunk = self.chunk(id)?;
+        if !chunk.list.exists(id) {
+            return None;
+        }
+        let entry = chunk.stream.as_ref()?.range(id, id, Some(1)).next()?;
+        entry.get(PAYLOAD_FIELD).map(|p| p.to_vec())
+    }
+
+    /// The payload of the job `id` to deliver. If its chunk is spilled the
+    /// chunk is queued to be loaded and the job is to be retried once it is.
+    fn payload(&mut self, id: StreamID) -> Payload {
+        let (l, i) = match self.chunk_index(id) {
+            Some(index) => index,
+            None => return Payload::Removed,
+        };
+        let files = &self.files;
+        let chunk = &mut self.levels[l][i];
+        if !chunk.list.exists(id) || chunk.state == ChunkState::Error {
+            return Payload::Removed;
+        }
+        if !chunk.is_resident() {
+            let readable = chunk.location
+                .and_then(|l| files.get(&l.file))
+                .map_or(false, |f| f.compacting.is_none());
+            if chunk.state == ChunkState::Idle && readable {
+                chunk.state = ChunkState::Loading;
+                self.tasks.push_back(Task::Load(chunk.first));
+            }
+            return Payload::Spilled;
+        }
+        self.get(id).map_or(Payload::Removed, Payload::Ready)
+    }
+
+    /// The priority level and the index in it of the chunk holding `id`. The
+    /// ID ranges of the levels interleave, so past the first job of a chunk
+    /// the job must not have been removed to be found.
+    fn chunk_index(&self, id: StreamID) -> Option<(usize, usize)> {
+        for (l, chunks) in self.levels.iter().enumerate() {
+            let (i, first) = match chunks.binary_search_by(|c| c.first.cmp(&id)) {
+                Ok(i) => (i, true),
+                Err(0) => continue,
+                Err(i) => (i - 1, false),
+            };
+            if chunks[i].contains(id) && (first || chunks[i].list.exists(id)) {
+                return Some((l, i));
+            }
+        }
+        None
+    }
+
+    fn chunk(&self, id: StreamID) -> Option<&JobsChunk> {
+        let (l, i) = self.chunk_index(id)?;
+        Some(&self.levels[l][i])
+    }
+
+    fn chunk_mut(&mut self, id: StreamID) -> Option<&mut JobsChunk> {
+        let (l, i) = self.chunk_index(id)?;
+        Some(&mut self.levels[l][i])
+    }
 
+    /// Number of jobs not removed yet.
+    pub fn len(&self) -> u64 {
+        self.len
     }
 
-    fn add_to_dedupe(&mut self) {
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn last_id(&self) -> StreamID {
+        self.last_id
+    }
 
+    /// Number of priority levels.
+    pub fn priorities(&self) -> usize {
+        self.levels.len()
     }
 
-    fn remove_job(&mut self) {}
+    pub fn chunks(&self) -> usize {
+        self.levels.iter().map(|chunks| chunks.len()).sum()
+    }
+
+    /// Number of chunks with their payloads in memory.
+    pub fn resident_chunks(&self) -> usize {
+        self.all_chunks().filter(|c| c.is_resident()).count()
+    }
+
+    /// Number of chunk files.
+    pub fn files(&self) -> usize {
+        self.files.len()
+    }
+}
+
+impl Drop for JobStream {
+    fn drop(&mut self) {
+        // I/O in flight could still create the files.
+        while self.in_flight > 0 && self.done.1.recv().is_ok() {
+            self.in_flight -= 1;
+        }
+        let files = std::mem::replace(&mut self.files, BTreeMap::new());
+        let paths = files.into_iter().flat_map(|(_, file)| {
+            let to = file.compacting.map(|(_, to, _)| to.path.clone());
+            Some(file.path).into_iter().chain(to)
+        });
+        for path in paths {
+            let op = FileOp::Delete { path: path.clone() };
+            match self.pool {
+                Some(ref pool) => pool.submit(op, Task::DeleteFile(path), &self.done.0),
+                None => {
+                    let _ = op.run();
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -115,7 +1023,349 @@ mod tests {
 
     #[test]
     fn test_packing() {
-        println!("sizeof<Job> =         {}", std::mem::size_of::<Job>());
-//        println!("sizeof<StreamID> =    {}", std::mem::size_of::<StreamID>());
+        assert_eq!(std::mem::size_of::<Job>(), 1 + MAX_DEDUPE_KEY_LEN + 8);
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut jobs = JobStream::new(Config::default());
+        let a = jobs.add(b"first", None).unwrap();
+        let b = jobs.add(b"second", Some(b"order-17")).unwrap();
+        assert!(b > a);
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs.get(b), Some(b"second".to_vec()));
+
+        assert_eq!(jobs.add(b"again", Some(b"order-17")), Err(JobError::Duplicate(b)));
+        assert_eq!(jobs.add(b"x", Some(&[b'k'; MAX_DEDUPE_KEY_LEN + 1])), Err(JobError::DedupeKeyTooLong));
+        assert_eq!(jobs.len(), 2);
+
+        assert!(jobs.remove_job(b));
+        assert!(!jobs.remove_job(b));
+        assert_eq!(jobs.get(b), None);
+        assert!(!jobs.remove_job(StreamID::new(1, 0)));
+
+        // Removing released the key.
+        let c = jobs.add(b"again", Some(b"order-17")).unwrap();
+        assert_eq!(jobs.get(c), Some(b"again".to_vec()));
+        assert_eq!(jobs.len(), 2);
+    }
+
+    #[test]
+    fn empty_payloads() {
+        let mut jobs = JobStream::new(Config::default());
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+        let a = jobs.add(b"", None).unwrap();
+        let b = jobs.add(b"", None).unwrap();
+        assert_eq!(jobs.levels[0][0].first, a);
+        assert_eq!(jobs.get(a), Some(Vec::new()));
+        assert_eq!(jobs.get(b), Some(Vec::new()));
+
+        assert_eq!(jobs.read(b"g", 10).unwrap(), vec![(a, Vec::new()), (b, Vec::new())]);
+        assert!(jobs.remove_job(a));
+        assert!(jobs.remove_job(b));
+        assert_eq!(jobs.len(), 0);
+    }
+
+    #[test]
+    fn dedupe_window() {
+        let mut jobs = JobStream::new(Config {
+            dedupe_window_ms: 20,
+            ..Config::default()
+        });
+        let a = jobs.add(b"a", Some(b"k")).unwrap();
+        assert_eq!(jobs.add(b"b", Some(b"k")), Err(JobError::Duplicate(a)));
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        let b = jobs.add(b"b", Some(b"k")).unwrap();
+
+        // The stale job no longer owns the key.
+        assert!(jobs.remove_job(a));
+        assert_eq!(jobs.add(b"c", Some(b"k")), Err(JobError::Duplicate(b)));
+
+        let mut jobs = JobStream::new(Config {
+            dedupe_window_ms: 0,
+            ..Config::default()
+        });
+        jobs.add(b"a", Some(b"k")).unwrap();
+        jobs.add(b"b", Some(b"k")).unwrap();
+    }
+
+    #[test]
+    fn chunks_roll_over() {
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            ..Config::default()
+        });
+        let ids: Vec<_> = (0..10).map(|_| jobs.add(&[b'x'; 30], None).unwrap()).collect();
+        // Three 30 byte payloads per chunk.
+        assert_eq!(jobs.chunks(), 4);
+        assert!(ids.iter().all(|id| jobs.get(*id).is_some()));
+
+        for id in &ids[..4] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.chunks(), 3);
+        assert_eq!(jobs.len(), 6);
+        assert_eq!(jobs.get(ids[5]), Some(vec![b'x'; 30]));
+    }
+
+    fn chunk_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sliced-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn spill_and_load() {
+        let dir = chunk_dir("spill");
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            chunks_per_file: 2,
+            page_size: 64,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        let ids: Vec<_> = (0..15u8).map(|i| jobs.add(&[i; 30], None).unwrap()).collect();
+        jobs.tick();
+        // Three jobs per chunk, only the head and the tail are in memory.
+        assert_eq!(jobs.chunks(), 5);
+        assert_eq!(jobs.resident_chunks(), 2);
+        assert_eq!(jobs.files(), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        for (i, id) in ids.iter().enumerate() {
+            let resident = i < 3 || i >= 12;
+            assert_eq!(jobs.get(*id), Some(vec![i as u8; 30]).filter(|_| resident));
+        }
+
+        // Removed while spilled.
+        assert!(jobs.remove_job(ids[4]));
+        assert_eq!(jobs.get(ids[4]), None);
+
+        // Consuming the head pages the next chunk in.
+        for id in &ids[..3] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.chunks(), 4);
+        assert_eq!(jobs.resident_chunks(), 2);
+        assert!(jobs.levels[0][0].is_resident());
+        assert_eq!(jobs.levels[0][0].state, ChunkState::Idle);
+        assert_eq!(jobs.get(ids[3]), Some(vec![3; 30]));
+        assert_eq!(jobs.get(ids[4]), None);
+        let loaded = jobs.levels[0][0].stream.as_ref().unwrap();
+        assert_eq!(loaded.range(ids[3], ids[5], None).map(|e| e.id).collect::<Vec<_>>(), vec![ids[3], ids[5]]);
+
+        // A chunk file that cannot be read back.
+        std::fs::write(&jobs.files[&1].path, b"garbage").unwrap();
+        assert_eq!(jobs.get(ids[9]), None);
+        for id in &[ids[3], ids[5], ids[6], ids[7], ids[8]] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.chunks(), 2);
+        assert_eq!(jobs.levels[0][0].state, ChunkState::Error);
+        assert_eq!(jobs.resident_chunks(), 1);
+        assert_eq!(jobs.get(ids[14]), Some(vec![14; 30]));
+
+        drop(jobs);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn groups_page_chunks_in() {
+        let dir = chunk_dir("groups");
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+        for i in 0..15u8 {
+            jobs.add(&[i; 30], None).unwrap();
+        }
+        jobs.tick();
+        assert_eq!(jobs.resident_chunks(), 3);
+
+        // The chunk at the read position and the next one are loaded, the
+        // unacked head stays.
+        assert_eq!(jobs.read(b"g", 3).unwrap().len(), 3);
+        jobs.tick();
+        let resident: Vec<_> = jobs.levels[0].iter().map(|c| c.is_resident()).collect();
+        assert_eq!(resident, vec![true, true, true, false, true]);
+
+        // Spilled again once read past.
+        assert_eq!(jobs.read(b"g", 6).unwrap().len(), 6);
+        jobs.tick();
+        let resident: Vec<_> = jobs.levels[0].iter().map(|c| c.is_resident()).collect();
+        assert_eq!(resident, vec![true, false, false, true, true]);
+
+        drop(jobs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_stops_at_spilled_chunks() {
+        let dir = chunk_dir("read-spilled");
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        jobs.create_group(b"g", GroupConfig::default()).unwrap();
+        let ids: Vec<_> = (0..15u8).map(|i| jobs.add(&[i; 30], None).unwrap()).collect();
+        jobs.tick();
+        assert!(!jobs.levels[0][2].is_resident());
+
+        // The read ends at the spilled chunk and loads it for the next one.
+        let read: Vec<_> = jobs.read(b"g", 9).unwrap().into_iter().map(|j| j.0).collect();
+        assert_eq!(read, &ids[..6]);
+        assert!(jobs.levels[0][2].is_resident());
+        let read: Vec<_> = jobs.read(b"g", 3).unwrap().into_iter().map(|j| j.0).collect();
+        assert_eq!(read, &ids[6..9]);
+
+        drop(jobs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact_and_delete_files() {
+        let dir = chunk_dir("compact");
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            chunks_per_file: 4,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        let ids: Vec<_> = (0..24u8).map(|i| jobs.add(&[i; 30], None).unwrap()).collect();
+        jobs.tick();
+        // Chunks 1 to 4 in file 0, 5 and 6 in file 1.
+        assert_eq!(jobs.chunks(), 8);
+        assert_eq!(jobs.files(), 2);
+
+        // Half of file 0 consumed, compacted once chunk 3 is loaded.
+        for id in &ids[..9] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.files.keys().cloned().collect::<Vec<_>>(), vec![0, 1]);
+        jobs.tick();
+        assert_eq!(jobs.files.keys().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        for (i, id) in ids.iter().enumerate().skip(9) {
+            let resident = jobs.chunk(*id).unwrap().is_resident();
+            assert_eq!(jobs.get(*id), Some(vec![i as u8; 30]).filter(|_| resident));
+        }
+
+        // Consuming everything but the tail deletes every file but the
+        // current one.
+        for id in &ids[9..21] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.files.keys().cloned().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(jobs.get(ids[21]), Some(vec![21; 30]));
+
+        drop(jobs);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_after_writes() {
+        let dir = chunk_dir("delete-writes");
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            chunks_per_file: 1,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        let ids: Vec<_> = (0..15u8).map(|i| jobs.add(&[i; 30], None).unwrap()).collect();
+
+        // The chunk is dropped while it is being written to file 0.
+        let write = jobs.start(&Task::Flush(ids[6])).unwrap();
+        for id in &ids[6..9] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.chunks(), 4);
+        assert_eq!(jobs.files[&0].live, 0);
+        assert!(jobs.files.contains_key(&0));
+
+        let path = jobs.files[&0].path.clone();
+        let result = write.run();
+        jobs.finish(Task::Flush(ids[6]), result);
+        jobs.tick();
+        assert!(!jobs.files.contains_key(&0));
+        assert!(!path.exists());
+
+        drop(jobs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn io_pool() {
+        let dir = chunk_dir("pool");
+        let pool = std::sync::Arc::new(IoPool::new(2));
+        let mut jobs = JobStream::new(Config {
+            chunk_size: 100,
+            head_memory_limit: 100,
+            dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        jobs.set_io_pool(pool.clone());
+        let ids: Vec<_> = (0..15u8).map(|i| jobs.add(&[i; 30], None).unwrap()).collect();
+
+        let settle = |jobs: &mut JobStream| {
+            for _ in 0..1000 {
+                jobs.tick();
+                if jobs.in_flight == 0 && jobs.tasks.is_empty() {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            panic!("I/O did not complete");
+        };
+
+        jobs.tick();
+        // Kept in memory until written.
+        assert!(jobs.levels[0].iter().skip(1).take(3).all(|c| c.state == ChunkState::Flushing));
+        assert_eq!(jobs.resident_chunks(), 5);
+        settle(&mut jobs);
+        assert_eq!(jobs.resident_chunks(), 2);
+
+        for id in &ids[..3] {
+            assert!(jobs.remove_job(*id));
+        }
+        jobs.tick();
+        assert_eq!(jobs.levels[0][0].state, ChunkState::Loading);
+        settle(&mut jobs);
+        assert_eq!(jobs.levels[0][0].state, ChunkState::Idle);
+        assert!(jobs.levels[0][0].is_resident());
+        for (i, id) in ids.iter().enumerate().skip(3) {
+            let resident = i < 6 || i >= 12;
+            assert_eq!(jobs.get(*id), Some(vec![i as u8; 30]).filter(|_| resident));
+        }
+
+        drop(jobs);
+        drop(pool);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_node_limits() {
+        let config = Config {
+            node_max_entries: 1000,
+            ..Config::default()
+        };
+        let s = config.new_stream();
+        assert_eq!((s.node_max_bytes(), s.node_max_entries()), (4096, 1000));
     }
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use super::Task;
+
+/// The outcome of a `FileOp` run for a `Task`. Reads carry the bytes read.
+pub type Done = (Task, io::Result<Vec<u8>>);
+
+/// Blocking file I/O of a `Task`. Everything it needs is owned so it can
+/// run on any thread, the chunk state is only touched when the result is
+/// applied on the main thread.
+#[derive(Debug)]
+pub enum FileOp {
+    /// Read `len` bytes at `offset`.
+    Read { path: PathBuf, offset: u64, len: u64 },
+    /// Write `bytes` at `offset`, creating the file if needed.
+    Write { path: PathBuf, offset: u64, bytes: Vec<u8> },
+    /// Copy byte ranges of `from` into `to`, each a source offset, a length
+    /// and a destination offset.
+    Copy { from: PathBuf, to: PathBuf, ranges: Vec<(u64, u64, u64)> },
+    Delete { path: PathBuf },
+}
+
+impl FileOp {
+    pub fn run(self) -> io::Result<Vec<u8>> {
+        match self {
+            FileOp::Read { path, offset, len } => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                let mut buf = vec![0; len as usize];
+                file.read_exact(&mut buf)?;
+                Ok(buf)
+            }
+            FileOp::Write { path, offset, bytes } => {
+                let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&bytes)?;
+                Ok(Vec::new())
+            }
+            FileOp::Copy { from, to, ranges } => {
+                let mut src = File::open(from)?;
+                let mut dst = OpenOptions::new().write(true).create(true).open(to)?;
+                for (from_offset, len, to_offset) in ranges {
+                    src.seek(SeekFrom::Start(from_offset))?;
+                    dst.seek(SeekFrom::Start(to_offset))?;
+                    if io::copy(&mut (&mut src).take(len), &mut dst)? != len {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk file truncated"));
+                    }
+                }
+                Ok(Vec::new())
+            }
+            FileOp::Delete { path } => fs::remove_file(path).map(|_| Vec::new()),
+        }
+    }
+}
+
+struct Work {
+    op: FileOp,
+    task: Task,
+    done: mpsc::Sender<Done>,
+}
+
+/// Threads running the chunk file I/O of job streams off the Redis main
+/// thread, shared by every `JobStream` given it with `set_io_pool`.
+///
+/// Each result is sent back on the channel of the stream that submitted the
+/// operation. The stream drains it in `JobStream::tick`, which is driven by
+/// a `Redis::start_timer` timer so results are applied on the main thread.
+/// Dropping the pool runs the operations already submitted and joins the
+/// threads.
+pub struct IoPool {
+    sender: Option<mpsc::Sender<Work>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl IoPool {
+    pub fn new(threads: usize) -> IoPool {
+        let (sender, receiver) = mpsc::channel::<Work>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("sliced-io-{}", i))
+                    .spawn(move || loop {
+                        let work = match receiver.lock().unwrap().recv() {
+                            Ok(work) => work,
+                            Err(_) => return,
+                        };
+                        let result = work.op.run();
+                        // The stream may be gone, nobody to tell then.
+                        let _ = work.done.send((work.task, result));
+                    })
+                    .expect("spawn I/O worker")
+            })
+            .collect();
+        IoPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub(super) fn submit(&self, op: FileOp, task: Task, done: &mpsc::Sender<Done>) {
+        let work = Work {
+            op,
+            task,
+            done: done.clone(),
+        };
+        self.sender
+            .as_ref()
+            .expect("I/O pool running")
+            .send(work)
+            .expect("I/O workers alive");
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
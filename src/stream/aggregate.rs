@@ -0,0 +1,271 @@
+use byteorder::{BigEndian, ByteOrder};
+use libc;
+use std;
+
+use super::*;
+
+/// How the values of a field are reduced within a bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggFn {
+    /// Entries having the field, numeric or not.
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    /// The value of the oldest entry in the bucket.
+    First,
+    /// The value of the newest entry in the bucket.
+    Last,
+}
+
+/// One time bucket of `Stream::aggregate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    /// Unix time in milliseconds the bucket starts at.
+    pub ms: u64,
+    /// Entries in the bucket having the field.
+    pub entries: u64,
+    pub value: f64,
+}
+
+#[derive(Default)]
+struct Acc {
+    ms: u64,
+    entries: u64,
+    numeric: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    first: f64,
+    last: f64,
+}
+
+impl Acc {
+    fn add(&mut self, value: &[u8]) {
+        self.entries += 1;
+        let v = match std::str::from_utf8(value).ok().and_then(|s| s.parse::<f64>().ok()) {
+            Some(v) if !v.is_nan() => v,
+            _ => return,
+        };
+        if self.numeric == 0 {
+            self.min = v;
+            self.max = v;
+            self.first = v;
+        } else {
+            self.min = self.min.min(v);
+            self.max = self.max.max(v);
+        }
+        self.numeric += 1;
+        self.sum += v;
+        self.last = v;
+    }
+
+    fn finish(&self, agg: AggFn) -> Option<Bucket> {
+        let value = match agg {
+            AggFn::Count => return Some(Bucket { ms: self.ms, entries: self.entries, value: self.entries as f64 }),
+            _ if self.numeric == 0 => return None,
+            AggFn::Sum => self.sum,
+            AggFn::Min => self.min,
+            AggFn::Max => self.max,
+            AggFn::Avg => self.sum / self.numeric as f64,
+            AggFn::First => self.first,
+            AggFn::Last => self.last,
+        };
+        Some(Bucket { ms: self.ms, entries: self.entries, value })
+    }
+}
+
+impl Stream {
+    /// Reduce the values of `field` for the entries between `start` and `end`
+    /// inclusive into buckets of `bucket_ms` milliseconds, aligned to the Unix
+    /// epoch. Zero makes a single bucket starting at `start`. Values that are
+    /// not numbers are only counted. Buckets without a value are left out.
+    ///
+    /// The listpack nodes are walked directly. Nodes entirely outside of the
+    /// range are skipped using their master ID and last entry, and the field
+    /// position is resolved once per node for entries sharing the master
+    /// fields.
+    pub fn aggregate(&self, start: StreamID, end: StreamID, bucket_ms: u64, field: &[u8], agg: AggFn) -> Vec<Bucket> {
+        let mut buckets = Vec::new();
+        if start > end {
+            return buckets;
+        }
+
+        let mut acc: Option<Acc> = None;
+        let mut value_buf = [0u8; LP_INTBUF_SIZE];
+        let mut start_key = [0u8; 16];
+        BigEndian::write_u64(&mut start_key[..8], start.ms);
+        BigEndian::write_u64(&mut start_key[8..], start.seq);
+
+        unsafe {
+            let mut it = RawRaxIter::new((*self.s).rax);
+            // The node holding `start` has the greatest master ID not above it.
+            it.seek(b"<=\0", &start_key);
+            if it.eof() {
+                it.seek(b"^\0", &[]);
+            }
+            while let Some((key, data)) = it.next() {
+                let mut master_id = StreamID::new(BigEndian::read_u64(&key[..8]), BigEndian::read_u64(&key[8..]));
+                if master_id > end {
+                    break;
+                }
+                let lp = data as *mut listpack;
+                let mut last_id = StreamID::default();
+                streamNodeLastID(lp as *mut u8, &mut master_id, &mut last_id);
+                if last_id < start {
+                    continue;
+                }
+
+                aggregate_node(lp, master_id, start, end, field, &mut value_buf, &mut |id, value| {
+                    let ms = if bucket_ms == 0 { start.ms } else { id.ms - id.ms % bucket_ms };
+                    if acc.as_ref().map_or(true, |a| a.ms != ms) {
+                        if let Some(b) = acc.take().and_then(|a| a.finish(agg)) {
+                            buckets.push(b);
+                        }
+                        acc = Some(Acc { ms, ..Acc::default() });
+                    }
+                    acc.as_mut().unwrap().add(value);
+                });
+            }
+        }
+
+        if let Some(b) = acc.and_then(|a| a.finish(agg)) {
+            buckets.push(b);
+        }
+        buckets
+    }
+}
+
+/// Call `f` with the ID and the value of `field` of every valid entry of the
+/// node `lp` between `start` and `end` having the field.
+unsafe fn aggregate_node<F: FnMut(StreamID, &[u8])>(
+    lp: *mut listpack,
+    master_id: StreamID,
+    start: StreamID,
+    end: StreamID,
+    field: &[u8],
+    buf: &mut [u8; LP_INTBUF_SIZE],
+    f: &mut F,
+) {
+    // Master entry: count | deleted | num-fields | fields... | 0
+    let mut p = lpFirst(lp);
+    let count = lpGetInteger(p);
+    p = lpNext(lp, p);
+    let deleted = lpGetInteger(p);
+    p = lpNext(lp, p);
+    let master_fields = lpGetInteger(p);
+    let mut master_index = None;
+    for i in 0..master_fields {
+        p = lpNext(lp, p);
+        if element(p, buf) == field {
+            master_index = Some(i);
+        }
+    }
+    p = lpNext(lp, p); /* Master entry zero terminator. */
+
+    // Entry: flags | ms-delta | seq-delta | [num-fields | field value...] or
+    // [value...] with SAMEFIELDS | lp-count
+    for _ in 0..count + deleted {
+        p = lpNext(lp, p);
+        let flags = lpGetInteger(p);
+        p = lpNext(lp, p);
+        let ms = master_id.ms + lpGetInteger(p) as u64;
+        p = lpNext(lp, p);
+        let id = StreamID::new(ms, master_id.seq + lpGetInteger(p) as u64);
+        if id > end {
+            return;
+        }
+        let wanted = id >= start && flags & STREAM_ITEM_FLAG_DELETED == 0;
+
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for i in 0..master_fields {
+                p = lpNext(lp, p);
+                if wanted && master_index == Some(i) {
+                    f(id, element(p, buf));
+                }
+            }
+        } else {
+            p = lpNext(lp, p);
+            let fields = lpGetInteger(p);
+            for _ in 0..fields {
+                p = lpNext(lp, p);
+                let matches = wanted && element(p, buf) == field;
+                p = lpNext(lp, p);
+                if matches {
+                    f(id, element(p, buf));
+                }
+            }
+        }
+        p = lpNext(lp, p); /* lp-count */
+    }
+}
+
+/// The bytes of the listpack element `p`. Integers are rendered into `buf`.
+unsafe fn element<'a>(p: *mut u8, buf: &'a mut [u8; LP_INTBUF_SIZE]) -> &'a [u8] {
+    let mut len: libc::int64_t = 0;
+    let ptr = lpGet(p, &mut len, buf.as_mut_ptr());
+    std::slice::from_raw_parts(ptr, len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use stream::{AggFn, Bucket, IdSpec, Stream, StreamID};
+
+    fn metrics() -> Stream {
+        let mut s = Stream::with_node_limits(0, 7);
+        // One entry every 10 seconds for 3 minutes, latency i % 6.
+        for i in 0..18u64 {
+            let latency = format!("{}", i % 6);
+            let host = if i % 2 == 0 { &b"a"[..] } else { &b"b"[..] };
+            s.add(&[(b"latency", latency.as_bytes()), (b"host", host)],
+                  IdSpec::Explicit(StreamID::new(60_000 + i * 10_000, 0))).unwrap();
+        }
+        // Different field shape, not sharing the master fields.
+        s.add(&[(b"host", b"c"), (b"latency", b"slow"), (b"latency2", b"1")],
+              IdSpec::Explicit(StreamID::new(240_000, 0))).unwrap();
+        s.add(&[(b"latency", b"100")], IdSpec::Explicit(StreamID::new(240_001, 0))).unwrap();
+        s.delete(&[StreamID::new(60_000 + 7 * 10_000, 0)]);
+        s
+    }
+
+    fn values(buckets: &[Bucket]) -> Vec<(u64, u64, f64)> {
+        buckets.iter().map(|b| (b.ms / 1000, b.entries, b.value)).collect()
+    }
+
+    #[test]
+    fn per_minute() {
+        let s = metrics();
+        let all = |agg| s.aggregate(StreamID::min(), StreamID::max(), 60_000, b"latency", agg);
+
+        // 60s..110s: 0..5, 120s..170s: 0,2..5 (130s deleted), 180s..230s: 0..5
+        assert_eq!(values(&all(AggFn::Count)), vec![(60, 6, 6.0), (120, 5, 5.0), (180, 6, 6.0), (240, 2, 2.0)]);
+        assert_eq!(values(&all(AggFn::Sum)), vec![(60, 6, 15.0), (120, 5, 14.0), (180, 6, 15.0), (240, 2, 100.0)]);
+        assert_eq!(values(&all(AggFn::Min)), vec![(60, 6, 0.0), (120, 5, 0.0), (180, 6, 0.0), (240, 2, 100.0)]);
+        assert_eq!(values(&all(AggFn::Max))[1], (120, 5, 5.0));
+        assert_eq!(values(&all(AggFn::Avg))[0], (60, 6, 2.5));
+        assert_eq!(values(&all(AggFn::First))[1], (120, 5, 0.0));
+        assert_eq!(values(&all(AggFn::Last)), vec![(60, 6, 5.0), (120, 5, 5.0), (180, 6, 5.0), (240, 2, 100.0)]);
+    }
+
+    #[test]
+    fn range_and_buckets() {
+        let s = metrics();
+        let agg = |start, end, bucket, field: &[u8], agg| {
+            values(&s.aggregate(StreamID::new(start, 0), StreamID::new(end, 0), bucket, field, agg))
+        };
+
+        // Starting in the middle of a node and ending in another.
+        assert_eq!(agg(100_000, 150_000, 60_000, b"latency", AggFn::Sum), vec![(60, 2, 9.0), (120, 3, 5.0)]);
+        assert_eq!(agg(100_000, 150_000, 0, b"latency", AggFn::Count), vec![(100, 5, 5.0)]);
+        assert_eq!(agg(100_000, 150_000, 30_000, b"latency", AggFn::Max), vec![(90, 2, 5.0), (120, 2, 2.0), (150, 1, 3.0)]);
+
+        assert_eq!(agg(60_000, 300_000, 0, b"host", AggFn::Count), vec![(60, 18, 18.0)]);
+        assert_eq!(agg(60_000, 300_000, 0, b"host", AggFn::Sum), vec![]);
+        assert_eq!(agg(60_000, 300_000, 0, b"latency2", AggFn::Sum), vec![(60, 1, 1.0)]);
+        assert_eq!(agg(0, 59_999, 60_000, b"latency", AggFn::Count), vec![]);
+        assert_eq!(agg(300_000, 400_000, 60_000, b"latency", AggFn::Count), vec![]);
+        assert_eq!(agg(200_000, 100_000, 60_000, b"latency", AggFn::Count), vec![]);
+        assert!(Stream::new().aggregate(StreamID::min(), StreamID::max(), 1000, b"x", AggFn::Count).is_empty());
+    }
+}
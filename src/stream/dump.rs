@@ -0,0 +1,702 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use libc;
+use sds::*;
+use std;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::*;
+
+/// Dump layout, every integer big endian:
+///
+/// ```text
+/// magic "RSTM" | version u16 | length u64 | last_id 128 | node_max_bytes u64
+/// | node_max_entries u64
+/// | nodes u64 | nodes * (key 128 | lp_len u32 | listpack)
+/// | groups u64 | groups * (name | last_id 128
+///     | pending u64 | pending * (id 128 | delivery_time i64 | delivery_count u64)
+///     | consumers u64 | consumers * (name | seen_time i64 | pending u64 | pending * id 128))
+/// | XXH64 of everything before
+/// ```
+///
+/// Names are a u32 length followed by the bytes. IDs are stored as 128 bit
+/// big endian numbers, the same as the rax keys, and listpacks verbatim.
+const DUMP_MAGIC: &[u8; 4] = b"RSTM";
+pub const DUMP_VERSION: u16 = 1;
+const DUMP_CHECKSUM_SEED: u64 = 0;
+
+#[derive(Debug)]
+pub enum DumpError {
+    Io(io::Error),
+    /// The input does not start with the dump magic.
+    NotADump,
+    /// The dump was written by a newer format version.
+    UnsupportedVersion(u16),
+    /// The checksum does not match, the dump was truncated or altered.
+    ChecksumMismatch,
+    /// The checksum matches but the content is inconsistent.
+    Corrupt,
+}
+
+impl From<io::Error> for DumpError {
+    fn from(err: io::Error) -> DumpError {
+        DumpError::Io(err)
+    }
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DumpError::Io(ref err) => write!(f, "{}", err),
+            DumpError::NotADump => write!(f, "not a stream dump"),
+            DumpError::UnsupportedVersion(v) => write!(f, "unsupported stream dump version {}", v),
+            DumpError::ChecksumMismatch => write!(f, "stream dump checksum mismatch"),
+            DumpError::Corrupt => write!(f, "corrupt stream dump"),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {
+    fn description(&self) -> &str {
+        match *self {
+            DumpError::Io(_) => "I/O error",
+            DumpError::NotADump => "not a dump",
+            DumpError::UnsupportedVersion(_) => "unsupported version",
+            DumpError::ChecksumMismatch => "checksum mismatch",
+            DumpError::Corrupt => "corrupt",
+        }
+    }
+}
+
+impl Stream {
+    /// Write the stream with its consumer groups to `w`. The listpack nodes
+    /// are written as they are in memory so `restore` gives back the exact
+    /// same stream.
+    pub fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut w = ChecksumWriter::new(w);
+        unsafe {
+            let s = &*self.s;
+            w.write_all(DUMP_MAGIC)?;
+            w.write_u16::<BigEndian>(DUMP_VERSION)?;
+            w.write_u64::<BigEndian>(s.length)?;
+            write_id(&mut w, s.last_id)?;
+            w.write_u64::<BigEndian>(self.node_max_bytes as u64)?;
+            w.write_u64::<BigEndian>(self.node_max_entries)?;
+
+            w.write_u64::<BigEndian>(raxSize(s.rax))?;
+            let mut it = RawRaxIter::new(s.rax);
+            it.seek(b"^\0", &[]);
+            while let Some((key, data)) = it.next() {
+                let lp = data as *mut listpack;
+                w.write_all(key)?;
+                let len = lpBytes(lp);
+                w.write_u32::<BigEndian>(len)?;
+                w.write_all(std::slice::from_raw_parts(lp as *const u8, len as usize))?;
+            }
+
+            if s.cgroups.is_null() {
+                w.write_u64::<BigEndian>(0)?;
+            } else {
+                w.write_u64::<BigEndian>(raxSize(s.cgroups))?;
+                let mut it = RawRaxIter::new(s.cgroups);
+                it.seek(b"^\0", &[]);
+                while let Some((name, data)) = it.next() {
+                    dump_group(&mut w, name, &*(data as *mut streamCG))?;
+                }
+            }
+        }
+        w.finish()
+    }
+
+    /// Read back a stream written by `dump`. The whole dump is read and its
+    /// checksum verified before anything is loaded.
+    pub fn restore<R: Read>(r: &mut R) -> Result<Stream, DumpError> {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        if buf.len() < DUMP_MAGIC.len() + 2 || &buf[..DUMP_MAGIC.len()] != DUMP_MAGIC {
+            return Err(DumpError::NotADump);
+        }
+        let version = (&buf[DUMP_MAGIC.len()..]).read_u16::<BigEndian>()?;
+        if version > DUMP_VERSION {
+            return Err(DumpError::UnsupportedVersion(version));
+        }
+        if buf.len() < DUMP_MAGIC.len() + 2 + 8 {
+            return Err(DumpError::ChecksumMismatch);
+        }
+        let (body, mut trailer) = buf.split_at(buf.len() - 8);
+        let checksum = unsafe { XXH64(body.as_ptr() as *const libc::c_void, body.len(), DUMP_CHECKSUM_SEED) };
+        if trailer.read_u64::<BigEndian>()? != checksum {
+            return Err(DumpError::ChecksumMismatch);
+        }
+
+        // Past the checksum a short read means the writer was wrong.
+        restore_body(&body[DUMP_MAGIC.len() + 2..]).map_err(|e| match e {
+            DumpError::Io(_) => DumpError::Corrupt,
+            e => e,
+        })
+    }
+}
+
+fn restore_body(mut r: &[u8]) -> Result<Stream, DumpError> {
+    let length = r.read_u64::<BigEndian>()?;
+    let last_id = read_id(&mut r)?;
+    let node_max_bytes = r.read_u64::<BigEndian>()? as usize;
+    let node_max_entries = r.read_u64::<BigEndian>()?;
+    let stream = Stream::with_node_limits(node_max_bytes, node_max_entries);
+
+    unsafe {
+        let mut entries = 0;
+        let mut last = None;
+        for _ in 0..r.read_u64::<BigEndian>()? {
+            let key = take(&mut r, size_of::<StreamID>())?;
+            let len = r.read_u32::<BigEndian>()? as usize;
+            let lp = take(&mut r, len)?;
+            // The checksum is no proof the listpack is well formed, the C code
+            // walking it trusts every byte.
+            let node = check_node(read_id(&mut &key[..])?, lp)?;
+            if last.map_or(false, |last| node.first <= last) {
+                return Err(DumpError::Corrupt);
+            }
+            if streamLoadNode(stream.s, key.as_ptr(), lp.as_ptr(), len) != C_OK {
+                return Err(DumpError::Corrupt);
+            }
+            entries += node.count;
+            last = Some(node.last);
+        }
+        if entries != length || last.map_or(false, |last| last > last_id) {
+            return Err(DumpError::Corrupt);
+        }
+        (*stream.s).length = length;
+        (*stream.s).last_id = last_id;
+
+        for _ in 0..r.read_u64::<BigEndian>()? {
+            restore_group(&mut r, &stream)?;
+        }
+    }
+
+    if !r.is_empty() {
+        return Err(DumpError::Corrupt);
+    }
+    Ok(stream)
+}
+
+/// An element of a listpack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LpElement<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+/// The elements of the listpack `lp`, none if any of its encodings, lengths
+/// or back lengths is off.
+fn lp_elements(lp: &[u8]) -> Option<Vec<LpElement>> {
+    const HEADER: usize = 6;
+    if lp.len() < HEADER + 1 || lp[lp.len() - 1] != 0xFF {
+        return None;
+    }
+    let total = u32::from(lp[0]) | u32::from(lp[1]) << 8 | u32::from(lp[2]) << 16 | u32::from(lp[3]) << 24;
+    let count = u16::from(lp[4]) | u16::from(lp[5]) << 8;
+    if total as usize != lp.len() {
+        return None;
+    }
+
+    let end = lp.len() - 1;
+    let mut elements = Vec::new();
+    let mut p = HEADER;
+    while p < end {
+        let rest = &lp[p..end];
+        let b = rest[0];
+        let int = |bytes: usize| -> Option<i64> {
+            let raw = rest.get(1..1 + bytes)?;
+            let v = raw.iter().rev().fold(0u64, |v, b| v << 8 | u64::from(*b));
+            // Sign extend from the top bit of the encoding.
+            let shift = 64 - 8 * bytes as u32;
+            Some(((v << shift) as i64) >> shift)
+        };
+        let (element, len) = match b {
+            _ if b & 0x80 == 0 => (LpElement::Int(i64::from(b)), 1),
+            _ if b & 0xC0 == 0x80 => {
+                let len = 1 + (b & 0x3F) as usize;
+                (LpElement::Str(rest.get(1..len)?), len)
+            }
+            _ if b & 0xE0 == 0xC0 => {
+                let v = i64::from(b & 0x1F) << 8 | i64::from(*rest.get(1)?);
+                (LpElement::Int(if v >= 1 << 12 { v - (1 << 13) } else { v }), 2)
+            }
+            _ if b & 0xF0 == 0xE0 => {
+                let len = 2 + (((b & 0x0F) as usize) << 8 | *rest.get(1)? as usize);
+                (LpElement::Str(rest.get(2..len)?), len)
+            }
+            0xF0 => {
+                let len = 5 + int(4)? as u32 as usize;
+                (LpElement::Str(rest.get(5..len)?), len)
+            }
+            0xF1 => (LpElement::Int(int(2)?), 3),
+            0xF2 => (LpElement::Int(int(3)?), 4),
+            0xF3 => (LpElement::Int(int(4)?), 5),
+            0xF4 => (LpElement::Int(int(8)?), 9),
+            _ => return None,
+        };
+
+        // The back length is the element length in 7 bit groups, most
+        // significant first, all but the first with the high bit set.
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        let encoded = rest.get(len..len + backlen)?;
+        for (i, byte) in encoded.iter().enumerate() {
+            let group = (len >> (7 * (backlen - 1 - i))) as u8 & 0x7F;
+            if *byte != if i == 0 { group } else { group | 0x80 } {
+                return None;
+            }
+        }
+        elements.push(element);
+        p += len + backlen;
+    }
+    if p != end || (count != u16::max_value() && count as usize != elements.len()) {
+        return None;
+    }
+    Some(elements)
+}
+
+/// What `check_node` found in a node.
+struct NodeSummary {
+    /// Valid entries.
+    count: u64,
+    /// ID of the first entry, valid or not.
+    first: StreamID,
+    /// ID of the last entry, valid or not.
+    last: StreamID,
+}
+
+/// Check the listpack `lp` of the node stored under `key` is a stream node:
+/// a master entry with the entry counts and the master fields followed by
+/// the entries in ID order, the first of them with the master ID.
+fn check_node(key: StreamID, lp: &[u8]) -> Result<NodeSummary, DumpError> {
+    let elements = lp_elements(lp).ok_or(DumpError::Corrupt)?;
+    let mut it = elements.iter();
+    let mut int = || match it.next() {
+        Some(&LpElement::Int(v)) if v >= 0 => Ok(v as u64),
+        _ => Err(DumpError::Corrupt),
+    };
+    let count = int()?;
+    let deleted = int()?;
+    let master_fields = int()? as usize;
+    if master_fields == 0 || master_fields > elements.len() {
+        return Err(DumpError::Corrupt);
+    }
+    let mut at = 3 + master_fields;
+    if elements.get(at) != Some(&LpElement::Int(0)) {
+        return Err(DumpError::Corrupt);
+    }
+    at += 1;
+
+    let (mut valid, mut flagged) = (0, 0);
+    let mut ids: Option<(StreamID, StreamID)> = None;
+    while at < elements.len() {
+        let int_at = |i: usize| match elements.get(i) {
+            Some(&LpElement::Int(v)) => Ok(v),
+            _ => Err(DumpError::Corrupt),
+        };
+        let flags = int_at(at)?;
+        if flags & !(STREAM_ITEM_FLAG_DELETED | STREAM_ITEM_FLAG_SAMEFIELDS) != 0 || flags < 0 {
+            return Err(DumpError::Corrupt);
+        }
+        let id = StreamID::new(
+            key.ms.wrapping_add(int_at(at + 1)? as u64),
+            key.seq.wrapping_add(int_at(at + 2)? as u64),
+        );
+        let pieces = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            3 + master_fields
+        } else {
+            let fields = int_at(at + 3)?;
+            if fields <= 0 || fields as usize > elements.len() {
+                return Err(DumpError::Corrupt);
+            }
+            4 + 2 * fields as usize
+        };
+        if int_at(at + pieces)? != pieces as i64 {
+            return Err(DumpError::Corrupt);
+        }
+        at += pieces + 1;
+
+        ids = match ids {
+            None if id == key => Some((id, id)),
+            Some((first, last)) if id > last => Some((first, id)),
+            _ => return Err(DumpError::Corrupt),
+        };
+        if flags & STREAM_ITEM_FLAG_DELETED != 0 {
+            flagged += 1;
+        } else {
+            valid += 1;
+        }
+    }
+    match ids {
+        Some((first, last)) if valid == count && flagged == deleted => Ok(NodeSummary { count, first, last }),
+        _ => Err(DumpError::Corrupt),
+    }
+}
+
+unsafe fn dump_group<W: Write>(w: &mut W, name: &[u8], cg: &streamCG) -> io::Result<()> {
+    write_name(w, name)?;
+    write_id(w, cg.last_id)?;
+
+    w.write_u64::<BigEndian>(raxSize(cg.pel))?;
+    let mut it = RawRaxIter::new(cg.pel);
+    it.seek(b"^\0", &[]);
+    while let Some((id, data)) = it.next() {
+        let nack = &*(data as *mut streamNACK);
+        w.write_all(id)?;
+        w.write_i64::<BigEndian>(nack.delivery_time)?;
+        w.write_u64::<BigEndian>(nack.delivery_count)?;
+    }
+
+    w.write_u64::<BigEndian>(raxSize(cg.consumers))?;
+    let mut it = RawRaxIter::new(cg.consumers);
+    it.seek(b"^\0", &[]);
+    while let Some((name, data)) = it.next() {
+        let consumer = &*(data as *mut streamConsumer);
+        write_name(w, name)?;
+        w.write_i64::<BigEndian>(consumer.seen_time)?;
+        w.write_u64::<BigEndian>(raxSize(consumer.pel))?;
+        let mut pel = RawRaxIter::new(consumer.pel);
+        pel.seek(b"^\0", &[]);
+        while let Some((id, _)) = pel.next() {
+            w.write_all(id)?;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn restore_group(r: &mut &[u8], stream: &Stream) -> Result<(), DumpError> {
+    let name = read_name(r)?;
+    let mut last_id = read_id(r)?;
+    let cg = streamCreateCG(stream.s, name.as_ptr() as *mut libc::c_char, name.len(), &mut last_id);
+    if cg.is_null() {
+        return Err(DumpError::Corrupt);
+    }
+
+    let pending = r.read_u64::<BigEndian>()?;
+    for _ in 0..pending {
+        let mut id = read_id(r)?;
+        let delivery_time = r.read_i64::<BigEndian>()?;
+        let delivery_count = r.read_u64::<BigEndian>()?;
+        if streamLoadNACK(cg, &mut id, delivery_time, delivery_count) != C_OK {
+            return Err(DumpError::Corrupt);
+        }
+    }
+
+    let mut owned = 0;
+    for _ in 0..r.read_u64::<BigEndian>()? {
+        let name = SdsString::new(read_name(r)?);
+        let before = raxSize((*cg).consumers);
+        let consumer = streamLookupConsumer(cg, name.as_raw(), 1);
+        if raxSize((*cg).consumers) == before {
+            return Err(DumpError::Corrupt);
+        }
+        (*consumer).seen_time = r.read_i64::<BigEndian>()?;
+        for _ in 0..r.read_u64::<BigEndian>()? {
+            let mut id = read_id(r)?;
+            if streamLoadConsumerNACK(cg, consumer, &mut id) != C_OK {
+                return Err(DumpError::Corrupt);
+            }
+            owned += 1;
+        }
+    }
+    // Every pending entry belongs to exactly one consumer.
+    if owned != pending {
+        return Err(DumpError::Corrupt);
+    }
+    Ok(())
+}
+
+fn write_id<W: Write>(w: &mut W, id: StreamID) -> io::Result<()> {
+    w.write_u64::<BigEndian>(id.ms)?;
+    w.write_u64::<BigEndian>(id.seq)
+}
+
+fn read_id(r: &mut &[u8]) -> io::Result<StreamID> {
+    Ok(StreamID::new(r.read_u64::<BigEndian>()?, r.read_u64::<BigEndian>()?))
+}
+
+fn write_name<W: Write>(w: &mut W, name: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(name.len() as u32)?;
+    w.write_all(name)
+}
+
+fn read_name<'a>(r: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = r.read_u32::<BigEndian>()? as usize;
+    take(r, len)
+}
+
+/// Split `len` bytes off the front of `r`.
+fn take<'a>(r: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if r.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short stream dump"));
+    }
+    let (head, tail) = r.split_at(len);
+    *r = tail;
+    Ok(head)
+}
+
+/// Hashes everything written through it and appends the hash on `finish`.
+struct ChecksumWriter<'a, W: Write + 'a> {
+    w: &'a mut W,
+    state: *mut libc::c_void,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    fn new(w: &'a mut W) -> ChecksumWriter<'a, W> {
+        let state = unsafe {
+            let state = XXH64_createState();
+            XXH64_reset(state, DUMP_CHECKSUM_SEED);
+            state
+        };
+        ChecksumWriter { w, state }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let checksum = unsafe { XXH64_digest(self.state) };
+        self.w.write_u64::<BigEndian>(checksum)?;
+        self.w.flush()
+    }
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        unsafe { XXH64_update(self.state, buf.as_ptr() as *const libc::c_void, n) };
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+impl<'a, W: Write> Drop for ChecksumWriter<'a, W> {
+    fn drop(&mut self) {
+        unsafe { XXH64_freeState(self.state) };
+    }
+}
+
+#[allow(improper_ctypes)]
+#[allow(non_snake_case)]
+#[link(name = "redismodule", kind = "static")]
+extern "C" {
+    fn XXH64(input: *const libc::c_void, length: libc::size_t, seed: libc::c_ulonglong) -> libc::c_ulonglong;
+
+    fn XXH64_createState() -> *mut libc::c_void;
+
+    fn XXH64_freeState(state: *mut libc::c_void) -> libc::c_int;
+
+    fn XXH64_reset(state: *mut libc::c_void, seed: libc::c_ulonglong) -> libc::c_int;
+
+    fn XXH64_update(state: *mut libc::c_void, input: *const libc::c_void, length: libc::size_t) -> libc::c_int;
+
+    fn XXH64_digest(state: *const libc::c_void) -> libc::c_ulonglong;
+
+    fn streamLoadNode(
+        s: *mut stream,
+        key: *const u8,
+        buf: *const u8,
+        len: libc::size_t,
+    ) -> libc::c_int;
+
+    fn streamLoadNACK(
+        cg: *mut streamCG,
+        id: *mut StreamID,
+        delivery_time: libc::c_longlong,
+        delivery_count: libc::uint64_t,
+    ) -> libc::c_int;
+
+    fn streamLoadConsumerNACK(
+        cg: *mut streamCG,
+        consumer: *mut streamConsumer,
+        id: *mut StreamID,
+    ) -> libc::c_int;
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use libc;
+    use stream::{DumpError, IdSpec, Stream, StreamID};
+
+    use super::{check_node, lp_elements, LpElement, DUMP_CHECKSUM_SEED, XXH64};
+
+    fn sample() -> Stream {
+        let mut s = Stream::with_node_limits(0, 16);
+        for i in 1..101 {
+            let value = format!("{}", i * 1000);
+            s.add(&[(b"n", value.as_bytes()), (b"tag", b"x")], IdSpec::Explicit(StreamID::new(i, 0)))
+                .unwrap();
+        }
+        s.add(&[(b"other", b"shape")], IdSpec::Auto).unwrap();
+        s.delete(&[StreamID::new(3, 0), StreamID::new(50, 0)]);
+
+        s.create_group(b"workers", StreamID::new(0, 0)).unwrap();
+        s.create_group(b"idle", StreamID::new(40, 0)).unwrap();
+        {
+            let mut g = s.group(b"workers").unwrap();
+            g.read(b"alice", Some(5), false);
+            g.read(b"bob", Some(3), false);
+            g.ack(&[StreamID::new(2, 0)]);
+            g.claim(b"bob", 0, &[StreamID::new(1, 0)], false);
+        }
+        s
+    }
+
+    fn dumped(s: &Stream) -> Vec<u8> {
+        let mut buf = Vec::new();
+        s.dump(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let mut s = sample();
+        let buf = dumped(&s);
+        let mut r = Stream::restore(&mut &buf[..]).unwrap();
+
+        assert_eq!(r.info(), s.info());
+        assert_eq!(r.node_max_entries(), 16);
+        assert_eq!(r.range(StreamID::min(), StreamID::max(), None).collect::<Vec<_>>(),
+                   s.range(StreamID::min(), StreamID::max(), None).collect::<Vec<_>>());
+        // Idle times depend on the clock, compare what was stored.
+        let groups = |s: &Stream| -> Vec<_> {
+            s.groups()
+                .into_iter()
+                .map(|g| {
+                    let consumers: Vec<_> = g.consumers.iter()
+                        .map(|c| (c.name.to_sds_string(), c.seen_time, c.pending))
+                        .collect();
+                    (g.name, g.last_id, g.pending, consumers)
+                })
+                .collect()
+        };
+        assert_eq!(groups(&r), groups(&s));
+        {
+            let pending = |s: &mut Stream| -> Vec<_> {
+                s.group(b"workers").unwrap()
+                    .pending(StreamID::min(), StreamID::max(), 100, None)
+                    .iter()
+                    .map(|p| (p.id, p.consumer.to_sds_string(), p.delivery_count))
+                    .collect()
+            };
+            assert_eq!(pending(&mut r), pending(&mut s));
+            assert_eq!(pending(&mut r).len(), 7);
+        }
+        // The restored stream dumps to the same bytes.
+        assert_eq!(dumped(&r), buf);
+
+        let mut g = r.group(b"workers").unwrap();
+        assert_eq!(g.ack(&[StreamID::new(1, 0), StreamID::new(4, 0)]), 2);
+        assert_eq!(g.read(b"carol", Some(1), false)[0].id, StreamID::new(10, 0));
+        drop(g);
+        r.add(&[(b"n", b"after")], IdSpec::Auto).unwrap();
+        assert_eq!(r.len(), s.len() + 1);
+    }
+
+    #[test]
+    fn restore_empty() {
+        let r = Stream::restore(&mut &dumped(&Stream::new())[..]).unwrap();
+        assert!(r.is_empty());
+        assert!(r.groups().is_empty());
+    }
+
+    #[test]
+    fn restore_rejects_bad_input() {
+        let buf = dumped(&sample());
+
+        match Stream::restore(&mut &b"not a dump"[..]) {
+            Err(DumpError::NotADump) => {}
+            r => panic!("{:?}", r.err()),
+        }
+
+        let mut newer = buf.clone();
+        newer[5] = 9;
+        match Stream::restore(&mut &newer[..]) {
+            Err(DumpError::UnsupportedVersion(9)) => {}
+            r => panic!("{:?}", r.err()),
+        }
+
+        for &at in &[6, buf.len() / 2, buf.len() - 1] {
+            let mut flipped = buf.clone();
+            flipped[at] ^= 0x20;
+            match Stream::restore(&mut &flipped[..]) {
+                Err(DumpError::ChecksumMismatch) => {}
+                r => panic!("{}: {:?}", at, r.err()),
+            }
+        }
+        match Stream::restore(&mut &buf[..buf.len() - 20]) {
+            Err(DumpError::ChecksumMismatch) => {}
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    /// `buf` changed by `f` with a valid checksum.
+    fn resealed<F: Fn(&mut Vec<u8>)>(buf: &[u8], f: F) -> Vec<u8> {
+        let mut body = buf[..buf.len() - 8].to_vec();
+        f(&mut body);
+        let checksum = unsafe { XXH64(body.as_ptr() as *const libc::c_void, body.len(), DUMP_CHECKSUM_SEED) };
+        body.write_u64::<BigEndian>(checksum).unwrap();
+        body
+    }
+
+    #[test]
+    fn restore_rejects_malformed_nodes() {
+        let buf = dumped(&sample());
+        assert!(Stream::restore(&mut &resealed(&buf, |_| {})[..]).is_ok());
+
+        // The first node key and its listpack, past the header of the dump.
+        let key = 4 + 2 + 8 + 16 + 8 + 8 + 8;
+        let lp = key + 16 + 4;
+        let count = lp + 6;
+        let cases: Vec<(&str, Box<dyn Fn(&mut Vec<u8>)>)> = vec![
+            ("bad encoding", Box::new(|b: &mut Vec<u8>| b[count] = 0xF5)),
+            ("count", Box::new(|b: &mut Vec<u8>| b[count] += 1)),
+            ("back length", Box::new(|b: &mut Vec<u8>| b[count + 1] += 1)),
+            ("listpack length", Box::new(|b: &mut Vec<u8>| b[lp] ^= 1)),
+            ("terminator", Box::new(|b: &mut Vec<u8>| {
+                let len = (&b[lp - 4..]).read_u32::<BigEndian>().unwrap() as usize;
+                b[lp + len - 1] = 0;
+            })),
+            ("key order", Box::new(|b: &mut Vec<u8>| b[key] = 0xFF)),
+            ("last ID", Box::new(|b: &mut Vec<u8>| {
+                for byte in &mut b[14..30] {
+                    *byte = 0;
+                }
+            })),
+        ];
+        for (what, f) in cases {
+            match Stream::restore(&mut &resealed(&buf, f)[..]) {
+                Err(DumpError::Corrupt) => {}
+                r => panic!("{}: {:?}", what, r.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn listpack_elements() {
+        let mut s = Stream::new();
+        let big = vec![b'x'; 5000];
+        let fields: &[(&[u8], &[u8])] = &[(b"a", b"-1"), (b"b", b"-5000"), (b"c", b"70000"), (b"d", &big[..])];
+        let id = s.add(fields, IdSpec::Auto).unwrap();
+        let buf = dumped(&s);
+        let lp = 4 + 2 + 8 + 16 + 8 + 8 + 8 + 16 + 4;
+        let len = (&buf[lp - 4..]).read_u32::<BigEndian>().unwrap() as usize;
+
+        let elements = lp_elements(&buf[lp..lp + len]).unwrap();
+        assert_eq!(elements[..3], [LpElement::Int(1), LpElement::Int(0), LpElement::Int(4)]);
+        assert_eq!(elements[3], LpElement::Str(b"a"));
+        assert_eq!(elements[8..12], [LpElement::Int(2), LpElement::Int(0), LpElement::Int(0), LpElement::Int(-1)]);
+        assert_eq!(elements[12..15], [LpElement::Int(-5000), LpElement::Int(70000), LpElement::Str(&big[..])]);
+        assert_eq!(elements[15], LpElement::Int(7));
+        assert_eq!(check_node(id, &buf[lp..lp + len]).unwrap().count, 1);
+    }
+}
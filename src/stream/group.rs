@@ -0,0 +1,599 @@
+use libc;
+use rax::*;
+use sds::*;
+use std;
+
+use super::*;
+
+/// A consumer group of a `Stream`. Deliveries are tracked in the group
+/// pending entries list (PEL) and in the PEL of the consumer the entry was
+/// delivered to, the same as XREADGROUP, XACK and XPENDING.
+pub struct ConsumerGroup<'a> {
+    stream: &'a Stream,
+    name: SdsString,
+    cg: *mut streamCG,
+    dead_letter: Option<Box<dyn FnMut(DeadLetter<'a>) + 'a>>,
+}
+
+/// An entry taken out of the PEL by `claim` or `auto_claim` because it was
+/// already delivered the maximum number of times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter<'a> {
+    pub entry: StreamEntry<'a>,
+    /// The consumer that held the entry last.
+    pub consumer: SdsString,
+    pub delivery_count: u64,
+}
+
+/// Result of `auto_claim`, the same as the XAUTOCLAIM reply.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoClaim<'a> {
+    /// Where the next call should start, or 0-0 when the PEL was scanned
+    /// to the end.
+    pub next: StreamID,
+    pub entries: Vec<StreamEntry<'a>>,
+    /// Pending IDs whose entry no longer exists in the stream. They were
+    /// removed from the PEL.
+    pub deleted: Vec<StreamID>,
+}
+
+/// Summary form of XPENDING.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingSummary<'g> {
+    pub count: u64,
+    pub min_id: Option<StreamID>,
+    pub max_id: Option<StreamID>,
+    /// Consumers with at least one pending entry and how many they hold,
+    /// ordered by name.
+    pub consumers: Vec<(SdsRef<'g>, u64)>,
+}
+
+/// Extended form of XPENDING, one per pending entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry<'g> {
+    pub id: StreamID,
+    pub consumer: SdsRef<'g>,
+    /// Milliseconds since the last delivery.
+    pub idle: u64,
+    pub delivery_count: u64,
+}
+
+impl Stream {
+    /// Create the consumer group `name` that will deliver entries after
+    /// `last_id`, the same as XGROUP CREATE. Pass `last_id()` for `$`.
+    pub fn create_group(&mut self, name: &[u8], mut last_id: StreamID) -> Result<(), StreamError> {
+        let cg = unsafe {
+            streamCreateCG(self.s, name.as_ptr() as *mut libc::c_char, name.len(), &mut last_id)
+        };
+        if cg.is_null() {
+            Err(StreamError::GroupExists)
+        } else {
+            self.emit(StreamEvent::GroupCreate { group: name, last_id });
+            Ok(())
+        }
+    }
+
+    /// A handle to the consumer group `name`. It borrows the stream mutably,
+    /// so there is one handle at a time and no other can free the consumers
+    /// it hands out the names of.
+    pub fn group<'a>(&'a mut self, name: &[u8]) -> Option<ConsumerGroup<'a>> {
+        let name = SdsString::new(name);
+        let cg = unsafe { streamLookupCG(self.s, name.as_raw()) };
+        if cg.is_null() {
+            None
+        } else {
+            Some(ConsumerGroup {
+                stream: self,
+                name,
+                cg,
+                dead_letter: None,
+            })
+        }
+    }
+}
+
+impl<'a> ConsumerGroup<'a> {
+    /// The ID of the last entry delivered to the group.
+    pub fn last_id(&self) -> StreamID {
+        unsafe { (*self.cg).last_id }
+    }
+
+    /// Deliver entries never delivered to the group before to `consumer`,
+    /// creating the consumer if needed. This is XREADGROUP with the `>` ID.
+    /// With `noack` the entries are not added to the PEL.
+    pub fn read(&mut self, consumer: &[u8], count: Option<usize>, noack: bool) -> Vec<StreamEntry<'a>> {
+        let start = match self.last_id().next() {
+            Some(id) => id,
+            None => return Vec::new(),
+        };
+
+        let consumer = self.lookup_consumer(consumer, true);
+        let entries: Vec<StreamEntry<'a>> = self.stream.range(start, StreamID::max(), count).collect();
+        for entry in &entries {
+            let mut id = entry.id;
+            unsafe { streamGroupDeliver(self.cg, consumer, &mut id, noack as libc::c_int) }
+            if !noack {
+                self.emit_claim(consumer, id);
+            }
+        }
+        if !entries.is_empty() {
+            self.stream.emit(StreamEvent::GroupSetId { group: &self.name, last_id: self.last_id() });
+        }
+        entries
+    }
+
+    /// Remove the entries from the PEL, the same as XACK. Returns the number
+    /// of entries that were pending.
+    pub fn ack(&mut self, ids: &[StreamID]) -> u64 {
+        let mut acked = Vec::new();
+        for id in ids {
+            let mut id = *id;
+            if unsafe { streamGroupAck(self.cg, &mut id) } != 0 {
+                acked.push(id);
+            }
+        }
+        if !acked.is_empty() {
+            self.stream.emit(StreamEvent::Ack { group: &self.name, ids: &acked });
+        }
+        acked.len() as u64
+    }
+
+    /// Delete `consumer` from the group, dropping its pending entries.
+    /// Returns the number of pending entries it had, zero if there is no
+    /// such consumer.
+    pub fn delete_consumer(&mut self, consumer: &[u8]) -> u64 {
+        let name = SdsString::new(consumer);
+        if unsafe { streamLookupConsumer(self.cg, name.as_raw(), 0) }.is_null() {
+            return 0;
+        }
+        let pending = unsafe { streamDelConsumer(self.cg, name.as_raw()) };
+        self.stream.emit(StreamEvent::ConsumerDelete { group: &self.name, consumer });
+        pending
+    }
+
+    /// The consumers of the group ordered by name, the same as XINFO
+    /// CONSUMERS.
+    pub fn consumers<'g>(&'g self) -> Vec<ConsumerInfo<'g>> {
+        unsafe { info::consumers_of(self.cg, mstime()) }
+    }
+
+    /// Number of entries delivered and not yet acknowledged.
+    pub fn pending_count(&self) -> u64 {
+        unsafe { raxSize((*self.cg).pel) }
+    }
+
+    pub fn pending_summary<'g>(&'g self) -> PendingSummary<'g> {
+        let pel = unsafe { (*self.cg).pel };
+        let mut summary = PendingSummary {
+            count: unsafe { raxSize(pel) },
+            min_id: None,
+            max_id: None,
+            consumers: Vec::new(),
+        };
+        if summary.count == 0 {
+            return summary;
+        }
+
+        unsafe {
+            let mut it = RawRaxIter::new(pel);
+            if it.seek(b"^\0", &[]) {
+                summary.min_id = it.next().map(|(key, _)| StreamID::from_buf(key.as_ptr(), key.len()));
+            }
+            if it.seek(b"$\0", &[]) {
+                summary.max_id = it.prev().map(|(key, _)| StreamID::from_buf(key.as_ptr(), key.len()));
+            }
+
+            let mut it = RawRaxIter::new((*self.cg).consumers);
+            it.seek(b"^\0", &[]);
+            while let Some((_, data)) = it.next() {
+                let consumer = data as *mut streamConsumer;
+                let count = raxSize((*consumer).pel);
+                if count > 0 {
+                    summary.consumers.push((SdsRef::from_raw((*consumer).name), count));
+                }
+            }
+        }
+        summary
+    }
+
+    /// Pending entries with IDs between `start` and `end` inclusive, at most
+    /// `count` of them, optionally only the ones owned by `consumer`.
+    pub fn pending<'g>(
+        &'g self,
+        start: StreamID,
+        end: StreamID,
+        count: usize,
+        consumer: Option<&[u8]>,
+    ) -> Vec<PendingEntry<'g>> {
+        let pel = match consumer {
+            None => unsafe { (*self.cg).pel },
+            Some(name) => {
+                let consumer = self.lookup_consumer(name, false);
+                if consumer.is_null() {
+                    return Vec::new();
+                }
+                unsafe { (*consumer).pel }
+            }
+        };
+
+        let now = unsafe { mstime() };
+        let mut pending = Vec::new();
+        unsafe {
+            let mut it = RawRaxIter::new(pel);
+            let start_key = start.encode();
+            let (ptr, len) = start_key.to_buf();
+            it.seek(b">=\0", std::slice::from_raw_parts(ptr, len));
+            while pending.len() < count {
+                let (key, data) = match it.next() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let id = StreamID::from_buf(key.as_ptr(), key.len());
+                if id > end {
+                    break;
+                }
+                let nack = data as *mut streamNACK;
+                let consumer = match consumer_name(nack) {
+                    Some(consumer) => consumer,
+                    None => continue,
+                };
+                pending.push(PendingEntry {
+                    id,
+                    consumer,
+                    idle: std::cmp::max(now - (*nack).delivery_time, 0) as u64,
+                    delivery_count: (*nack).delivery_count,
+                });
+            }
+        }
+        pending
+    }
+
+    /// Route entries delivered `max_deliveries` times or more to `f` instead
+    /// of claiming them again. They are removed from the PEL. The limit is
+    /// kept by the stream for every later handle of the group, `f` only
+    /// serves this handle, see `on_dead_letter`.
+    pub fn dead_letter<F>(&mut self, max_deliveries: u64, f: F)
+    where
+        F: FnMut(DeadLetter<'a>) + 'a,
+    {
+        self.stream.max_deliveries.borrow_mut().insert(self.name.clone(), max_deliveries);
+        self.on_dead_letter(f);
+    }
+
+    /// Route the entries over the max-delivery limit set by `dead_letter` to
+    /// `f`. Without it they are dropped from the PEL all the same.
+    pub fn on_dead_letter<F>(&mut self, f: F)
+    where
+        F: FnMut(DeadLetter<'a>) + 'a,
+    {
+        self.dead_letter = Some(Box::new(f));
+    }
+
+    /// The max-delivery limit of the group, if any.
+    pub fn max_deliveries(&self) -> Option<u64> {
+        self.stream.max_deliveries.borrow().get(&self.name).cloned()
+    }
+
+    /// Transfer the pending entries `ids` idle for at least `min_idle`
+    /// milliseconds to `consumer`, the same as XCLAIM. With `force` entries
+    /// that exist in the stream but are not pending are claimed too.
+    /// Returns the claimed entries.
+    pub fn claim(
+        &mut self,
+        consumer: &[u8],
+        min_idle: u64,
+        ids: &[StreamID],
+        force: bool,
+    ) -> Vec<StreamEntry<'a>> {
+        let consumer = self.lookup_consumer(consumer, true);
+        let mut claimed = Vec::new();
+        for id in ids {
+            let nack = self.find_nack(*id);
+            if nack.is_null() && !force {
+                continue;
+            }
+            if !nack.is_null() && !self.idle_for(nack, min_idle) {
+                continue;
+            }
+            match self.claim_one(consumer, *id, nack) {
+                Claim::Claimed(entry) => claimed.push(entry),
+                Claim::Missing if !nack.is_null() => {
+                    self.ack(&[*id]);
+                }
+                _ => {}
+            }
+        }
+        claimed
+    }
+
+    /// Scan the PEL from `start` and claim up to `count` entries idle for at
+    /// least `min_idle` milliseconds, the same as XAUTOCLAIM. Like it, at most
+    /// `count * 10` entries are scanned per call so a large PEL does not block
+    /// the event loop, the rest is left to the call starting at `next`.
+    pub fn auto_claim(
+        &mut self,
+        consumer: &[u8],
+        min_idle: u64,
+        start: StreamID,
+        count: usize,
+    ) -> AutoClaim<'a> {
+        let mut candidates = Vec::with_capacity(count);
+        let mut next = StreamID::default();
+        let mut attempts = count.saturating_mul(10);
+        unsafe {
+            let mut it = RawRaxIter::new((*self.cg).pel);
+            let start_key = start.encode();
+            let (ptr, len) = start_key.to_buf();
+            it.seek(b">=\0", std::slice::from_raw_parts(ptr, len));
+            while let Some((key, data)) = it.next() {
+                let id = StreamID::from_buf(key.as_ptr(), key.len());
+                if candidates.len() == count || attempts == 0 {
+                    next = id;
+                    break;
+                }
+                attempts -= 1;
+                if self.idle_for(data as *mut streamNACK, min_idle) {
+                    candidates.push(id);
+                }
+            }
+        }
+
+        let consumer = self.lookup_consumer(consumer, true);
+        let mut result = AutoClaim {
+            next,
+            entries: Vec::new(),
+            deleted: Vec::new(),
+        };
+        for id in candidates {
+            let nack = self.find_nack(id);
+            match self.claim_one(consumer, id, nack) {
+                Claim::Claimed(entry) => result.entries.push(entry),
+                Claim::Missing => {
+                    self.ack(&[id]);
+                    result.deleted.push(id);
+                }
+                Claim::DeadLetter => {}
+            }
+        }
+        result
+    }
+
+    /// Claim a single entry that passed the idle check. `nack` is null when
+    /// forcing an entry that is not pending.
+    fn claim_one(&mut self, consumer: *mut streamConsumer, mut id: StreamID, nack: *mut streamNACK) -> Claim<'a> {
+        let entry = match self.stream.range(id, id, Some(1)).next() {
+            Some(entry) => entry,
+            None => return Claim::Missing,
+        };
+
+        if !nack.is_null() {
+            let delivery_count = unsafe { (*nack).delivery_count };
+            let max_deliveries = self.max_deliveries().unwrap_or(u64::max_value());
+            if delivery_count >= max_deliveries {
+                let owner = unsafe { consumer_name(nack) }.map_or(SdsString::new(b""), |name| name.to_sds_string());
+                self.ack(&[id]);
+                if let Some(ref mut f) = self.dead_letter {
+                    f(DeadLetter {
+                        entry,
+                        consumer: owner,
+                        delivery_count,
+                    });
+                }
+                return Claim::DeadLetter;
+            }
+        }
+
+        unsafe { streamGroupClaim(self.cg, consumer, &mut id) };
+        self.emit_claim(consumer, id);
+        Claim::Claimed(entry)
+    }
+
+    /// Tell the observers `id` is now pending for `consumer`.
+    fn emit_claim(&self, consumer: *mut streamConsumer, id: StreamID) {
+        let nack = self.find_nack(id);
+        unsafe {
+            self.stream.emit(StreamEvent::Claim {
+                group: &self.name,
+                consumer: SdsRef::from_raw((*consumer).name).as_bytes(),
+                id,
+                delivery_time: (*nack).delivery_time,
+                delivery_count: (*nack).delivery_count,
+            });
+        }
+    }
+
+    fn find_nack(&self, id: StreamID) -> *mut streamNACK {
+        let key = id.encode();
+        let (ptr, len) = key.to_buf();
+        let nack = unsafe { raxFind((*self.cg).pel, ptr, len) };
+        if nack == unsafe { raxNotFound } {
+            ptr::null_mut()
+        } else {
+            nack as *mut streamNACK
+        }
+    }
+
+    fn idle_for(&self, nack: *mut streamNACK, min_idle: u64) -> bool {
+        let idle = unsafe { mstime() - (*nack).delivery_time };
+        idle >= 0 && idle as u64 >= min_idle
+    }
+
+    fn lookup_consumer(&self, name: &[u8], create: bool) -> *mut streamConsumer {
+        let name = self.stream.name(name);
+        unsafe { streamLookupConsumer(self.cg, name.as_raw(), create as libc::c_int) }
+    }
+}
+
+/// The name of the consumer holding `nack`, none if it has no owner.
+unsafe fn consumer_name<'g>(nack: *mut streamNACK) -> Option<SdsRef<'g>> {
+    let consumer = (*nack).consumer;
+    if consumer.is_null() {
+        None
+    } else {
+        Some(SdsRef::from_raw((*consumer).name))
+    }
+}
+
+enum Claim<'a> {
+    Claimed(StreamEntry<'a>),
+    /// The entry is not in the stream anymore.
+    Missing,
+    DeadLetter,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::thread;
+    use std::time::Duration;
+    use stream::{IdSpec, Stream, StreamError, StreamID};
+
+    fn filled(n: u64) -> Stream {
+        let mut s = Stream::new();
+        for i in 1..n + 1 {
+            s.add(&[(b"n", format!("{}", i).as_bytes())], IdSpec::Explicit(StreamID::new(i, 0)))
+                .unwrap();
+        }
+        s
+    }
+
+    #[test]
+    fn read_and_ack() {
+        let mut s = filled(10);
+        s.create_group(b"workers", StreamID::new(0, 0)).unwrap();
+        assert_eq!(s.create_group(b"workers", StreamID::new(0, 0)), Err(StreamError::GroupExists));
+        assert!(s.group(b"nobody").is_none());
+
+        let mut g = s.group(b"workers").unwrap();
+        let a: Vec<_> = g.read(b"alice", Some(4), false).iter().map(|e| e.id.ms()).collect();
+        assert_eq!(a, vec![1, 2, 3, 4]);
+        let b: Vec<_> = g.read(b"bob", None, false).iter().map(|e| e.id.ms()).collect();
+        assert_eq!(b, (5..11).collect::<Vec<_>>());
+        assert!(g.read(b"bob", None, false).is_empty());
+        assert_eq!(g.last_id(), StreamID::new(10, 0));
+        assert_eq!(g.pending_count(), 10);
+
+        assert_eq!(g.ack(&[StreamID::new(1, 0), StreamID::new(6, 0), StreamID::new(99, 0)]), 2);
+        assert_eq!(g.ack(&[StreamID::new(1, 0)]), 0);
+
+        let summary = g.pending_summary();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.min_id, Some(StreamID::new(2, 0)));
+        assert_eq!(summary.max_id, Some(StreamID::new(10, 0)));
+        let consumers: Vec<_> = summary.consumers.iter().map(|c| (c.0.as_bytes(), c.1)).collect();
+        assert_eq!(consumers, vec![(&b"alice"[..], 3), (&b"bob"[..], 5)]);
+    }
+
+    #[test]
+    fn noack_and_pending() {
+        let mut s = filled(6);
+        s.create_group(b"g", StreamID::new(2, 0)).unwrap();
+        {
+            let mut g = s.group(b"g").unwrap();
+            assert_eq!(g.read(b"x", Some(1), true).len(), 1);
+            assert_eq!(g.pending_count(), 0);
+            assert_eq!(g.read(b"y", None, false).len(), 3);
+
+            let p = g.pending(StreamID::new(0, 0), StreamID::max(), 10, None);
+            assert_eq!(p.iter().map(|e| e.id.ms()).collect::<Vec<_>>(), vec![4, 5, 6]);
+            assert!(p.iter().all(|e| e.consumer.as_bytes() == b"y" && e.delivery_count == 1));
+            assert!(p[0].idle < 1000);
+
+            assert_eq!(g.pending(StreamID::new(5, 0), StreamID::new(5, 0), 10, None).len(), 1);
+            assert_eq!(g.pending(StreamID::new(0, 0), StreamID::max(), 2, Some(b"y")).len(), 2);
+            assert!(g.pending(StreamID::new(0, 0), StreamID::max(), 10, Some(b"x")).is_empty());
+            assert!(g.pending(StreamID::new(0, 0), StreamID::max(), 10, Some(b"z")).is_empty());
+
+            assert_eq!(g.delete_consumer(b"y"), 3);
+            assert_eq!(g.pending_count(), 0);
+        }
+
+        // New entries are delivered after the group last ID.
+        s.add(&[(b"n", b"7")], IdSpec::Explicit(StreamID::new(7, 0))).unwrap();
+        let mut g = s.group(b"g").unwrap();
+        assert_eq!(g.read(b"x", None, false)[0].id, StreamID::new(7, 0));
+    }
+
+    #[test]
+    fn claim() {
+        let mut s = filled(5);
+        s.create_group(b"g", StreamID::new(0, 0)).unwrap();
+        s.delete(&[StreamID::new(2, 0)]);
+        let mut g = s.group(b"g").unwrap();
+        assert_eq!(g.read(b"dead-worker", Some(3), false).len(), 3);
+
+        // Not idle long enough yet.
+        let ids = [StreamID::new(1, 0), StreamID::new(3, 0), StreamID::new(4, 0)];
+        assert!(g.claim(b"alice", 60000, &ids, false).is_empty());
+
+        thread::sleep(Duration::from_millis(20));
+        let claimed: Vec<_> = g.claim(b"alice", 10, &ids, false).iter().map(|e| e.id.ms()).collect();
+        assert_eq!(claimed, vec![1, 3, 4]);
+
+        let p = g.pending(StreamID::new(0, 0), StreamID::max(), 10, None);
+        assert!(p.iter().all(|e| e.consumer.as_bytes() == b"alice" && e.delivery_count == 2));
+        assert!(g.pending(StreamID::new(0, 0), StreamID::max(), 10, Some(b"dead-worker")).is_empty());
+
+        // FORCE claims entries that exist but were never delivered.
+        assert!(g.claim(b"bob", 0, &[StreamID::new(5, 0)], false).is_empty());
+        assert_eq!(g.claim(b"bob", 0, &[StreamID::new(5, 0), StreamID::new(2, 0)], true).len(), 1);
+        let p = g.pending(StreamID::new(5, 0), StreamID::new(5, 0), 1, Some(b"bob"));
+        assert_eq!(p[0].delivery_count, 1);
+        assert_eq!(g.pending_count(), 4);
+    }
+
+    #[test]
+    fn auto_claim_and_dead_letter() {
+        let mut s = filled(10);
+        s.create_group(b"g", StreamID::new(0, 0)).unwrap();
+        s.delete(&[StreamID::new(3, 0)]);
+        {
+            let mut g = s.group(b"g").unwrap();
+            g.read(b"a", None, false);
+        }
+        s.delete(&[StreamID::new(4, 0)]);
+
+        let dead = RefCell::new(Vec::new());
+        let mut g = s.group(b"g").unwrap();
+        g.dead_letter(3, |d| dead.borrow_mut().push((d.entry.id.ms(), d.consumer.to_string(), d.delivery_count)));
+
+        let r = g.auto_claim(b"b", 0, StreamID::new(0, 0), 3);
+        assert_eq!(r.entries.iter().map(|e| e.id.ms()).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(r.deleted, vec![StreamID::new(4, 0)]);
+        assert_eq!(r.next, StreamID::new(5, 0));
+
+        let r = g.auto_claim(b"b", 0, r.next, 100);
+        assert_eq!(r.entries.len(), 6);
+        assert_eq!(r.next, StreamID::new(0, 0));
+        assert_eq!(g.pending_count(), 8);
+
+        // Entry 1 reaches three deliveries, the fourth attempt dead letters it.
+        g.claim(b"c", 0, &[StreamID::new(1, 0)], false);
+        assert!(g.claim(b"d", 0, &[StreamID::new(1, 0)], false).is_empty());
+        assert_eq!(g.pending_count(), 7);
+        drop(g);
+        assert_eq!(*dead.borrow(), vec![(1, "c".to_string(), 3)]);
+
+        // The limit outlives the handle.
+        let mut g = s.group(b"g").unwrap();
+        assert_eq!(g.max_deliveries(), Some(3));
+        g.claim(b"c", 0, &[StreamID::new(2, 0)], false);
+        assert!(g.claim(b"d", 0, &[StreamID::new(2, 0)], false).is_empty());
+        assert_eq!(g.pending_count(), 6);
+    }
+
+    #[test]
+    fn auto_claim_scan_limit() {
+        let mut s = filled(30);
+        s.create_group(b"g", StreamID::new(0, 0)).unwrap();
+        let mut g = s.group(b"g").unwrap();
+        g.read(b"a", None, false);
+
+        // Nothing is idle, ten entries are scanned per entry asked for.
+        let r = g.auto_claim(b"b", 60000, StreamID::new(0, 0), 1);
+        assert!(r.entries.is_empty());
+        assert_eq!(r.next, StreamID::new(11, 0));
+        let r = g.auto_claim(b"b", 60000, r.next, 2);
+        assert_eq!(r.next, StreamID::new(0, 0));
+    }
+}
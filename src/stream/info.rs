@@ -0,0 +1,170 @@
+use sds::*;
+
+use super::*;
+
+/// The XINFO STREAM reply.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamInfo<'a> {
+    pub length: u64,
+    /// Number of keys in the radix tree, one per listpack.
+    pub radix_tree_keys: u64,
+    pub radix_tree_nodes: u64,
+    pub groups: u64,
+    pub last_id: StreamID,
+    pub first_entry: Option<StreamEntry<'a>>,
+    pub last_entry: Option<StreamEntry<'a>>,
+}
+
+/// The XINFO STREAM FULL reply: the stream plus every group and consumer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamInfoFull<'a> {
+    pub info: StreamInfo<'a>,
+    pub groups: Vec<GroupInfo<'a>>,
+}
+
+/// One element of the XINFO GROUPS reply, with the consumers of XINFO
+/// CONSUMERS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupInfo<'a> {
+    pub name: SdsString,
+    pub last_id: StreamID,
+    pub pending: u64,
+    pub consumers: Vec<ConsumerInfo<'a>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerInfo<'a> {
+    pub name: SdsRef<'a>,
+    /// Unix time in milliseconds the consumer was last active.
+    pub seen_time: i64,
+    /// Milliseconds since `seen_time`.
+    pub idle: u64,
+    pub pending: u64,
+}
+
+impl Stream {
+    pub fn info<'a>(&'a self) -> StreamInfo<'a> {
+        unsafe {
+            let s = &*self.s;
+            StreamInfo {
+                length: s.length,
+                radix_tree_keys: (*s.rax).numele,
+                radix_tree_nodes: (*s.rax).numnodes,
+                groups: if s.cgroups.is_null() { 0 } else { (*s.cgroups).numele },
+                last_id: s.last_id,
+                first_entry: self.range(StreamID::min(), StreamID::max(), Some(1)).next(),
+                last_entry: self.rev_range(StreamID::max(), StreamID::min(), Some(1)).next(),
+            }
+        }
+    }
+
+    pub fn info_full<'a>(&'a self) -> StreamInfoFull<'a> {
+        StreamInfoFull {
+            info: self.info(),
+            groups: self.groups(),
+        }
+    }
+
+    /// Every consumer group ordered by name.
+    pub fn groups<'a>(&'a self) -> Vec<GroupInfo<'a>> {
+        let cgroups = unsafe { (*self.s).cgroups };
+        if cgroups.is_null() {
+            return Vec::new();
+        }
+
+        let now = unsafe { mstime() };
+        let mut groups = Vec::new();
+        unsafe {
+            let mut it = RawRaxIter::new(cgroups);
+            it.seek(b"^\0", &[]);
+            while let Some((name, data)) = it.next() {
+                let cg = data as *mut streamCG;
+                groups.push(GroupInfo {
+                    name: SdsString::new(name),
+                    last_id: (*cg).last_id,
+                    pending: raxSize((*cg).pel),
+                    consumers: consumers_of(cg, now),
+                });
+            }
+        }
+        groups
+    }
+}
+
+pub(super) unsafe fn consumers_of<'a>(cg: *mut streamCG, now: i64) -> Vec<ConsumerInfo<'a>> {
+    let mut consumers = Vec::new();
+    let mut it = RawRaxIter::new((*cg).consumers);
+    it.seek(b"^\0", &[]);
+    while let Some((_, data)) = it.next() {
+        let consumer = data as *mut streamConsumer;
+        let seen_time = (*consumer).seen_time;
+        consumers.push(ConsumerInfo {
+            name: SdsRef::from_raw((*consumer).name),
+            seen_time,
+            idle: std::cmp::max(now - seen_time, 0) as u64,
+            pending: raxSize((*consumer).pel),
+        });
+    }
+    consumers
+}
+
+#[cfg(test)]
+mod tests {
+    use stream::{IdSpec, Stream, StreamID};
+
+    #[test]
+    fn info() {
+        let mut s = Stream::new();
+        let info = s.info();
+        assert_eq!((info.length, info.radix_tree_keys, info.groups), (0, 0, 0));
+        assert!(info.first_entry.is_none() && info.last_entry.is_none());
+        assert!(s.info_full().groups.is_empty());
+
+        for i in 1..251 {
+            s.add(&[(b"n", format!("{}", i).as_bytes())], IdSpec::Explicit(StreamID::new(i, 0)))
+                .unwrap();
+        }
+        s.delete(&[StreamID::new(1, 0)]);
+
+        let info = s.info();
+        assert_eq!(info.length, 249);
+        assert_eq!(info.radix_tree_keys, 3);
+        assert!(info.radix_tree_nodes >= 3);
+        assert_eq!(info.last_id, StreamID::new(250, 0));
+        assert_eq!(info.first_entry.unwrap().get(b"n"), Some(&b"2"[..]));
+        assert_eq!(info.last_entry.unwrap().id, StreamID::new(250, 0));
+    }
+
+    #[test]
+    fn info_full() {
+        let mut s = Stream::new();
+        for i in 1..11 {
+            s.add(&[(b"n", b"x")], IdSpec::Explicit(StreamID::new(i, 0))).unwrap();
+        }
+        s.create_group(b"b-group", StreamID::new(0, 0)).unwrap();
+        s.create_group(b"a-group", StreamID::new(5, 0)).unwrap();
+        {
+            let mut g = s.group(b"b-group").unwrap();
+            g.read(b"zed", Some(3), false);
+            g.read(b"amy", Some(2), false);
+            g.ack(&[StreamID::new(1, 0)]);
+        }
+
+        let full = s.info_full();
+        assert_eq!(full.info.groups, 2);
+        let names: Vec<_> = full.groups.iter().map(|g| g.name.to_string()).collect();
+        assert_eq!(names, vec!["a-group", "b-group"]);
+
+        let a = &full.groups[0];
+        assert_eq!((a.last_id, a.pending), (StreamID::new(5, 0), 0));
+        assert!(a.consumers.is_empty());
+
+        let b = &full.groups[1];
+        assert_eq!((b.last_id, b.pending), (StreamID::new(5, 0), 4));
+        let consumers: Vec<_> = b.consumers.iter().map(|c| (c.name.as_bytes(), c.pending)).collect();
+        assert_eq!(consumers, vec![(&b"amy"[..], 2), (&b"zed"[..], 2)]);
+        assert!(b.consumers.iter().all(|c| c.seen_time > 0 && c.idle < 1000));
+
+        assert_eq!(s.group(b"b-group").unwrap().consumers().len(), 2);
+    }
+}
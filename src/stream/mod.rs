@@ -0,0 +1,1237 @@
+#![allow(dead_code)]
+
+use libc;
+use listpack::*;
+use rax::*;
+use sds::*;
+use sds_pool::{SdsPool, SdsPoolStats};
+use std;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::default::Default;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod aggregate;
+mod dump;
+mod group;
+mod info;
+mod observer;
+mod waiters;
+
+pub use self::aggregate::{AggFn, Bucket};
+pub use self::dump::{DumpError, DUMP_VERSION};
+pub use self::group::{AutoClaim, ConsumerGroup, DeadLetter, PendingEntry, PendingSummary};
+pub use self::info::{ConsumerInfo, GroupInfo, StreamInfo, StreamInfoFull};
+pub use self::observer::{ReplicateObserver, StreamEvent, StreamObserver};
+pub use self::waiters::{free_blocked_privdata, BlockedClient, Resumed, StreamWaiters, Wake, WaiterToken};
+
+pub struct Stream {
+    pub s: *mut stream,
+    /// A new listpack node is started once the tail one is bigger than this.
+    node_max_bytes: usize,
+    /// A new listpack node is started once the tail one holds more entries.
+    node_max_entries: u64,
+    /// Readers to signal on `add`, and the key they know this stream by.
+    waiters: Option<(Rc<RefCell<StreamWaiters>>, SdsString)>,
+    observers: Vec<Rc<dyn StreamObserver>>,
+    /// Field and consumer names, see `name`.
+    names: RefCell<SdsPool>,
+    /// The max-delivery limit of each consumer group, see
+    /// `ConsumerGroup::dead_letter`.
+    max_deliveries: RefCell<HashMap<SdsString, u64>>,
+}
+
+/// Default `Stream` node limits, the same as the stream-node-max-bytes and
+/// stream-node-max-entries server settings.
+pub const STREAM_NODE_MAX_BYTES: usize = 4096;
+pub const STREAM_NODE_MAX_ENTRIES: u64 = 100;
+
+/// Entry flags of the stream listpack nodes, see c/stream.c.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+/// Distinct field and consumer names a `Stream` interns, later ones get their
+/// own allocation.
+pub const MAX_POOLED_NAMES: usize = 1024;
+
+const STREAM_ID: StreamID = StreamID { ms: 0, seq: 0 };
+const STREAM_ID_REF: *const StreamID = &STREAM_ID as *const StreamID;
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::with_node_limits(STREAM_NODE_MAX_BYTES, STREAM_NODE_MAX_ENTRIES)
+    }
+
+    /// A stream whose listpack nodes are limited to `max_bytes` and
+    /// `max_entries`. Bigger nodes mean less memory overhead per entry but
+    /// slower inserts and lookups inside a node. Zero disables a limit.
+    pub fn with_node_limits(max_bytes: usize, max_entries: u64) -> Stream {
+        Stream {
+            s: unsafe { streamNew() },
+            node_max_bytes: max_bytes,
+            node_max_entries: max_entries,
+            waiters: None,
+            observers: Vec::new(),
+            names: RefCell::new(SdsPool::new()),
+            max_deliveries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn node_max_bytes(&self) -> usize {
+        self.node_max_bytes
+    }
+
+    #[inline]
+    pub fn node_max_entries(&self) -> u64 {
+        self.node_max_entries
+    }
+
+    fn lookup_consumer_group(&self, groupname: Sds) -> *mut streamCG {
+        unsafe { streamLookupCG(self.s, groupname) }
+    }
+
+    /// Number of entries in the stream.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.s).length }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The ID of the last entry added. Zero if nothing was added yet.
+    pub fn last_id(&self) -> StreamID {
+        unsafe { (*self.s).last_id }
+    }
+
+    /// Append an entry made of the specified field/value pairs, the same as
+    /// XADD. Returns the ID the entry was added with.
+    pub fn add(&mut self, fields: &[(&[u8], &[u8])], id: IdSpec) -> Result<StreamID, StreamError> {
+        if fields.is_empty() {
+            return Err(StreamError::NoFields);
+        }
+
+        let last_id = self.last_id();
+        let mut use_id = match id {
+            IdSpec::Auto => None,
+            IdSpec::Explicit(id) => {
+                if id <= last_id {
+                    return Err(StreamError::IdTooSmall { id, last_id });
+                }
+                Some(id)
+            }
+            IdSpec::PartialMs(ms) => {
+                if ms < last_id.ms {
+                    return Err(StreamError::IdTooSmall { id: StreamID::new(ms, 0), last_id });
+                } else if ms > last_id.ms {
+                    Some(StreamID::new(ms, 0))
+                } else if last_id.seq == u64::max_value() {
+                    return Err(StreamError::IdExhausted(last_id));
+                } else {
+                    Some(StreamID::new(ms, last_id.seq + 1))
+                }
+            }
+        };
+
+        // streamAppendItemSDSMapLimits() reads fields and values as SDS strings,
+        // alternating field, value. It does not keep them, the field names can
+        // be shared.
+        let mut args: Vec<(Rc<SdsString>, SdsString)> = Vec::with_capacity(fields.len());
+        let mut argv: Vec<Sds> = Vec::with_capacity(fields.len() * 2);
+        for &(field, value) in fields {
+            args.push((self.name(field), SdsString::from(value)));
+        }
+        for &(ref field, ref value) in &args {
+            argv.push(field.as_raw());
+            argv.push(value.as_raw());
+        }
+
+        let mut added_id = StreamID::default();
+        let r = unsafe {
+            streamAppendItemSDSMapLimits(
+                self.s,
+                argv.as_mut_ptr(),
+                fields.len() as i64,
+                &mut added_id,
+                match use_id {
+                    Some(ref mut id) => id as *mut StreamID,
+                    None => ptr::null_mut(),
+                },
+                self.node_max_bytes,
+                self.node_max_entries as i64,
+            )
+        };
+
+        if r == C_OK {
+            self.emit(StreamEvent::Add { id: added_id, fields });
+            if let Some((ref waiters, ref key)) = self.waiters {
+                let resumed = waiters.borrow_mut().signal(key, added_id);
+                resumed.wake();
+            }
+            Ok(added_id)
+        } else {
+            Err(StreamError::IdTooSmall { id: use_id.unwrap_or_default(), last_id })
+        }
+    }
+
+    /// The shared SDS of the field or consumer name `name`, interned up to
+    /// `MAX_POOLED_NAMES` distinct names.
+    pub(crate) fn name(&self, name: &[u8]) -> Rc<SdsString> {
+        let mut names = self.names.borrow_mut();
+        if names.len() < MAX_POOLED_NAMES || names.contains(name) {
+            names.intern(name)
+        } else {
+            Rc::new(SdsString::new(name))
+        }
+    }
+
+    /// Interning statistics of the field and consumer names.
+    pub fn name_stats(&self) -> SdsPoolStats {
+        self.names.borrow().stats()
+    }
+
+    /// Trim the stream to at most `maxlen` entries, removing the oldest. With
+    /// `approx` only whole nodes are removed so more than `maxlen` entries may
+    /// be left. Returns the number of entries removed.
+    pub fn trim_maxlen(&mut self, maxlen: u64, approx: bool) -> u64 {
+        let removed = unsafe {
+            streamTrimByLength(self.s, maxlen as libc::size_t, approx as libc::c_int) as u64
+        };
+        self.trimmed(removed)
+    }
+
+    /// Remove the entries with an ID smaller than `minid`. With `approx` only
+    /// whole nodes are removed. Returns the number of entries removed.
+    pub fn trim_minid(&mut self, mut minid: StreamID, approx: bool) -> u64 {
+        let removed = unsafe { streamTrimByMinID(self.s, &mut minid, approx as libc::c_int) } as u64;
+        self.trimmed(removed)
+    }
+
+    fn trimmed(&self, removed: u64) -> u64 {
+        if removed > 0 && !self.observers.is_empty() {
+            self.emit(StreamEvent::Trim { removed, first_id: self.first_id() });
+        }
+        removed
+    }
+
+    /// Delete the entries with the given IDs, the same as XDEL. Deleted entries
+    /// stay in their node as tombstones until `compact` runs. Returns the
+    /// number of entries that existed and were deleted.
+    pub fn delete(&mut self, ids: &[StreamID]) -> u64 {
+        let mut deleted = Vec::new();
+        for id in ids {
+            let mut id = *id;
+            if unsafe { streamDeleteItem(self.s, &mut id) } != 0 {
+                deleted.push(id);
+            }
+        }
+        if !deleted.is_empty() {
+            self.emit(StreamEvent::Delete { ids: &deleted });
+        }
+        deleted.len() as u64
+    }
+
+    /// Rewrite the nodes where deleted entries reach half the valid ones.
+    /// Returns the number of tombstones reclaimed.
+    pub fn compact(&mut self) -> u64 {
+        self.compact_ratio(STREAM_COMPACT_RATIO)
+    }
+
+    /// Rewrite the nodes where deleted entries reach `ratio` times the valid
+    /// ones. Rewritten nodes honor the node limits of the stream. Returns
+    /// the number of tombstones reclaimed.
+    pub fn compact_ratio(&mut self, ratio: f64) -> u64 {
+        unsafe {
+            streamCompact(self.s, ratio, self.node_max_bytes, self.node_max_entries as i64) as u64
+        }
+    }
+
+    /// Entries with IDs between `start` and `end` inclusive, oldest first,
+    /// stopping after `count` entries if given. This is XRANGE.
+    pub fn range<'a>(&'a self, start: StreamID, end: StreamID, count: Option<usize>) -> StreamRange<'a> {
+        StreamRange::new(self, start, end, count, false)
+    }
+
+    /// Entries with IDs between `end` and `start` inclusive, newest first.
+    /// Like XREVRANGE the upper bound comes first.
+    pub fn rev_range<'a>(&'a self, end: StreamID, start: StreamID, count: Option<usize>) -> StreamRange<'a> {
+        StreamRange::new(self, start, end, count, true)
+    }
+}
+
+/// A single stream entry. Field and value bytes borrow the listpack they live
+/// in. Only integer encoded elements, which have no string representation in
+/// the listpack, are materialized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamEntry<'a> {
+    pub id: StreamID,
+    pub fields: Vec<(Cow<'a, [u8]>, Cow<'a, [u8]>)>,
+}
+
+impl<'a> StreamEntry<'a> {
+    /// The value of the first field named `field`.
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|f| &*f.0 == field)
+            .map(|f| &*f.1)
+    }
+}
+
+/// Iterator over a range of stream entries, see `Stream::range`.
+pub struct StreamRange<'a> {
+    si: Box<streamIterator>,
+    count: Option<usize>,
+    _marker: PhantomData<&'a Stream>,
+}
+
+impl<'a> StreamRange<'a> {
+    fn new(
+        stream: &'a Stream,
+        mut start: StreamID,
+        mut end: StreamID,
+        count: Option<usize>,
+        rev: bool,
+    ) -> StreamRange<'a> {
+        let mut si = streamIterator::new_boxed();
+        unsafe {
+            streamIteratorStart(&mut *si, stream.s, &mut start, &mut end, rev as libc::c_int);
+        }
+        StreamRange {
+            si,
+            count,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turn a pointer handed out by lpGet() into bytes. Integer encoded
+    /// elements are rendered into the iterator buffers, which are reused for
+    /// the next element, so those are copied.
+    unsafe fn element(&self, ptr: *mut u8, len: i64) -> Cow<'a, [u8]> {
+        let bytes: &'a [u8] = std::slice::from_raw_parts(ptr, len as usize);
+        if ptr == self.si.field_buf.as_ptr() as *mut u8
+            || ptr == self.si.value_buf.as_ptr() as *mut u8 {
+            Cow::Owned(bytes.to_vec())
+        } else {
+            Cow::Borrowed(bytes)
+        }
+    }
+}
+
+impl<'a> Iterator for StreamRange<'a> {
+    type Item = StreamEntry<'a>;
+
+    fn next(&mut self) -> Option<StreamEntry<'a>> {
+        if self.count == Some(0) {
+            return None;
+        }
+
+        unsafe {
+            let mut id = StreamID::default();
+            let mut numfields: i64 = 0;
+            if streamIteratorGetID(&mut *self.si, &mut id, &mut numfields) == 0 {
+                self.count = Some(0);
+                return None;
+            }
+
+            let mut fields = Vec::with_capacity(numfields as usize);
+            for _ in 0..numfields {
+                let mut field: *mut u8 = ptr::null_mut();
+                let mut value: *mut u8 = ptr::null_mut();
+                let mut field_len: i64 = 0;
+                let mut value_len: i64 = 0;
+                streamIteratorGetField(
+                    &mut *self.si,
+                    &mut field,
+                    &mut value,
+                    &mut field_len,
+                    &mut value_len,
+                );
+                fields.push((self.element(field, field_len), self.element(value, value_len)));
+            }
+
+            if let Some(ref mut count) = self.count {
+                *count -= 1;
+            }
+            Some(StreamEntry { id, fields })
+        }
+    }
+}
+
+impl<'a> Drop for StreamRange<'a> {
+    fn drop(&mut self) {
+        unsafe { streamIteratorStop(&mut *self.si) }
+    }
+}
+
+const C_OK: libc::c_int = 0;
+
+/// Deleted to valid entries ratio at which `Stream::compact` rewrites a node.
+pub const STREAM_COMPACT_RATIO: f64 = 0.5;
+
+/// How the ID of a new entry is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdSpec {
+    /// Generated from the current time, like XADD with `*`.
+    Auto,
+    /// Use this exact ID. It must be greater than the last ID.
+    Explicit(StreamID),
+    /// Use this millisecond time with the next free sequence number.
+    PartialMs(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    /// An entry must have at least one field/value pair.
+    NoFields,
+    /// The requested ID is equal or smaller than the stream top item.
+    IdTooSmall { id: StreamID, last_id: StreamID },
+    /// No sequence numbers are left for the requested millisecond.
+    IdExhausted(StreamID),
+    /// A consumer group with the same name already exists.
+    GroupExists,
+    /// Not a valid stream ID string.
+    InvalidId,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::NoFields => write!(f, "wrong number of arguments for stream entry"),
+            StreamError::IdTooSmall { id, last_id } => write!(
+                f,
+                "the ID {} is equal or smaller than the target stream top item {}",
+                id, last_id
+            ),
+            StreamError::IdExhausted(id) => write!(f, "no sequence numbers left after {}", id),
+            StreamError::GroupExists => write!(f, "consumer group name already exists"),
+            StreamError::InvalidId => write!(f, "invalid stream ID specified as stream command argument"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::NoFields => "no fields",
+            StreamError::IdTooSmall { .. } => "ID too small",
+            StreamError::IdExhausted(_) => "ID exhausted",
+            StreamError::GroupExists => "group exists",
+            StreamError::InvalidId => "invalid ID",
+        }
+    }
+}
+
+//
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe { freeStream(self.s) }
+    }
+}
+
+/// Stream item ID: a 128 bit number composed of a milliseconds time and a
+/// sequence counter. Ordered the same as streamCompareID().
+#[derive(Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+pub struct StreamID {
+    ms: libc::uint64_t,
+    seq: libc::uint64_t,
+}
+
+impl StreamID {
+    pub fn new(ms: u64, seq: u64) -> StreamID {
+        StreamID { ms, seq }
+    }
+
+    /// The first ID of the millisecond `ms`.
+    pub fn from_millis(ms: u64) -> StreamID {
+        StreamID { ms, seq: 0 }
+    }
+
+    /// The first ID of the millisecond `t` falls in. Times before the epoch
+    /// map to 0-0.
+    pub fn from_system_time(t: SystemTime) -> StreamID {
+        let ms = t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            .unwrap_or(0);
+        StreamID::from_millis(ms)
+    }
+
+    /// The smallest possible ID, "-" in range queries.
+    #[inline]
+    pub fn min() -> StreamID {
+        StreamID { ms: 0, seq: 0 }
+    }
+
+    /// The greatest possible ID, "+" in range queries.
+    #[inline]
+    pub fn max() -> StreamID {
+        StreamID { ms: std::u64::MAX, seq: std::u64::MAX }
+    }
+
+    #[inline]
+    pub fn ms(&self) -> u64 {
+        self.ms
+    }
+
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The millisecond part as a point in time.
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.ms)
+    }
+
+    /// The smallest ID greater than this one, None for the max ID.
+    pub fn next(&self) -> Option<StreamID> {
+        if self.seq < std::u64::MAX {
+            Some(StreamID { ms: self.ms, seq: self.seq + 1 })
+        } else if self.ms < std::u64::MAX {
+            Some(StreamID { ms: self.ms + 1, seq: 0 })
+        } else {
+            None
+        }
+    }
+
+    /// The greatest ID smaller than this one, None for 0-0.
+    pub fn prev(&self) -> Option<StreamID> {
+        if self.seq > 0 {
+            Some(StreamID { ms: self.ms, seq: self.seq - 1 })
+        } else if self.ms > 0 {
+            Some(StreamID { ms: self.ms - 1, seq: std::u64::MAX })
+        } else {
+            None
+        }
+    }
+
+    /// The ID streamNextID() generates for a new entry when this is the last
+    /// ID of the stream: the current time, or this ID with the sequence
+    /// incremented if the clock did not move forward.
+    pub fn next_auto(&self) -> StreamID {
+        let mut last = *self;
+        let mut id = StreamID::default();
+        unsafe { streamNextID(&mut last, &mut id) };
+        id
+    }
+
+    /// Parse the end of a range: like `from_str` but a bare "ms" means the
+    /// last ID of that millisecond, as XRANGE does.
+    pub fn parse_end(s: &str) -> Result<StreamID, StreamError> {
+        StreamID::parse(s, std::u64::MAX)
+    }
+
+    fn parse(s: &str, missing_seq: u64) -> Result<StreamID, StreamError> {
+        match s {
+            "-" => return Ok(StreamID::min()),
+            "+" => return Ok(StreamID::max()),
+            _ => {}
+        }
+        let mut parts = s.splitn(2, '-');
+        let ms = parse_u64(parts.next().unwrap_or(""))?;
+        let seq = match parts.next() {
+            Some(seq) => parse_u64(seq)?,
+            None => missing_seq,
+        };
+        Ok(StreamID { ms, seq })
+    }
+}
+
+/// Strict unsigned parsing: digits only, no sign or whitespace.
+fn parse_u64(s: &str) -> Result<u64, StreamError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(StreamError::InvalidId);
+    }
+    s.parse().map_err(|_| StreamError::InvalidId)
+}
+
+/// Parses "ms-seq", "ms" (meaning ms-0), "-" and "+".
+impl FromStr for StreamID {
+    type Err = StreamError;
+
+    fn from_str(s: &str) -> Result<StreamID, StreamError> {
+        StreamID::parse(s, 0)
+    }
+}
+
+impl fmt::Display for StreamID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Parses the XADD ID argument: "*", "ms-*" or an explicit ID.
+impl FromStr for IdSpec {
+    type Err = StreamError;
+
+    fn from_str(s: &str) -> Result<IdSpec, StreamError> {
+        if s == "*" {
+            Ok(IdSpec::Auto)
+        } else if s.ends_with("-*") {
+            Ok(IdSpec::PartialMs(parse_u64(&s[..s.len() - 2])?))
+        } else {
+            match s {
+                "-" | "+" => Err(StreamError::InvalidId),
+                _ => Ok(IdSpec::Explicit(s.parse()?)),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for StreamID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl Default for StreamID {
+    fn default() -> Self {
+        StreamID { ms: 0, seq: 0 }
+    }
+}
+
+impl Clone for StreamID {
+    fn clone(&self) -> Self {
+        StreamID { ms: self.ms, seq: self.seq }
+    }
+}
+
+
+impl RaxKey for StreamID {
+    type Output = StreamID;
+
+    fn encode(self) -> Self::Output {
+        StreamID {
+            ms: self.ms.to_be(),
+            seq: self.seq.to_be(),
+        }
+    }
+
+    fn to_buf(&self) -> (*const u8, usize) {
+        (self as *const _ as *const u8, size_of::<StreamID>())
+    }
+
+    fn from_buf(ptr: *const u8, len: usize) -> StreamID {
+        if len != size_of::<StreamID>() {
+            return StreamID::default();
+        }
+
+        unsafe {
+            StreamID {
+                ms: u64::from_be(*(ptr as *mut [u8; 8] as *mut u64)),
+                seq: u64::from_be(*(ptr.offset(8) as *mut [u8; 8] as *mut u64)),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct EntryPack;
+
+//use std::fs::File;
+//use std::io::prelude::*;
+
+impl EntryPack {
+
+
+    fn read_from_from() {
+
+//        std::fs::File::open()
+//
+//        let mut f = File::open(filename).expect("file not found");
+//
+//        let mut contents = String::new();
+//        f.read_to_string(&mut contents)
+//            .expect("something went wrong reading the file");
+    }
+
+    fn deserialize() -> *mut listpack {
+        std::ptr::null_mut()
+    }
+
+    fn append(lp: *mut listpack, fields: &[Sds]) -> *mut listpack {
+        /* Create a new listpack and radix tree node if needed. Note that when
+     * a new listpack is created, we populate it with a "master entry". This
+     * is just a set of fields that is taken as refernce in order to compress
+     * the stream entries that we'll add inside the listpack.
+     *
+     * Note that while we use the first added entry fields to create
+     * the master entry, the first added entry is NOT represented in the master
+     * entry, which is a stand alone object. But of course, the first entry
+     * will compress well because it's used as reference.
+     *
+     * The master entry is composed like in the following example:
+     *
+     * +-------+---------+------------+---------+--/--+---------+---------+-+
+     * | count | deleted | num-fields | field_1 | field_2 | ... | field_N |0|
+     * +-------+---------+------------+---------+--/--+---------+---------+-+
+     *
+     * count and deleted just represent respectively the total number of
+     * entries inside the listpack that are valid, and marked as deleted
+     * (delted flag in the entry flags set). So the total number of items
+     * actually inside the listpack (both deleted and not) is count+deleted.
+     *
+     * The real entries will be encoded with an ID that is just the
+     * millisecond and sequence difference compared to the key stored at
+     * the radix tree node containing the listpack (delta encoding), and
+     * if the fields of the entry are the same as the master enty fields, the
+     * entry flags will specify this fact and the entry fields and number
+     * of fields will be omitted (see later in the code of this function).
+     *
+     * The "0" entry at the end is the same as the 'lp-count' entry in the
+     * regular stream entries (see below), and marks the fact that there are
+     * no more entries, when we scan the stream from right to left. */
+        unsafe {
+            if lp.is_null() {
+                let numfields = fields.len() / 2;
+
+
+                let mut lp = lpNew();
+                lp = lpAppendInteger(lp, 1);
+                lp = lpAppendInteger(lp, 0);
+                lp = lpAppendInteger(lp, numfields as i64);
+                for i in 0..numfields {
+                    let field = fields[i * 2];
+                    lp = lpAppend(lp, field as *mut u8, get_len(field) as u32);
+                }
+                lp = lpAppendInteger(lp, 0); /* Master entry zero terminator. */
+            }
+        }
+
+        lp
+    }
+
+//    fn append(lp: *mut listpack, map: &[Sds]) -> (*mut listpack, bool) {
+//        (lp, true)
+//    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct stream {
+    rax: *mut ::rax::rax,
+    length: libc::uint64_t,
+    last_id: StreamID,
+    cgroups: *mut ::rax::rax,
+}
+
+const LP_INTBUF_SIZE: usize = 21;
+
+/// The embedded rax iterator keeps pointers into itself once started, so a
+/// streamIterator must not move between streamIteratorStart() and
+/// streamIteratorStop(). Allocate it with `new_boxed`.
+#[repr(C)]
+pub struct streamIterator {
+    stream: *mut stream,
+    master_id: StreamID,
+    master_fields_count: libc::uint64_t,
+    master_fields_start: *mut u8,
+    master_fields_ptr: *mut u8,
+    entry_flags: libc::c_int,
+    rev: libc::c_int,
+    start_key: [libc::uint64_t; 2],
+    end_key: [libc::uint64_t; 2],
+    ri: raxIterator,
+    lp: *mut u8,
+    lp_ele: *mut u8,
+    lp_flags: *mut u8,
+    field_buf: [u8; LP_INTBUF_SIZE],
+    value_buf: [u8; LP_INTBUF_SIZE],
+}
+
+impl streamIterator {
+    pub fn new_boxed() -> Box<streamIterator> {
+        unsafe { Box::new(std::mem::zeroed()) }
+    }
+}
+
+/// A rax iterator over a tree owned by C, such as a consumer group PEL.
+/// Boxed since a started raxIterator points into itself.
+struct RawRaxIter {
+    ri: Box<raxIterator>,
+}
+
+impl RawRaxIter {
+    unsafe fn new(rt: *mut ::rax::rax) -> RawRaxIter {
+        let ri: Box<raxIterator> = Box::new(std::mem::zeroed());
+        raxStart(&*ri, rt);
+        RawRaxIter { ri }
+    }
+
+    /// `op` is a nul terminated rax seek operator such as `b">=\0"`.
+    unsafe fn seek(&mut self, op: &[u8], key: &[u8]) -> bool {
+        raxSeek(&*self.ri, op.as_ptr(), key.as_ptr(), key.len()) == 1
+    }
+
+    /// True once a seek found no element or the iteration reached the end.
+    fn eof(&self) -> bool {
+        self.ri.flags & RAX_ITER_EOF != 0
+    }
+
+    unsafe fn next(&mut self) -> Option<(&[u8], *mut libc::c_void)> {
+        if raxNext(&*self.ri) == 1 {
+            Some(self.current())
+        } else {
+            None
+        }
+    }
+
+    unsafe fn prev(&mut self) -> Option<(&[u8], *mut libc::c_void)> {
+        if raxPrev(&*self.ri) == 1 {
+            Some(self.current())
+        } else {
+            None
+        }
+    }
+
+    unsafe fn current(&self) -> (&[u8], *mut libc::c_void) {
+        (std::slice::from_raw_parts(self.ri.key, self.ri.key_len), self.ri.data)
+    }
+}
+
+impl Drop for RawRaxIter {
+    fn drop(&mut self) {
+        unsafe { raxStop(&*self.ri) }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct streamCG {
+    last_id: StreamID,
+    pel: *mut rax,
+    consumers: *mut rax,
+}
+
+//#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct streamConsumer {
+    seen_time: libc::c_longlong,
+    name: Sds,
+    pel: *mut rax,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct streamNACK {
+    delivery_time: libc::c_longlong,
+    delivery_count: libc::uint64_t,
+    consumer: *mut streamConsumer,
+}
+
+#[allow(improper_ctypes)]
+#[allow(non_snake_case)]
+#[link(name = "redismodule", kind = "static")]
+extern "C" {
+//    fn createObject()
+
+    fn streamNew() -> *mut stream;
+
+    fn freeStream(s: *mut stream);
+
+    fn streamAppendItemSDSMap(
+        s: *mut stream,
+        argv: *mut Sds,
+        numfields: libc::int64_t,
+        added_id: *mut StreamID,
+        use_id: *mut StreamID,
+    ) -> libc::c_int;
+
+    fn streamAppendItemSDSMapLimits(
+        s: *mut stream,
+        argv: *mut Sds,
+        numfields: libc::int64_t,
+        added_id: *mut StreamID,
+        use_id: *mut StreamID,
+        node_max_bytes: libc::size_t,
+        node_max_entries: libc::int64_t,
+    ) -> libc::c_int;
+
+    fn streamTrimByLength(
+        s: *mut stream,
+        maxlen: libc::size_t,
+        approx: libc::c_int,
+    ) -> libc::int64_t;
+
+    fn streamNodeLastID(
+        lp: *mut u8,
+        master_id: *mut StreamID,
+        last_id: *mut StreamID,
+    );
+
+    fn streamTrimByMinID(
+        s: *mut stream,
+        minid: *mut StreamID,
+        approx: libc::c_int,
+    ) -> libc::int64_t;
+
+    fn streamNextID(
+        last_id: *mut StreamID,
+        new_id: *mut StreamID,
+    );
+
+    fn streamGroupDeliver(
+        cg: *mut streamCG,
+        consumer: *mut streamConsumer,
+        id: *mut StreamID,
+        noack: libc::c_int,
+    );
+
+    fn streamGroupAck(
+        cg: *mut streamCG,
+        id: *mut StreamID,
+    ) -> libc::c_int;
+
+    fn streamGroupClaim(
+        cg: *mut streamCG,
+        consumer: *mut streamConsumer,
+        id: *mut StreamID,
+    );
+
+    pub(crate) fn mstime() -> libc::c_longlong;
+
+    fn streamCompact(
+        s: *mut stream,
+        ratio: libc::c_double,
+        node_max_bytes: libc::size_t,
+        node_max_entries: libc::int64_t,
+    ) -> libc::int64_t;
+
+    fn streamIteratorStart(
+        si: *mut streamIterator,
+        s: *mut stream,
+        start: *mut StreamID,
+        end: *mut StreamID,
+        rev: libc::c_int,
+    );
+
+    fn streamIteratorGetID(
+        si: *mut streamIterator,
+        id: *mut StreamID,
+        numfields: *mut libc::int64_t,
+    ) -> libc::c_int;
+
+    fn streamIteratorGetField(
+        si: *mut streamIterator,
+        fieldptr: *mut *mut u8,
+        valueptr: *mut *mut u8,
+        fieldlen: *mut libc::int64_t,
+        valuelen: *mut libc::int64_t,
+    );
+
+    fn streamIteratorRemoveEntry(
+        si: *mut streamIterator,
+        id: *mut StreamID,
+    );
+
+    fn streamIteratorStop(
+        si: *mut streamIterator,
+    );
+
+    fn streamDeleteItem(
+        s: *mut stream,
+        id: *mut StreamID,
+    ) -> libc::c_int;
+
+    fn string2ull(
+        s: *const libc::c_char,
+        value: *mut libc::uint64_t,
+    ) -> libc::c_int;
+
+    fn streamCreateNACK(
+        consumer: *mut streamConsumer
+    ) -> *mut streamNACK;
+
+    fn streamFreeNACK(
+        na: *mut streamNACK
+    );
+
+    fn streamFreeConsumer(
+        sc: *mut streamConsumer
+    );
+
+    fn streamCreateCG(
+        s: *mut stream,
+        name: *mut libc::c_char,
+        namelen: libc::size_t, id: *mut StreamID,
+    ) -> *mut streamCG;
+
+    fn streamFreeCG(cg: *mut streamCG);
+
+    fn streamLookupCG(
+        s: *mut stream,
+        groupname: Sds,
+    ) -> *mut streamCG;
+
+    fn streamLookupConsumer(
+        cg: *mut streamCG,
+        name: Sds,
+        create: libc::c_int,
+    ) -> *mut streamConsumer;
+
+    fn streamDelConsumer(
+        cg: *mut streamCG,
+        name: Sds,
+    ) -> libc::uint64_t;
+}
+
+#[cfg(test)]
+mod tests {
+    use rax::raxSize;
+    use std::collections::HashSet;
+    use std::time::{Duration, UNIX_EPOCH};
+    use stream::{IdSpec, Stream, StreamError, StreamID, STREAM_NODE_MAX_ENTRIES};
+
+    #[test]
+    fn add_auto() {
+        let mut s = Stream::new();
+        let fields: &[(&[u8], &[u8])] = &[(b"id", b"1"), (b"auth-key", b"\x00secret")];
+
+        let mut last = StreamID::default();
+        for _ in 0..1000 {
+            let id = s.add(fields, IdSpec::Auto).unwrap();
+            assert!(id > last);
+            last = id;
+        }
+        assert_eq!(s.len(), 1000);
+        assert_eq!(s.last_id(), last);
+
+        // One allocation per field name.
+        let names = s.name_stats();
+        assert_eq!((names.entries, names.misses, names.hits), (2, 2, 1998));
+        let entry = s.range(last, last, None).next().unwrap();
+        assert_eq!(entry.get(b"auth-key"), Some(&b"\x00secret"[..]));
+    }
+
+    #[test]
+    fn add_explicit_and_partial() {
+        let mut s = Stream::new();
+        let fields: &[(&[u8], &[u8])] = &[(b"data", b"{}")];
+
+        assert_eq!(
+            s.add(fields, IdSpec::Explicit(StreamID::new(0, 0))),
+            Err(StreamError::IdTooSmall { id: StreamID::new(0, 0), last_id: StreamID::new(0, 0) })
+        );
+        assert_eq!(s.add(fields, IdSpec::Explicit(StreamID::new(5, 1))), Ok(StreamID::new(5, 1)));
+        assert_eq!(s.add(fields, IdSpec::PartialMs(5)), Ok(StreamID::new(5, 2)));
+        assert_eq!(s.add(fields, IdSpec::PartialMs(7)), Ok(StreamID::new(7, 0)));
+        assert!(s.add(fields, IdSpec::PartialMs(6)).is_err());
+        assert!(s.add(fields, IdSpec::Explicit(StreamID::new(7, 0))).is_err());
+        assert_eq!(s.add(&[], IdSpec::Auto), Err(StreamError::NoFields));
+        assert_eq!(s.len(), 3);
+    }
+
+    #[test]
+    fn range_and_rev_range() {
+        let mut s = Stream::new();
+        for i in 1..301u64 {
+            let seq = format!("{}", i);
+            let fields: Vec<(&[u8], &[u8])> = if i % 3 == 0 {
+                vec![(b"other", seq.as_bytes())]
+            } else {
+                vec![(b"id", seq.as_bytes()), (b"data", b"payload")]
+            };
+            s.add(&fields, IdSpec::Explicit(StreamID::new(i, 0))).unwrap();
+        }
+
+        let all: Vec<_> = s.range(StreamID::new(0, 0), StreamID::max(), None).collect();
+        assert_eq!(all.len(), 300);
+        assert_eq!(all[0].id, StreamID::new(1, 0));
+        assert_eq!(all[0].get(b"id"), Some(&b"1"[..]));
+        assert_eq!(all[0].get(b"data"), Some(&b"payload"[..]));
+        assert_eq!(all[2].get(b"other"), Some(&b"3"[..]));
+
+        let some: Vec<_> = s.range(StreamID::new(10, 0), StreamID::new(20, 0), Some(5))
+            .map(|e| e.id.ms())
+            .collect();
+        assert_eq!(some, vec![10, 11, 12, 13, 14]);
+
+        let rev: Vec<_> = s.rev_range(StreamID::new(250, 0), StreamID::new(245, 0), None)
+            .map(|e| e.id.ms())
+            .collect();
+        assert_eq!(rev, vec![250, 249, 248, 247, 246, 245]);
+
+        assert_eq!(s.range(StreamID::new(301, 0), StreamID::new(400, 0), None).count(), 0);
+    }
+
+    fn filled(n: u64) -> Stream {
+        let mut s = Stream::new();
+        for i in 1..n + 1 {
+            s.add(&[(b"n", format!("{}", i).as_bytes())], IdSpec::Explicit(StreamID::new(i, 0)))
+                .unwrap();
+        }
+        s
+    }
+
+    fn first_ms(s: &Stream) -> u64 {
+        s.range(StreamID::new(0, 0), StreamID::max(), Some(1))
+            .next()
+            .map(|e| e.id.ms())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn trim_maxlen() {
+        // Nodes hold 101 entries.
+        let mut s = filled(500);
+        assert_eq!(s.trim_maxlen(450, true), 0);
+        assert_eq!(s.trim_maxlen(350, true), 101);
+        assert_eq!(s.len(), 399);
+
+        assert_eq!(s.trim_maxlen(350, false), 49);
+        assert_eq!(s.len(), 350);
+        assert_eq!(first_ms(&s), 151);
+        assert_eq!(s.range(StreamID::new(0, 0), StreamID::new(1000, 0), None).count(), 350);
+        assert_eq!(s.trim_maxlen(350, false), 0);
+    }
+
+    #[test]
+    fn trim_minid() {
+        let mut s = filled(500);
+        assert_eq!(s.trim_minid(StreamID::new(150, 0), true), 101);
+        assert_eq!(first_ms(&s), 102);
+
+        assert_eq!(s.trim_minid(StreamID::new(150, 0), false), 48);
+        assert_eq!(s.len(), 351);
+        assert_eq!(first_ms(&s), 150);
+
+        // Counters stay consistent so whole-node removal still adds up.
+        assert_eq!(s.trim_minid(StreamID::new(203, 0), false), 53);
+        assert_eq!(s.len(), 298);
+        assert_eq!(s.range(StreamID::new(0, 0), StreamID::new(1000, 0), None).count(), 298);
+        assert_eq!(s.trim_minid(StreamID::new(1000, 0), false), 298);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn delete_and_compact() {
+        let mut s = Stream::new();
+        for i in 1..304u64 {
+            let n = format!("{}", i);
+            let fields: Vec<(&[u8], &[u8])> = if i % 2 == 0 {
+                vec![(b"n", n.as_bytes()), (b"data", b"even")]
+            } else {
+                vec![(b"n", n.as_bytes())]
+            };
+            s.add(&fields, IdSpec::Explicit(StreamID::new(i, 0))).unwrap();
+        }
+        let nodes = |s: &Stream| unsafe { raxSize((*s.s).rax) };
+        assert_eq!(nodes(&s), 3);
+
+        // Half of the first node plus its first entry, one from the second.
+        let mut ids: Vec<_> = (1..52).map(|i| StreamID::new(i, 0)).collect();
+        ids.push(StreamID::new(150, 0));
+        ids.push(StreamID::new(9999, 0));
+        assert_eq!(s.delete(&ids), 52);
+        assert_eq!(s.delete(&[StreamID::new(1, 0)]), 0);
+        assert_eq!(s.len(), 251);
+
+        // The whole third node.
+        let third: Vec<_> = (203..304).map(|i| StreamID::new(i, 0)).collect();
+        assert_eq!(s.delete(&third), 101);
+        assert_eq!(nodes(&s), 2);
+
+        let before: Vec<_> = s.range(StreamID::new(0, 0), StreamID::new(1000, 0), None)
+            .map(|e| (e.id, e.fields.iter().map(|f| (f.0.to_vec(), f.1.to_vec())).collect::<Vec<_>>()))
+            .collect();
+
+        assert_eq!(s.compact(), 51);
+        assert_eq!(s.compact(), 0);
+        assert_eq!(s.compact_ratio(0.0), 1);
+        assert_eq!(nodes(&s), 2);
+        assert_eq!(s.len(), 150);
+
+        let after: Vec<_> = s.range(StreamID::new(0, 0), StreamID::new(1000, 0), None)
+            .map(|e| (e.id, e.fields.iter().map(|f| (f.0.to_vec(), f.1.to_vec())).collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(before, after);
+        assert_eq!(after[0].0, StreamID::new(52, 0));
+        assert_eq!(s.rev_range(StreamID::new(1000, 0), StreamID::new(0, 0), Some(1)).next().unwrap().id,
+                   StreamID::new(202, 0));
+
+        // Appending after compaction keeps working.
+        s.add(&[(b"n", b"x")], IdSpec::Auto).unwrap();
+        assert_eq!(s.len(), 151);
+    }
+
+    #[test]
+    fn node_limits() {
+        let mut small = Stream::with_node_limits(0, 9);
+        let mut large = Stream::with_node_limits(0, 0);
+        let mut bytes = Stream::with_node_limits(256, 0);
+        for i in 1..101 {
+            let id = IdSpec::Explicit(StreamID::new(i, 0));
+            small.add(&[(b"n", b"x")], id).unwrap();
+            large.add(&[(b"n", b"x")], id).unwrap();
+            bytes.add(&[(b"payload", &[b'x'; 64][..])], id).unwrap();
+        }
+        // A node is only full once it holds more than the limit.
+        assert_eq!(small.info().radix_tree_keys, 10);
+        assert_eq!(large.info().radix_tree_keys, 1);
+        assert!(bytes.info().radix_tree_keys >= 25);
+        assert_eq!(Stream::new().node_max_entries(), STREAM_NODE_MAX_ENTRIES);
+
+        small.delete(&(0..50).map(|i| StreamID::new(i * 2 + 1, 0)).collect::<Vec<_>>());
+        assert_eq!(small.compact_ratio(0.0), 50);
+        assert_eq!(small.info().radix_tree_keys, 10);
+        let ids: Vec<_> = small.range(StreamID::min(), StreamID::max(), None).map(|e| e.id.ms).collect();
+        assert_eq!(ids, (1..51).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stream_id_parse_and_format() {
+        assert_eq!("1526919030474-55".parse(), Ok(StreamID::new(1526919030474, 55)));
+        assert_eq!("1526919030474".parse(), Ok(StreamID::new(1526919030474, 0)));
+        assert_eq!(StreamID::parse_end("15"), Ok(StreamID::new(15, u64::max_value())));
+        assert_eq!("-".parse(), Ok(StreamID::min()));
+        assert_eq!("+".parse(), Ok(StreamID::max()));
+        for bad in &["", "a-1", "1-", "-1", "1-2-3", "+1", " 1", "18446744073709551616"] {
+            assert_eq!(bad.parse::<StreamID>(), Err(StreamError::InvalidId), "{}", bad);
+        }
+
+        let id = StreamID::new(1526919030474, 55);
+        assert_eq!(id.to_string(), "1526919030474-55");
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(format!("{:?}", id), "1526919030474-55");
+
+        assert_eq!("*".parse(), Ok(IdSpec::Auto));
+        assert_eq!("5-*".parse(), Ok(IdSpec::PartialMs(5)));
+        assert_eq!("5-1".parse(), Ok(IdSpec::Explicit(StreamID::new(5, 1))));
+        assert_eq!("+".parse::<IdSpec>(), Err(StreamError::InvalidId));
+        assert_eq!("x-*".parse::<IdSpec>(), Err(StreamError::InvalidId));
+    }
+
+    #[test]
+    fn stream_id_arithmetic() {
+        let max = u64::max_value();
+        assert!(StreamID::new(1, max) < StreamID::new(2, 0));
+        assert_eq!(StreamID::new(1, max).next(), Some(StreamID::new(2, 0)));
+        assert_eq!(StreamID::new(1, 5).next(), Some(StreamID::new(1, 6)));
+        assert_eq!(StreamID::max().next(), None);
+        assert_eq!(StreamID::new(2, 0).prev(), Some(StreamID::new(1, max)));
+        assert_eq!(StreamID::min().prev(), None);
+
+        let t = UNIX_EPOCH + Duration::from_millis(1526919030474);
+        assert_eq!(StreamID::from_system_time(t), StreamID::from_millis(1526919030474));
+        assert_eq!(StreamID::new(1526919030474, 9).to_system_time(), t);
+
+        // Same generation rule as XADD *.
+        let future = StreamID::new(u64::max_value() - 1, 7);
+        assert_eq!(future.next_auto(), StreamID::new(u64::max_value() - 1, 8));
+        assert!(StreamID::min().next_auto() > StreamID::from_millis(1526919030474));
+
+        let mut set = HashSet::new();
+        set.insert(StreamID::new(1, 1));
+        assert!(set.contains(&StreamID::new(1, 1)));
+    }
+}
@@ -0,0 +1,265 @@
+use mod_api::{self, RedisModuleCtx};
+use sds::*;
+use std::cell::Cell;
+use std::ptr;
+use std::rc::Rc;
+
+use super::*;
+
+/// A mutation of a `Stream`, in the order it was applied. Events describe
+/// the effect, not the request: a trim carries what is left, a delete only
+/// the IDs that existed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamEvent<'a> {
+    Add {
+        id: StreamID,
+        fields: &'a [(&'a [u8], &'a [u8])],
+    },
+    Delete {
+        ids: &'a [StreamID],
+    },
+    /// Entries removed from the head of the stream by a MAXLEN or MINID trim.
+    Trim {
+        removed: u64,
+        /// The oldest entry left, none if the stream is now empty.
+        first_id: Option<StreamID>,
+    },
+    GroupCreate {
+        group: &'a [u8],
+        last_id: StreamID,
+    },
+    /// The group last delivered ID moved, after a read.
+    GroupSetId {
+        group: &'a [u8],
+        last_id: StreamID,
+    },
+    Ack {
+        group: &'a [u8],
+        ids: &'a [StreamID],
+    },
+    /// The entry is now pending for `consumer`, delivered by a read or
+    /// transferred by a claim.
+    Claim {
+        group: &'a [u8],
+        consumer: &'a [u8],
+        id: StreamID,
+        delivery_time: i64,
+        delivery_count: u64,
+    },
+    ConsumerDelete {
+        group: &'a [u8],
+        consumer: &'a [u8],
+    },
+}
+
+/// Receives every mutation of the streams it is attached to with
+/// `Stream::observe`. Called after the mutation is applied.
+pub trait StreamObserver {
+    fn on_event(&self, event: &StreamEvent);
+}
+
+impl Stream {
+    /// Attach `observer`, which sees the mutations made from now on.
+    pub fn observe(&mut self, observer: Rc<dyn StreamObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Detach an observer attached with `observe`. Returns false if it was
+    /// not attached.
+    pub fn unobserve(&mut self, observer: &Rc<dyn StreamObserver>) -> bool {
+        let before = self.observers.len();
+        self.observers.retain(|o| !Rc::ptr_eq(o, observer));
+        self.observers.len() != before
+    }
+
+    pub(super) fn emit(&self, event: StreamEvent) {
+        for observer in &self.observers {
+            observer.on_event(&event);
+        }
+    }
+
+    /// The ID of the oldest entry, if any.
+    pub(super) fn first_id(&self) -> Option<StreamID> {
+        self.range(StreamID::min(), StreamID::max(), Some(1)).next().map(|e| e.id)
+    }
+}
+
+/// Replicates the effects of every event to replicas and the AOF with
+/// `mod_api::replicate_argv`, as the stream commands of Redis do. Entries
+/// are added with their final ID, trims become an exact MINID trim and
+/// group reads become XCLAIM with the delivery time and count followed by
+/// XGROUP SETID, so the result does not depend on the clock or on the node
+/// layout of the replica.
+///
+/// Replication happens in the context of the command running, which must be
+/// set with `set_context` before mutating the stream.
+pub struct ReplicateObserver {
+    key: SdsString,
+    ctx: Cell<*mut RedisModuleCtx>,
+}
+
+impl ReplicateObserver {
+    pub fn new(key: &[u8]) -> ReplicateObserver {
+        ReplicateObserver {
+            key: SdsString::new(key),
+            ctx: Cell::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn set_context(&self, ctx: *mut RedisModuleCtx) {
+        self.ctx.set(ctx);
+    }
+
+    /// The commands replicating `event`, each a nul terminated command name
+    /// and its arguments.
+    pub fn commands(&self, event: &StreamEvent) -> Vec<(&'static [u8], Vec<Vec<u8>>)> {
+        let key = self.key.to_vec();
+        let id = |id: StreamID| id.to_string().into_bytes();
+        match *event {
+            StreamEvent::Add { id: added, fields } => {
+                let mut args = vec![key, id(added)];
+                for &(field, value) in fields {
+                    args.push(field.to_vec());
+                    args.push(value.to_vec());
+                }
+                vec![(b"XADD\0", args)]
+            }
+            StreamEvent::Delete { ids } => {
+                let mut args = vec![key];
+                args.extend(ids.iter().map(|i| id(*i)));
+                vec![(b"XDEL\0", args)]
+            }
+            StreamEvent::Trim { first_id: Some(first), .. } => {
+                vec![(b"XTRIM\0", vec![key, b"MINID".to_vec(), id(first)])]
+            }
+            StreamEvent::Trim { first_id: None, .. } => {
+                vec![(b"XTRIM\0", vec![key, b"MAXLEN".to_vec(), b"0".to_vec()])]
+            }
+            StreamEvent::GroupCreate { group, last_id } => {
+                vec![(b"XGROUP\0", vec![b"CREATE".to_vec(), key, group.to_vec(), id(last_id)])]
+            }
+            StreamEvent::GroupSetId { group, last_id } => {
+                vec![(b"XGROUP\0", vec![b"SETID".to_vec(), key, group.to_vec(), id(last_id)])]
+            }
+            StreamEvent::Ack { group, ids } => {
+                let mut args = vec![key, group.to_vec()];
+                args.extend(ids.iter().map(|i| id(*i)));
+                vec![(b"XACK\0", args)]
+            }
+            StreamEvent::Claim { group, consumer, id: claimed, delivery_time, delivery_count } => {
+                vec![(b"XCLAIM\0", vec![
+                    key,
+                    group.to_vec(),
+                    consumer.to_vec(),
+                    b"0".to_vec(),
+                    id(claimed),
+                    b"TIME".to_vec(),
+                    delivery_time.to_string().into_bytes(),
+                    b"RETRYCOUNT".to_vec(),
+                    delivery_count.to_string().into_bytes(),
+                    b"FORCE".to_vec(),
+                    b"JUSTID".to_vec(),
+                ])]
+            }
+            StreamEvent::ConsumerDelete { group, consumer } => {
+                vec![(b"XGROUP\0", vec![b"DELCONSUMER".to_vec(), key, group.to_vec(), consumer.to_vec()])]
+            }
+        }
+    }
+}
+
+impl StreamObserver for ReplicateObserver {
+    fn on_event(&self, event: &StreamEvent) {
+        let ctx = self.ctx.get();
+        if ctx.is_null() {
+            return;
+        }
+        for (cmd, args) in self.commands(event) {
+            let args: Vec<&[u8]> = args.iter().map(|a| a.as_slice()).collect();
+            mod_api::replicate_argv(ctx, cmd, &args);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use stream::{IdSpec, ReplicateObserver, Stream, StreamEvent, StreamID, StreamObserver};
+
+    /// Records every event as the replicated commands.
+    struct Log(ReplicateObserver, RefCell<Vec<String>>);
+
+    impl StreamObserver for Log {
+        fn on_event(&self, event: &StreamEvent) {
+            for (cmd, args) in self.0.commands(event) {
+                let mut line = String::from_utf8_lossy(&cmd[..cmd.len() - 1]).into_owned();
+                for arg in args {
+                    line.push(' ');
+                    line.push_str(&String::from_utf8_lossy(&arg));
+                }
+                self.1.borrow_mut().push(line);
+            }
+        }
+    }
+
+    fn taken(log: &Log) -> Vec<String> {
+        log.1.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn events_in_order() {
+        let log = Rc::new(Log(ReplicateObserver::new(b"s"), RefCell::new(Vec::new())));
+        let mut s = Stream::new();
+        s.add(&[(b"f", b"before")], IdSpec::Explicit(StreamID::new(1, 0))).unwrap();
+        s.observe(log.clone());
+
+        for i in 2..7 {
+            s.add(&[(b"f", b"v")], IdSpec::Explicit(StreamID::new(i, 0))).unwrap();
+        }
+        s.delete(&[StreamID::new(2, 0), StreamID::new(100, 0)]);
+        s.delete(&[StreamID::new(100, 0)]);
+        s.trim_maxlen(4, false);
+        s.trim_maxlen(4, false);
+        s.create_group(b"g", StreamID::new(0, 0)).unwrap();
+        assert!(s.create_group(b"g", StreamID::new(0, 0)).is_err());
+        assert_eq!(taken(&log), vec![
+            "XADD s 2-0 f v", "XADD s 3-0 f v", "XADD s 4-0 f v", "XADD s 5-0 f v", "XADD s 6-0 f v",
+            "XDEL s 2-0",
+            "XTRIM s MINID 3-0",
+            "XGROUP CREATE s g 0-0",
+        ]);
+
+        {
+            let mut g = s.group(b"g").unwrap();
+            g.read(b"alice", Some(2), false);
+            g.read(b"bob", Some(1), true);
+            g.ack(&[StreamID::new(3, 0), StreamID::new(5, 0)]);
+            g.claim(b"bob", 0, &[StreamID::new(4, 0)], false);
+            g.delete_consumer(b"bob");
+            assert_eq!(g.delete_consumer(b"bob"), 0);
+        }
+        let lines = taken(&log);
+        let claims: Vec<_> = lines.iter()
+            .map(|l| l.split(" TIME ").next().unwrap().to_string())
+            .collect();
+        assert_eq!(claims, vec![
+            "XCLAIM s g alice 0 3-0", "XCLAIM s g alice 0 4-0", "XGROUP SETID s g 4-0",
+            "XGROUP SETID s g 5-0",
+            "XACK s g 3-0",
+            "XCLAIM s g bob 0 4-0",
+            "XGROUP DELCONSUMER s g bob",
+        ]);
+        assert!(lines[0].ends_with(" RETRYCOUNT 1 FORCE JUSTID"));
+        assert!(lines[5].ends_with(" RETRYCOUNT 2 FORCE JUSTID"));
+
+        s.trim_minid(StreamID::new(100, 0), false);
+        assert_eq!(taken(&log), vec!["XTRIM s MAXLEN 0"]);
+
+        let observer: Rc<dyn StreamObserver> = log.clone();
+        assert!(s.unobserve(&observer));
+        assert!(!s.unobserve(&observer));
+        s.add(&[(b"f", b"after")], IdSpec::Auto).unwrap();
+        assert!(taken(&log).is_empty());
+    }
+}
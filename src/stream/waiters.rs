@@ -0,0 +1,444 @@
+use libc;
+use mod_api::{self, RedisModuleBlockedClient, RedisModuleCtx};
+use redis::{Redis, TimerID};
+use sds::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ptr;
+use std::rc::Rc;
+
+use super::*;
+
+/// Identifies a blocked reader, see `StreamWaiters::block`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WaiterToken(u64);
+
+/// How a blocked reader is resumed.
+pub trait Wake {
+    /// An entry with an ID after the one the reader waits for was added.
+    fn wake(self: Box<Self>, added: StreamID);
+    /// The timeout passed to `block` elapsed.
+    fn timeout(self: Box<Self>);
+}
+
+struct Waiter {
+    token: WaiterToken,
+    group: Option<SdsString>,
+    after: StreamID,
+    /// Unix time in milliseconds, or none to wait forever.
+    deadline: Option<i64>,
+    wake: Box<dyn Wake>,
+}
+
+/// Readers resumed by `StreamWaiters::signal` or `StreamWaiters::expire`.
+/// They are woken by `wake` once the registry is no longer borrowed, a
+/// reader may block again or cancel another one from `Wake`.
+#[must_use]
+pub struct Resumed {
+    /// The added ID, none for timeouts.
+    added: Option<StreamID>,
+    waiters: Vec<Box<dyn Wake>>,
+}
+
+impl Resumed {
+    /// Wake every reader, oldest first.
+    pub fn wake(self) {
+        for w in self.waiters {
+            match self.added {
+                Some(added) => w.wake(added),
+                None => w.timeout(),
+            }
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+/// Readers blocked on streams, the registry behind XREAD BLOCK and
+/// XREADGROUP BLOCK. Waiters are kept per stream key in the order they
+/// blocked and resumed in that order.
+///
+/// A plain reader is woken by the first entry with an ID after the one it
+/// waits for. An entry is only delivered once within a consumer group, so
+/// every added entry wakes just the oldest waiter of each group.
+pub struct StreamWaiters {
+    keys: HashMap<SdsString, VecDeque<Waiter>>,
+    tokens: HashMap<WaiterToken, SdsString>,
+    next_token: u64,
+    /// The timer armed by `arm_timer` and the deadline it fires at.
+    timer: Option<(TimerID, i64)>,
+}
+
+impl Default for StreamWaiters {
+    fn default() -> Self {
+        StreamWaiters::new()
+    }
+}
+
+impl StreamWaiters {
+    pub fn new() -> StreamWaiters {
+        StreamWaiters {
+            keys: HashMap::new(),
+            tokens: HashMap::new(),
+            next_token: 1,
+            timer: None,
+        }
+    }
+
+    /// Block a reader of `key` until an entry with an ID greater than `after`
+    /// is added, or `timeout_ms` elapses. Zero waits forever, the same as
+    /// BLOCK 0. Readers of a consumer group pass the group name and the
+    /// group last delivered ID.
+    pub fn block(
+        &mut self,
+        key: &[u8],
+        group: Option<&[u8]>,
+        after: StreamID,
+        timeout_ms: u64,
+        wake: Box<dyn Wake>,
+    ) -> WaiterToken {
+        let token = WaiterToken(self.next_token);
+        self.next_token += 1;
+
+        let key = SdsString::new(key);
+        self.tokens.insert(token, key.clone());
+        self.keys.entry(key).or_insert_with(VecDeque::new).push_back(Waiter {
+            token,
+            group: group.map(SdsString::new),
+            after,
+            deadline: if timeout_ms == 0 {
+                None
+            } else {
+                Some(unsafe { mstime() } + timeout_ms as i64)
+            },
+            wake,
+        });
+        token
+    }
+
+    /// Forget a waiter without resuming it, for a client that disconnected.
+    /// Returns false if it was already resumed.
+    pub fn cancel(&mut self, token: WaiterToken) -> bool {
+        let key = match self.tokens.remove(&token) {
+            Some(key) => key,
+            None => return false,
+        };
+        let empty = {
+            let queue = self.keys.get_mut(&key).expect("waiter key");
+            queue.retain(|w| w.token != token);
+            queue.is_empty()
+        };
+        if empty {
+            self.keys.remove(&key);
+        }
+        true
+    }
+
+    /// Resume the readers of `key` that `added` satisfies, to be woken in
+    /// that order.
+    pub fn signal(&mut self, key: &[u8], added: StreamID) -> Resumed {
+        let woken = {
+            let queue = match self.keys.get_mut(key) {
+                Some(queue) => queue,
+                None => return Resumed { added: Some(added), waiters: Vec::new() },
+            };
+            let mut woken = Vec::new();
+            let mut groups: Vec<SdsString> = Vec::new();
+            let mut i = 0;
+            while i < queue.len() {
+                let ready = {
+                    let w = &queue[i];
+                    added > w.after && match w.group {
+                        None => true,
+                        Some(ref g) if !groups.contains(g) => {
+                            groups.push(g.clone());
+                            true
+                        }
+                        Some(_) => false,
+                    }
+                };
+                if ready {
+                    woken.push(queue.remove(i).unwrap());
+                } else {
+                    i += 1;
+                }
+            }
+            woken
+        };
+
+        if self.keys.get(key).map_or(false, |q| q.is_empty()) {
+            self.keys.remove(key);
+        }
+        for w in &woken {
+            self.tokens.remove(&w.token);
+        }
+        Resumed {
+            added: Some(added),
+            waiters: woken.into_iter().map(|w| w.wake).collect(),
+        }
+    }
+
+    /// Time out every waiter whose deadline is at or before `now`, a Unix
+    /// time in milliseconds.
+    pub fn expire(&mut self, now: i64) -> Resumed {
+        let mut expired = Vec::new();
+        for queue in self.keys.values_mut() {
+            let mut i = 0;
+            while i < queue.len() {
+                if queue[i].deadline.map_or(false, |d| d <= now) {
+                    expired.push(queue.remove(i).unwrap());
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.keys.retain(|_, q| !q.is_empty());
+
+        for w in &expired {
+            self.tokens.remove(&w.token);
+        }
+        Resumed {
+            added: None,
+            waiters: expired.into_iter().map(|w| w.wake).collect(),
+        }
+    }
+
+    /// Arm a `Redis::start_timer` timer expiring the waiters at the earliest
+    /// deadline, to be called after `block`. The timer arms the next one
+    /// itself, an armed timer is replaced if a waiter is due before it.
+    pub fn arm_timer(waiters: &Rc<RefCell<StreamWaiters>>, redis: &Redis) {
+        let deadline = {
+            let mut w = waiters.borrow_mut();
+            let deadline = match w.next_deadline() {
+                Some(deadline) => deadline,
+                None => return,
+            };
+            match w.timer {
+                Some((_, at)) if at <= deadline => return,
+                Some((id, _)) => {
+                    redis.cancel_timer(id);
+                    w.timer = None;
+                }
+                None => {}
+            }
+            deadline
+        };
+
+        let timer_waiters = waiters.clone();
+        let millis = (deadline - unsafe { mstime() }).max(0);
+        let id = redis.start_timer(millis, move |redis| {
+            let expired = {
+                let mut w = timer_waiters.borrow_mut();
+                w.timer = None;
+                w.expire(unsafe { mstime() })
+            };
+            expired.wake();
+            StreamWaiters::arm_timer(&timer_waiters, &redis);
+        });
+        waiters.borrow_mut().timer = Some((id, deadline));
+    }
+
+    /// The earliest deadline, to schedule the next `expire`.
+    pub fn next_deadline(&self) -> Option<i64> {
+        self.keys
+            .values()
+            .flat_map(|q| q.iter().filter_map(|w| w.deadline))
+            .min()
+    }
+
+    /// Number of readers blocked on `key`.
+    pub fn waiting(&self, key: &[u8]) -> usize {
+        self.keys.get(key).map_or(0, |q| q.len())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl Stream {
+    /// Signal `waiters` under `key` for every entry added from now on.
+    pub fn notify(&mut self, waiters: Rc<RefCell<StreamWaiters>>, key: &[u8]) {
+        self.waiters = Some((waiters, SdsString::new(key)));
+    }
+}
+
+/// A client blocked with `mod_api::block_client`. Waking it unblocks the
+/// client with the added ID as private data, for the reply callback to read
+/// with `added_id`. A timeout unblocks it with no private data, which the
+/// reply callback answers with a null reply like XREAD does.
+///
+/// Timeouts are tracked by `StreamWaiters`, see `arm_timer`, so the client
+/// should be blocked with a zero timeout and `free_blocked_privdata`.
+pub struct BlockedClient {
+    bc: *mut RedisModuleBlockedClient,
+}
+
+impl BlockedClient {
+    pub fn new(bc: *mut RedisModuleBlockedClient) -> BlockedClient {
+        BlockedClient { bc }
+    }
+
+    /// The ID passed to the reply callback of a client resumed by `wake`.
+    pub fn added_id(ctx: *mut RedisModuleCtx) -> Option<StreamID> {
+        let data = mod_api::get_blocked_client_private_data(ctx) as *const StreamID;
+        if data.is_null() {
+            None
+        } else {
+            Some(unsafe { *data })
+        }
+    }
+}
+
+impl Wake for BlockedClient {
+    fn wake(self: Box<Self>, added: StreamID) {
+        mod_api::unblock_client(self.bc, Box::into_raw(Box::new(added)) as *mut u8);
+    }
+
+    fn timeout(self: Box<Self>) {
+        mod_api::unblock_client(self.bc, ptr::null_mut());
+    }
+}
+
+/// The `free_privdata` callback for clients resumed through `BlockedClient`.
+pub extern "C" fn free_blocked_privdata(
+    _ctx: *mut RedisModuleCtx,
+    data: *mut libc::c_void,
+) -> *mut libc::c_void {
+    if !data.is_null() {
+        drop(unsafe { Box::from_raw(data as *mut StreamID) });
+    }
+    ptr::null_mut()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use stream::{IdSpec, Stream, StreamID, StreamWaiters, Wake};
+
+    type Log = Rc<RefCell<Vec<(&'static str, Option<StreamID>)>>>;
+
+    struct Recorder(&'static str, Log);
+
+    impl Wake for Recorder {
+        fn wake(self: Box<Self>, added: StreamID) {
+            self.1.borrow_mut().push((self.0, Some(added)));
+        }
+
+        fn timeout(self: Box<Self>) {
+            self.1.borrow_mut().push((self.0, None));
+        }
+    }
+
+    #[test]
+    fn wake_in_order() {
+        let log: Log = Rc::new(RefCell::new(Vec::new()));
+        let waiters = Rc::new(RefCell::new(StreamWaiters::new()));
+        let mut s = Stream::new();
+        s.notify(waiters.clone(), b"jobs");
+        s.add(&[(b"n", b"1")], IdSpec::Explicit(StreamID::new(1, 0))).unwrap();
+
+        {
+            let mut w = waiters.borrow_mut();
+            let rec = |name| Box::new(Recorder(name, log.clone()));
+            w.block(b"jobs", None, StreamID::new(5, 0), 0, rec("late"));
+            w.block(b"jobs", None, StreamID::new(1, 0), 0, rec("a"));
+            w.block(b"jobs", Some(b"g"), StreamID::new(1, 0), 0, rec("g1"));
+            w.block(b"jobs", Some(b"g"), StreamID::new(1, 0), 0, rec("g2"));
+            w.block(b"jobs", None, StreamID::new(0, 0), 0, rec("b"));
+            w.block(b"other", None, StreamID::new(0, 0), 0, rec("other"));
+            assert_eq!(w.waiting(b"jobs"), 5);
+        }
+
+        s.add(&[(b"n", b"2")], IdSpec::Explicit(StreamID::new(2, 0))).unwrap();
+        let id = Some(StreamID::new(2, 0));
+        assert_eq!(*log.borrow(), vec![("a", id), ("g1", id), ("b", id)]);
+
+        log.borrow_mut().clear();
+        s.add(&[(b"n", b"6")], IdSpec::Explicit(StreamID::new(6, 0))).unwrap();
+        let id = Some(StreamID::new(6, 0));
+        assert_eq!(*log.borrow(), vec![("late", id), ("g2", id)]);
+
+        let w = waiters.borrow();
+        assert_eq!(w.waiting(b"jobs"), 0);
+        assert_eq!(w.len(), 1);
+    }
+
+    #[test]
+    fn timeout_and_cancel() {
+        let log: Log = Rc::new(RefCell::new(Vec::new()));
+        let mut w = StreamWaiters::new();
+        let rec = |name| Box::new(Recorder(name, log.clone()));
+        let short = w.block(b"s", None, StreamID::new(0, 0), 10, rec("short"));
+        let long = w.block(b"s", None, StreamID::new(0, 0), 60000, rec("long"));
+        let gone = w.block(b"s", Some(b"g"), StreamID::new(0, 0), 0, rec("gone"));
+        let forever = w.block(b"s", None, StreamID::new(0, 0), 0, rec("forever"));
+
+        let deadline = w.next_deadline().unwrap();
+        assert!(w.expire(deadline - 1).is_empty());
+        let expired = w.expire(deadline);
+        assert_eq!(expired.len(), 1);
+        expired.wake();
+        assert_eq!(*log.borrow(), vec![("short", None)]);
+        assert!(!w.cancel(short));
+
+        assert!(w.cancel(gone));
+        assert!(!w.cancel(gone));
+        let expired = w.expire(deadline + 60000);
+        assert_eq!(expired.len(), 1);
+        expired.wake();
+        assert!(!w.cancel(long));
+        assert_eq!(w.next_deadline(), None);
+
+        let woken = w.signal(b"s", StreamID::new(1, 0));
+        assert_eq!(woken.len(), 1);
+        woken.wake();
+        assert!(!w.cancel(forever));
+        assert!(w.is_empty());
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    /// Blocks again on the next entry once woken.
+    struct Reblock(Rc<RefCell<StreamWaiters>>, Rc<RefCell<Vec<StreamID>>>);
+
+    impl Wake for Reblock {
+        fn wake(self: Box<Self>, added: StreamID) {
+            self.1.borrow_mut().push(added);
+            let waiters = self.0.clone();
+            waiters.borrow_mut().block(b"jobs", None, added, 0, self);
+        }
+
+        fn timeout(self: Box<Self>) {}
+    }
+
+    #[test]
+    fn wake_while_adding() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let waiters = Rc::new(RefCell::new(StreamWaiters::new()));
+        let mut s = Stream::new();
+        s.notify(waiters.clone(), b"jobs");
+        let reader = Box::new(Reblock(waiters.clone(), seen.clone()));
+        waiters.borrow_mut().block(b"jobs", None, StreamID::new(0, 0), 0, reader);
+
+        for ms in 1..4 {
+            s.add(&[(b"n", b"1")], IdSpec::Explicit(StreamID::new(ms, 0))).unwrap();
+        }
+        assert_eq!(*seen.borrow(), vec![StreamID::new(1, 0), StreamID::new(2, 0), StreamID::new(3, 0)]);
+        assert_eq!(waiters.borrow().waiting(b"jobs"), 1);
+    }
+}